@@ -0,0 +1,58 @@
+//! Plays random moves through a curriculum of generated layouts, or through
+//! every layout of `assets/layouts` with `fixed`, logging the schedule.
+//!
+//! `cargo run --example curriculum -- [curriculum|fixed] [episodes] [schedule.jsonl]`
+use rand::seq::SliceRandom;
+use rixel::{
+    curriculum::{CurriculumSettings, LayoutSampling},
+    env::{Env, EnvConfig},
+    game::Rewards,
+    observation::ObservationConfig,
+};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let sampling = match args.next().as_deref() {
+        Some("fixed") => LayoutSampling::from_dir("./assets/layouts").unwrap(),
+        _ => LayoutSampling::Curriculum(CurriculumSettings::generated(3, 0.5, 10)),
+    };
+    let episodes = args.next().and_then(|n| n.parse().ok()).unwrap_or(50);
+    let log = args.next().unwrap_or_else(|| "schedule.jsonl".to_string());
+
+    let mut env = Env::new(EnvConfig {
+        layouts: Some(sampling),
+        schedule_log: Some(log.into()),
+        // A window keeps the observations the same size on every layout.
+        observation: ObservationConfig {
+            window: Some(3),
+            ..Default::default()
+        },
+        max_steps: Some(500),
+        rewards: Rewards::load_default(),
+        ..Default::default()
+    })
+    .unwrap();
+    let mut rng = rand::thread_rng();
+
+    for episode in 0..episodes {
+        env.reset(Some(episode));
+        let layout = env.layout().name.clone();
+        let won = loop {
+            let legal = env.legal_actions();
+            let actions = (0..legal.len()).filter(|i| legal[*i]).collect::<Vec<_>>();
+            let (_, _, done, info) = env.step(*actions.choose(&mut rng).unwrap());
+            if done {
+                break info.won;
+            }
+        };
+        let sampler = env.sampler().unwrap();
+        println!(
+            "episode {} on {} (stage {}): {}, success rate {:.2}",
+            episode,
+            layout,
+            sampler.stage(),
+            if won { "won" } else { "lost" },
+            sampler.success_rate()
+        );
+    }
+}
@@ -0,0 +1,43 @@
+//! Evolves feature weights, or a small network with `network`, over the given layouts.
+//!
+//! `cargo run --example evolve -- [features|network] [generations] [layouts...]`
+use std::path::PathBuf;
+
+use rixel::{
+    evolution::{Evolution, EvolutionSettings, GenomeKind},
+    observation::ObservationConfig,
+};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut settings = EvolutionSettings::default();
+    if args.next().as_deref() == Some("network") {
+        settings.genome = GenomeKind::Network {
+            observation: ObservationConfig {
+                window: Some(2),
+                ..Default::default()
+            },
+            hidden: vec![16],
+        };
+    }
+    let generations = args.next().and_then(|n| n.parse().ok()).unwrap_or(10);
+    let layouts = args.map(PathBuf::from).collect::<Vec<_>>();
+    if !layouts.is_empty() {
+        settings.layouts = layouts;
+    }
+
+    let mut evolution = Evolution::new(settings).unwrap();
+    for stats in evolution.run(generations).unwrap() {
+        println!(
+            "generation {}: best {:.1}, mean {:.1}",
+            stats.generation, stats.best, stats.mean
+        );
+    }
+    let best = &evolution.hall_of_fame()[0];
+    println!(
+        "best fitness {:.1} from generation {}, saved to {:?}",
+        best.fitness,
+        best.generation,
+        evolution.settings().save_dir
+    );
+}
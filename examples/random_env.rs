@@ -0,0 +1,40 @@
+//! Plays a few episodes of the headless environment with random legal actions.
+use rand::seq::SliceRandom;
+use rixel::{
+    env::{Env, EnvConfig},
+    game::Rewards,
+};
+
+fn main() {
+    let mut env = Env::new(EnvConfig {
+        max_steps: Some(500),
+        rewards: Rewards::load_default(),
+        ..Default::default()
+    })
+    .unwrap();
+    println!(
+        "Actions {:?}, observations {:?}",
+        env.action_space(),
+        env.observation_space()
+    );
+
+    let mut rng = rand::thread_rng();
+    for episode in 0..5 {
+        env.reset(Some(episode));
+        loop {
+            let legal = env.legal_actions();
+            let actions = (0..legal.len()).filter(|i| legal[*i]).collect::<Vec<_>>();
+            let (_observation, _reward, done, info) = env.step(*actions.choose(&mut rng).unwrap());
+            if done {
+                println!(
+                    "Episode {} {} after {} steps with score {}",
+                    episode,
+                    if info.won { "won" } else { "lost" },
+                    info.steps,
+                    info.score
+                );
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Records random episodes of the headless environment into a dataset and loads it back.
+//!
+//! `cargo run --example record_dataset -- <directory> [npz|npy|jsonl] [episodes]`
+use rand::seq::SliceRandom;
+use rixel::{
+    dataset::{load_dataset, DatasetFormat, DatasetWriter, TrajectoryRecorder},
+    env::{Env, EnvConfig},
+    game::Rewards,
+};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let dir = args.next().unwrap_or_else(|| "dataset".to_string());
+    let format = match args.next().as_deref() {
+        Some("npy") => DatasetFormat::Npy,
+        Some("jsonl") => DatasetFormat::Jsonl,
+        _ => DatasetFormat::Npz,
+    };
+    let episodes = args.next().and_then(|n| n.parse().ok()).unwrap_or(5);
+
+    let mut env = Env::new(EnvConfig {
+        max_steps: Some(200),
+        rewards: Rewards::load_default(),
+        ..Default::default()
+    })
+    .unwrap();
+    let mut writer = DatasetWriter::new(&dir, format).unwrap();
+    let mut rng = rand::thread_rng();
+
+    for episode in 0..episodes {
+        let mut observation = env.reset(Some(episode));
+        let mut recorder = TrajectoryRecorder::new(env.layout().name.clone(), env.seed());
+        loop {
+            let legal = env.legal_actions();
+            let actions = (0..legal.len()).filter(|i| legal[*i]).collect::<Vec<_>>();
+            let action = *actions.choose(&mut rng).unwrap();
+            let (next, reward, done, _info) = env.step(action);
+            recorder.push(observation, action, reward, done);
+            observation = next;
+            if done {
+                break;
+            }
+        }
+        writer.write(&recorder.finish()).unwrap();
+    }
+
+    for trajectory in load_dataset(&dir).unwrap() {
+        println!(
+            "{} seed {}: {} steps, observations {:?}, return {}",
+            trajectory.layout,
+            trajectory.seed,
+            trajectory.len(),
+            trajectory.observations.shape(),
+            trajectory.rewards.sum()
+        );
+    }
+}
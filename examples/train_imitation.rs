@@ -0,0 +1,33 @@
+//! Clones the play recorded with the `R` key and reports how well it predicts held out episodes.
+//!
+//! `cargo run --example train_imitation -- [demonstrations] [imitation.json] [epochs]`
+use std::path::PathBuf;
+
+use rixel::{
+    dataset::load_dataset,
+    imitation::{clone_behavior, local_observation, split_episodes, CloningSettings},
+};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let dir = args.next().unwrap_or_else(|| "demonstrations".to_string());
+    let output = PathBuf::from(args.next().unwrap_or_else(|| "imitation.json".to_string()));
+    let settings = CloningSettings {
+        epochs: args.next().and_then(|n| n.parse().ok()).unwrap_or(20),
+        ..Default::default()
+    };
+
+    let mut rng = rand::thread_rng();
+    let trajectories = load_dataset(&dir).unwrap();
+    let (training, evaluation) = split_episodes(trajectories, 0.2, &mut rng);
+    println!(
+        "{} training episodes, {} held out",
+        training.len(),
+        evaluation.len()
+    );
+
+    let policy = clone_behavior(&training, local_observation(), &settings, &mut rng);
+    println!("training accuracy {:.3}", policy.accuracy(&training));
+    println!("held out accuracy {:.3}", policy.accuracy(&evaluation));
+    policy.save(&output).unwrap();
+}
@@ -0,0 +1,48 @@
+//! Steps many headless environments at once with random actions and reports the throughput.
+use std::time::Instant;
+
+use rand::Rng;
+use rixel::{env::EnvConfig, game::Rewards, vec_env::VecEnv};
+
+fn main() {
+    let instances = 64;
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut envs = VecEnv::uniform(
+        EnvConfig {
+            max_steps: Some(500),
+            rewards: Rewards::load_default(),
+            ..Default::default()
+        },
+        instances,
+        0,
+        threads,
+    )
+    .unwrap();
+
+    let observations = envs.reset();
+    println!(
+        "Observations {:?} on {} threads",
+        observations.shape(),
+        threads
+    );
+
+    let mut rng = rand::thread_rng();
+    let start = Instant::now();
+    let mut episodes = 0;
+    let steps = 200;
+    for _ in 0..steps {
+        let actions = (0..envs.len())
+            .map(|_| rng.gen_range(0..4))
+            .collect::<Vec<_>>();
+        let step = envs.step(&actions);
+        episodes += step.dones.iter().filter(|done| **done).count();
+    }
+    let elapsed = start.elapsed().as_secs_f32();
+    println!(
+        "{} steps, {} episodes in {:.2}s: {:.0} steps/s",
+        steps * instances,
+        episodes,
+        elapsed,
+        (steps * instances) as f32 / elapsed
+    );
+}
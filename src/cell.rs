@@ -12,8 +12,22 @@ use bevy::{
 };
 
 use crate::grid::{self, GridConfig};
+use serde::{Deserialize, Serialize};
 use wgpu::{PrimitiveTopology, VertexFormat};
-#[derive(Component, Reflect, Default, Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd)]
+#[derive(
+    Component,
+    Reflect,
+    Default,
+    Clone,
+    Copy,
+    Debug,
+    Hash,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Serialize,
+    Deserialize,
+)]
 pub struct CellPosition {
     pub x: u32,
     pub y: u32,
@@ -48,6 +62,27 @@ impl CellPosition {
         )
     }
 
+    pub fn from_screen_position(x: f32, y: f32, grid_config: &grid::GridConfig) -> Option<Self> {
+        let GridConfig {
+            grid_width,
+            grid_height,
+            window_width,
+            window_height,
+        } = grid_config;
+
+        let size_x = (*window_width / *grid_width) as f32;
+        let size_y = (*window_height / *grid_height) as f32;
+        let left = (*window_width as f32 / 2.) - (size_x / 2.);
+        let top = (*window_height as f32 / 2.) - (size_y / 2.);
+        let cell_x = ((x + left) / size_x).round();
+        let cell_y = ((top - y) / size_y).round();
+        if cell_x < 0. || cell_y < 0. {
+            return None;
+        }
+        let position = Self::new(cell_x as u32, cell_y as u32);
+        position.within_map_bounds(grid_config).then_some(position)
+    }
+
     pub fn within_map_bounds(&self, grid_config: &grid::GridConfig) -> bool {
         self.x < grid_config.grid_width && self.y < grid_config.grid_height
     }
@@ -154,6 +189,40 @@ impl From<Cell> for Mesh {
     }
 }
 
+/// Triangle pointing up, used to draw directions on top of cells.
+#[derive(Debug, Copy, Clone)]
+pub struct Arrow {
+    size: f32,
+}
+
+impl Arrow {
+    pub fn new(size: f32) -> Self {
+        Self { size }
+    }
+}
+
+impl From<Arrow> for Mesh {
+    fn from(arrow: Arrow) -> Self {
+        let radius = arrow.size / 2.0;
+        let positions = vec![
+            [0.0, radius, 0.0],
+            [-radius * 0.6, -radius, 0.0],
+            [radius * 0.6, -radius, 0.0],
+        ];
+        let normals = vec![[0.0, 0.0, 1.0]; 3];
+        let uvs = vec![[0.0, 0.0]; 3];
+        let properties = vec![[0.0, 0.0]; 3];
+
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+        mesh.insert_attribute(Cell::ATTRIBUTE_PROPS, properties);
+        mesh.set_indices(Some(Indices::U32(vec![0, 1, 2])));
+        mesh
+    }
+}
+
 // This is the struct that will be passed to your shader
 #[derive(AsBindGroup, Clone, TypeUuid)]
 #[uuid = "4ee9c363-1124-4113-890e-199d81b00281"]
@@ -0,0 +1,618 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use bevy::{app::AppExit, ecs::system::CommandQueue, prelude::*, time::TimePlugin};
+use itertools::Itertools;
+
+use crate::{
+    cell::CellPosition,
+    controller::{self, ControllerConfig, ControllerKind},
+    dqn::DqnSettings,
+    features::ExtractorKind,
+    game::{self, AgentScore, Episode, Ghost, Rewards},
+    layout::Layout,
+    learning::{ApproximateSettings, LearningSettings},
+    mdp::MdpSettings,
+    movement::{Actions, TransitionModel},
+    replay,
+    rng::GameSeed,
+    save,
+    simulation::{self, SimulationClock},
+    Agent, AppState, MainLayout,
+};
+
+pub const USAGE: &str = "\
+Usage: rixel [options]
+
+Without options the game opens on the menu. Any option but --seed skips it and
+starts the game it describes.
+
+Options:
+  -l, --layout <name>       layout from assets/layouts, or the path of a layout,
+                            replay or saved game (default capsuleClassic)
+  -p, --pacman <agent>      agent controlling the pacmen (default keyboard)
+  -g, --ghosts <agent>      agent controlling the ghosts (default random)
+  -a, --agent-args <args>   settings of the pacman agent, as key=value,key=value
+  -k, --num-ghosts <n>      keeps the first n ghosts of the layout
+  -n, --num-games <n>       games to play before quitting
+      --max-steps <n>       ends a game as a loss after n ticks (default 1000
+                            without the window, no limit in it)
+      --frame-time <secs>   seconds between two ticks (default 0.1)
+      --discount <g>        discount of a gridworld (default 0.9)
+      --noise <p>           chance of slipping sideways in a gridworld
+                            (default 0.2)
+      --living-reward <r>   reward of every step in a gridworld (default 0)
+      --seed <n>            plays every game from the same seed
+  -q, --quiet               plays without a window, printing the scores only
+  -t, --text                plays without a window, printing the board
+  -h, --help                prints this message
+
+Agents: keyboard, random, search, qlearning, sarsa, approximate, dqn,
+imitation and scripted; the names of pacman.py work too (KeyboardAgent,
+RandomGhost, PacmanQAgent, ApproximateQAgent...).
+
+Agent args: epsilon, alpha, gamma (or discount) for the learning agents,
+extractor for approximate, load and save_dir for approximate and dqn, path for
+imitation, moves (UUDLR...) for scripted.";
+
+/// How the game is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Display {
+    #[default]
+    Window,
+    /// No window, only the score of every game.
+    Quiet,
+    /// No window, the board is printed after every tick.
+    Text,
+}
+
+/// Command line of the game, after the options of the Berkeley `pacman.py`.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct Options {
+    pub layout: Option<PathBuf>,
+    pub pacman: Option<ControllerKind>,
+    pub ghosts: Option<ControllerKind>,
+    pub num_ghosts: Option<usize>,
+    pub num_games: Option<u32>,
+    pub max_steps: Option<u64>,
+    pub frame_time: Option<f32>,
+    /// Gridworld settings replacing those of the layout.
+    pub discount: Option<f32>,
+    pub noise: Option<f32>,
+    pub living_reward: Option<f32>,
+    pub seed: Option<u64>,
+    pub display: Display,
+}
+
+impl Options {
+    /// Reads the options the game was started with, printing the usage and
+    /// quitting when they cannot be understood.
+    pub fn from_env() -> Self {
+        let args = std::env::args().skip(1).collect::<Vec<_>>();
+        if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+            println!("{}", USAGE);
+            std::process::exit(0);
+        }
+        Self::parse(args).unwrap_or_else(|error| {
+            eprintln!("{}\n\n{}", error, USAGE);
+            std::process::exit(2);
+        })
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut agent_args = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "-l" | "--layout" => options.layout = Some(layout_path(&value()?)),
+                "-p" | "--pacman" => options.pacman = Some(parse_agent(&value()?)?),
+                "-g" | "--ghosts" => options.ghosts = Some(parse_agent(&value()?)?),
+                "-a" | "--agent-args" => agent_args = Some(value()?),
+                "-k" | "--num-ghosts" => options.num_ghosts = Some(parse_number(&arg, &value()?)?),
+                "-n" | "--num-games" => options.num_games = Some(parse_number(&arg, &value()?)?),
+                "--max-steps" => options.max_steps = Some(parse_number(&arg, &value()?)?),
+                "--frame-time" => options.frame_time = Some(parse_seconds(&arg, &value()?)?),
+                "--discount" => options.discount = Some(parse_number(&arg, &value()?)?),
+                "--noise" => options.noise = Some(parse_probability(&arg, &value()?)?),
+                "--living-reward" => options.living_reward = Some(parse_number(&arg, &value()?)?),
+                "--seed" => options.seed = Some(parse_number(&arg, &value()?)?),
+                "-q" | "--quiet" => options.display = Display::Quiet,
+                "-t" | "--text" => options.display = Display::Text,
+                _ => return Err(format!("Unknown option {}", arg)),
+            }
+        }
+
+        if let Some(agent_args) = agent_args {
+            let mut pacman = options.pacman.take().unwrap_or(ControllerKind::Keyboard);
+            apply_agent_args(&mut pacman, &agent_args)?;
+            options.pacman = Some(pacman);
+        }
+        if options.display != Display::Window {
+            if options.controller_config().default == ControllerKind::Keyboard {
+                return Err("The keyboard agent needs the window, pick another --pacman".into());
+            }
+            if options
+                .layout
+                .as_deref()
+                .is_some_and(|path| !is_layout(path))
+            {
+                return Err("Replays and saved games are only shown in the window".into());
+            }
+        }
+        Ok(options)
+    }
+
+    /// Whether the game starts at once instead of on the menu.
+    pub fn skips_menu(&self) -> bool {
+        self.layout.is_some()
+            || self.pacman.is_some()
+            || self.ghosts.is_some()
+            || self.num_ghosts.is_some()
+            || self.num_games.is_some()
+            || self.max_steps.is_some()
+            || self.frame_time.is_some()
+            || self.discount.is_some()
+            || self.noise.is_some()
+            || self.living_reward.is_some()
+            || self.display != Display::Window
+    }
+
+    pub fn layout_path(&self) -> PathBuf {
+        self.layout
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(MainLayout::default().path))
+    }
+
+    pub fn controller_config(&self) -> ControllerConfig {
+        let defaults = ControllerConfig::default();
+        ControllerConfig {
+            default: self.pacman.clone().unwrap_or(defaults.default),
+            ghosts: self.ghosts.clone().unwrap_or(defaults.ghosts),
+            agents: HashMap::new(),
+        }
+    }
+
+    pub fn clock(&self) -> SimulationClock {
+        self.frame_time
+            .map_or_else(SimulationClock::default, SimulationClock::new)
+    }
+
+    /// Gridworld settings of a layout, with those given on the command line.
+    pub fn mdp_settings(&self, layout: &Layout) -> MdpSettings {
+        let settings = layout.mdp.unwrap_or_default();
+        MdpSettings {
+            living_reward: self.living_reward.unwrap_or(settings.living_reward),
+            discount: self.discount.unwrap_or(settings.discount),
+            noise: self.noise.unwrap_or(settings.noise),
+        }
+    }
+
+    /// Reads the layout, replay or saved game the game starts with, to report
+    /// a missing or broken file before opening the window.
+    pub fn check_layout(&self) -> std::io::Result<()> {
+        let path = self.layout_path();
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(replay::EXTENSION) => replay::Replay::load(&path).map(drop),
+            Some(save::EXTENSION) => save::SavedGame::load(&path).map(drop),
+            _ => Layout::load(&path).map(drop),
+        }
+    }
+
+    /// The layout with only as many ghosts as were asked for.
+    pub fn load_layout(&self) -> std::io::Result<Layout> {
+        let mut layout = Layout::load(self.layout_path())?;
+        if let Some(count) = self.num_ghosts {
+            layout.keep_ghosts(count);
+        }
+        Ok(layout)
+    }
+}
+
+/// A bare name is looked for in `assets/layouts`, like `-l mediumClassic`.
+fn layout_path(name: &str) -> PathBuf {
+    let path = Path::new(name);
+    if path.extension().is_some() || path.components().count() > 1 {
+        return path.to_path_buf();
+    }
+    Path::new("./assets/layouts").join(format!("{}.json", name))
+}
+
+fn is_layout(path: &Path) -> bool {
+    !matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some(replay::EXTENSION | save::EXTENSION)
+    )
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a number, not {}", option, value))
+}
+
+/// A duration the clock and `thread::sleep` accept: finite and not negative.
+fn parse_seconds(option: &str, value: &str) -> Result<f32, String> {
+    let seconds: f32 = parse_number(option, value)?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(format!(
+            "{} expects a number of seconds of at least 0, not {}",
+            option, value
+        ));
+    }
+    Ok(seconds)
+}
+
+fn parse_probability(option: &str, value: &str) -> Result<f32, String> {
+    let probability: f32 = parse_number(option, value)?;
+    if !(0.0..=1.0).contains(&probability) {
+        return Err(format!(
+            "{} expects a probability between 0 and 1, not {}",
+            option, value
+        ));
+    }
+    Ok(probability)
+}
+
+fn parse_agent(name: &str) -> Result<ControllerKind, String> {
+    Ok(match name.to_lowercase().as_str() {
+        "keyboard" | "keyboardagent" => ControllerKind::Keyboard,
+        "random" | "randomagent" | "randomghost" => ControllerKind::Random,
+        "search" | "searchagent" => ControllerKind::Search,
+        "qlearning" | "qlearningagent" | "pacmanqagent" => {
+            ControllerKind::QLearning(LearningSettings::default())
+        }
+        "sarsa" => ControllerKind::Sarsa(LearningSettings::default()),
+        "approximate" | "approximateqagent" => {
+            ControllerKind::ApproximateQ(ApproximateSettings::default())
+        }
+        "dqn" => ControllerKind::Dqn(DqnSettings::default()),
+        "imitation" => ControllerKind::Imitation(PathBuf::from("imitation.json")),
+        "scripted" => ControllerKind::Scripted(Vec::new()),
+        _ => return Err(format!("Unknown agent {}", name)),
+    })
+}
+
+fn parse_extractor(name: &str) -> Result<ExtractorKind, String> {
+    Ok(match name.to_lowercase().as_str() {
+        "bias" | "identityextractor" => ExtractorKind::Bias,
+        "closestfood" => ExtractorKind::ClosestFood,
+        "ghostsonestepaway" => ExtractorKind::GhostsOneStepAway,
+        "eatsfood" => ExtractorKind::EatsFood,
+        "simple" | "simpleextractor" => ExtractorKind::Simple,
+        _ => return Err(format!("Unknown extractor {}", name)),
+    })
+}
+
+/// Sets `key=value,key=value` on the agent, refusing keys it has no use for.
+fn apply_agent_args(kind: &mut ControllerKind, agent_args: &str) -> Result<(), String> {
+    for pair in agent_args.split(',').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("Agent args are key=value pairs, not {}", pair))?;
+        let learning = match kind {
+            ControllerKind::QLearning(settings) | ControllerKind::Sarsa(settings) => Some(settings),
+            ControllerKind::ApproximateQ(settings) => Some(&mut settings.learning),
+            _ => None,
+        };
+        if let Some(settings) = learning {
+            if set_learning(settings, key, value)? {
+                continue;
+            }
+        }
+        match (key, &mut *kind) {
+            ("extractor", ControllerKind::ApproximateQ(settings)) => {
+                settings.extractor = parse_extractor(value)?
+            }
+            ("load", ControllerKind::ApproximateQ(settings)) => {
+                settings.load = Some(PathBuf::from(value))
+            }
+            ("save_dir", ControllerKind::ApproximateQ(settings)) => {
+                settings.save_dir = PathBuf::from(value)
+            }
+            ("load", ControllerKind::Dqn(settings)) => settings.load = Some(PathBuf::from(value)),
+            ("save_dir", ControllerKind::Dqn(settings)) => settings.save_dir = PathBuf::from(value),
+            ("path", ControllerKind::Imitation(path)) => *path = PathBuf::from(value),
+            ("moves", ControllerKind::Scripted(moves)) => {
+                *moves = value
+                    .chars()
+                    .map(|c| {
+                        replay::char_direction(c)
+                            .ok_or_else(|| format!("Scripted moves are U, L, D or R, not {}", c))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            (_, kind) => return Err(format!("{:?} has no setting {}", kind, key)),
+        }
+    }
+    Ok(())
+}
+
+/// Whether `key` is one of the learning settings, which is then set.
+fn set_learning(settings: &mut LearningSettings, key: &str, value: &str) -> Result<bool, String> {
+    match key {
+        "epsilon" => settings.epsilon = parse_number(key, value)?,
+        "alpha" => settings.alpha = parse_number(key, value)?,
+        "gamma" | "discount" => settings.discount = parse_number(key, value)?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Scores of the games played so far, printed as they end. Once `limit` games
+/// are over the summary is printed and the game quits.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct Games {
+    pub limit: Option<u32>,
+    /// Ticks after which a game that is still going on is lost.
+    pub max_steps: Option<u64>,
+    /// Final score of pacman `0` and whether the game was won.
+    pub results: Vec<(f32, bool)>,
+    /// Prints the scores on the standard output instead of logging them, for
+    /// the games played without the window.
+    pub print: bool,
+    steps: u64,
+}
+
+impl Games {
+    /// Cap of the games played without the window, which nobody can stop.
+    pub const HEADLESS_MAX_STEPS: u64 = 1000;
+
+    pub fn new(limit: Option<u32>, max_steps: Option<u64>) -> Self {
+        Self {
+            limit,
+            max_steps,
+            results: Vec::new(),
+            print: false,
+            steps: 0,
+        }
+    }
+
+    fn report(&self, message: &str) {
+        if self.print {
+            println!("{}", message);
+        } else {
+            info!("{}", message);
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.limit
+            .is_some_and(|limit| self.results.len() >= limit as usize)
+    }
+
+    pub fn summary(&self) -> String {
+        let games = self.results.len().max(1) as f32;
+        let scores = self.results.iter().map(|(score, _)| *score);
+        let wins = self.results.iter().filter(|(_, won)| *won).count();
+        format!(
+            "Average Score: {}\nScores:        {}\nWin Rate:      {}/{} ({:.2})\nRecord:        {}",
+            scores.clone().sum::<f32>() / games,
+            scores.map(|score| score.to_string()).join(", "),
+            wins,
+            self.results.len(),
+            wins as f32 / games,
+            self.results
+                .iter()
+                .map(|(_, won)| if *won { "Win" } else { "Loss" })
+                .join(", "),
+        )
+    }
+}
+
+pub struct GamesPlugin;
+
+impl Plugin for GamesPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Games>().add_system_set(
+            SystemSet::on_update(AppState::InGame).with_system(
+                record_game
+                    .after(game::apply_rules)
+                    .before(controller::observe_rewards)
+                    .before(game::reset_episode),
+            ),
+        );
+    }
+}
+
+/// Runs before the episode resets, while the final score is still there, and
+/// before the agents observe it so that they learn when a game is cut short.
+fn record_game(
+    clock: Res<SimulationClock>,
+    mut episode: ResMut<Episode>,
+    mut games: ResMut<Games>,
+    mut exit: EventWriter<AppExit>,
+    agent_query: Query<(&Agent, &AgentScore), Without<Ghost>>,
+) {
+    if games.finished() {
+        return;
+    }
+    let mut timed_out = false;
+    if clock.just_ticked() && !episode.done {
+        games.steps += 1;
+        if games.max_steps.is_some_and(|max| games.steps >= max) {
+            episode.done = true;
+            episode.won = false;
+            timed_out = true;
+        }
+    }
+    if !episode.is_changed() || !episode.done {
+        return;
+    }
+    let score = agent_query
+        .iter()
+        .find(|(agent, _)| agent.id == 0)
+        .map_or(0.0, |(_, score)| score.score);
+    games.report(&format!(
+        "{} Score: {}",
+        if episode.won {
+            "Pacman emerges victorious!"
+        } else if timed_out {
+            "Pacman ran out of time!"
+        } else {
+            "Pacman died!"
+        },
+        score
+    ));
+    games.results.push((score, episode.won));
+    games.steps = 0;
+    if games.finished() {
+        games.report(&games.summary());
+        exit.send(AppExit);
+    }
+}
+
+/// Plays the games of `--quiet` and `--text` without a window, as fast as they
+/// go or a frame time apart when the board is printed.
+pub fn run_headless(options: &Options) -> std::io::Result<()> {
+    let layout = options.load_layout()?;
+    let rewards = Rewards::load_default();
+    let mut app = App::new();
+    app.add_plugin(CorePlugin::default())
+        .add_plugin(TimePlugin)
+        .init_resource::<Input<KeyCode>>()
+        .add_state(AppState::InGame)
+        .add_plugin(simulation::SimulationPlugin)
+        .add_plugin(game::GamePlugin)
+        .add_plugin(controller::ControllerPlugin)
+        .add_plugin(GamesPlugin)
+        .insert_resource(Games {
+            print: true,
+            ..Games::new(
+                Some(options.num_games.unwrap_or(1)),
+                Some(options.max_steps.unwrap_or(Games::HEADLESS_MAX_STEPS)),
+            )
+        })
+        .insert_resource(rewards)
+        .insert_resource(TransitionModel::default())
+        .insert_resource(GameSeed(options.seed))
+        .insert_resource(options.controller_config());
+    let mut clock = options.clock();
+    clock.paused = true;
+    app.insert_resource(clock);
+
+    let mut queue = CommandQueue::default();
+    let mut commands = Commands::new(&mut queue, &app.world);
+    layout.insert_resources(&mut commands);
+    commands.insert_resource(options.mdp_settings(&layout));
+    queue.apply(&mut app.world);
+
+    // The first update spawns the agents without ticking the clock.
+    app.update();
+    while !app.world.resource::<Games>().finished() {
+        app.world.resource_mut::<SimulationClock>().step();
+        app.update();
+        if options.display == Display::Text {
+            println!("{}", board(&mut app.world));
+            if let Some(frame_time) = options.frame_time {
+                thread::sleep(Duration::from_secs_f32(frame_time));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The board the way `pacman.py` prints it: `%` walls, `.` food, `o`
+/// capsules, `P` pacmen and `G` ghosts, with the score below.
+fn board(world: &mut World) -> String {
+    let mut cells = world.resource::<Actions>().grid.map(|value| match value {
+        0 => '%',
+        1 => '.',
+        4 => 'o',
+        _ => ' ',
+    });
+    let mut agent_query = world.query::<(&Agent, &CellPosition, &AgentScore, Option<&Ghost>)>();
+    let mut score = 0.0;
+    for (agent, position, agent_score, ghost) in agent_query.iter(world) {
+        if let Some(cell) = cells.get_mut((position.x as usize, position.y as usize)) {
+            *cell = if ghost.is_some() { 'G' } else { 'P' };
+        }
+        if agent.id == 0 {
+            score = agent_score.score;
+        }
+    }
+    let (width, height) = cells.dim();
+    let mut board = String::new();
+    // Rows go down the screen like in the window, where y = 0 is the top row.
+    for y in 0..height {
+        board.extend((0..width).map(|x| cells[(x, y)]));
+        board.push('\n');
+    }
+    let _ = write!(board, "Score: {}", score);
+    board
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        Options::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn layouts_are_looked_for_by_name_or_path() {
+        let options = parse("-l smallClassic").unwrap();
+        assert_eq!(
+            options.layout,
+            Some(PathBuf::from("./assets/layouts/smallClassic.json"))
+        );
+        assert!(options.skips_menu());
+        let options = parse("--layout replays/game.replay").unwrap();
+        assert_eq!(options.layout, Some(PathBuf::from("replays/game.replay")));
+        assert!(parse("-q -p random -l replays/game.replay").is_err());
+    }
+
+    #[test]
+    fn agent_args_need_an_agent_that_uses_them() {
+        let options = parse("-p qlearning -a epsilon=0.05,alpha=0.2").unwrap();
+        let Some(ControllerKind::QLearning(settings)) = options.pacman else {
+            panic!("expected a q-learning pacman, got {:?}", options.pacman);
+        };
+        assert_eq!((settings.epsilon, settings.alpha), (0.05, 0.2));
+        assert!(parse("-p random -a epsilon=0.05").is_err());
+        assert!(parse("-a epsilon=0.05").is_err());
+        assert!(parse("-p qlearning -a epsilon").is_err());
+    }
+
+    #[test]
+    fn unknown_options_and_missing_values_are_errors() {
+        assert_eq!(parse("--fast").unwrap_err(), "Unknown option --fast");
+        assert_eq!(parse("-n").unwrap_err(), "-n needs a value");
+        assert_eq!(parse("-p random -l").unwrap_err(), "-l needs a value");
+        assert!(parse("-n many").is_err());
+        assert!(parse("-p pacbot").is_err());
+    }
+
+    #[test]
+    fn frame_times_are_finite_and_not_negative() {
+        assert_eq!(parse("--frame-time 0.05").unwrap().frame_time, Some(0.05));
+        assert_eq!(parse("--frame-time 0").unwrap().frame_time, Some(0.0));
+        for value in ["-1", "NaN", "inf"] {
+            assert!(
+                parse(&format!("--frame-time {}", value)).is_err(),
+                "accepted {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn gridworld_settings_replace_those_of_the_layout() {
+        let layout = Layout::load("assets/layouts/bridgeGrid.json").unwrap();
+        let options = parse("-l bridgeGrid --noise 0 --living-reward -0.5").unwrap();
+        let settings = options.mdp_settings(&layout);
+        assert_eq!(
+            (settings.noise, settings.living_reward, settings.discount),
+            (0.0, -0.5, 0.9)
+        );
+        assert!(parse("--noise 1.5").is_err());
+        assert!(parse("--discount").is_err());
+    }
+}
@@ -0,0 +1,570 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+};
+
+use bevy::prelude::{
+    info, Added, App, Camera, Changed, Commands, Component, Entity, EventWriter, GlobalTransform,
+    Input, IntoSystemDescriptor, KeyCode, MouseButton, Plugin, Query, Res, ResMut, Resource,
+    SystemSet, Windows,
+};
+use rand::seq::SliceRandom;
+
+use crate::{
+    cell::CellPosition,
+    dqn::{DqnController, DqnSettings},
+    fog::Memory,
+    game::{self, AgentScore, Episode, Ghost, Visited},
+    grid::{self, GridConfig},
+    imitation::ImitationController,
+    inference::{Belief, GhostTracking},
+    learning::{
+        ApproximateQController, ApproximateSettings, LearningSettings, TabularAlgorithm,
+        TabularController,
+    },
+    movement::{self, Actions, Direction, Movement, TransitionModel},
+    overlay::ValueOverlay,
+    rng::{GameRng, Stream, StreamRng},
+    simulation::{self, KeyboardBuffer, SimulationClock},
+    Agent, AppState, UpdateCell,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct GhostView {
+    pub id: u32,
+    pub position: CellPosition,
+    pub scared_timer: u32,
+}
+
+/// What an agent is allowed to look at when choosing its next move.
+pub struct GameView<'a> {
+    pub agent: &'a Agent,
+    pub position: CellPosition,
+    pub actions: &'a Actions,
+    pub agents: &'a [(u32, CellPosition)],
+    pub ghosts: &'a [GhostView],
+    /// Noisy distance to every ghost, in id order, when a pacman tracks the
+    /// ghosts instead of seeing them; empty otherwise.
+    pub readings: &'a [Option<u32>],
+    pub beliefs: &'a [Belief],
+    /// Cells the agent went through since the episode started.
+    pub visited: &'a HashSet<CellPosition>,
+    pub keyboard: Option<Direction>,
+    pub selected: bool,
+}
+
+impl GameView<'_> {
+    pub fn is_ghost(&self) -> bool {
+        self.ghosts.iter().any(|ghost| ghost.id == self.agent.id)
+    }
+}
+
+pub fn ghost_views<'a>(
+    agents: impl Iterator<Item = (&'a Agent, &'a CellPosition, Option<&'a Ghost>)>,
+) -> Vec<GhostView> {
+    agents
+        .filter_map(|(agent, position, ghost)| {
+            ghost.map(|ghost| GhostView {
+                id: agent.id,
+                position: *position,
+                scared_timer: ghost.scared_timer,
+            })
+        })
+        .collect()
+}
+
+/// What pacmen are told about the ghosts: their positions, or only the
+/// readings and beliefs while the ghosts are tracked.
+struct GhostSight<'a> {
+    tracked: bool,
+    pacmen: Vec<(u32, CellPosition)>,
+    readings: &'a [Option<u32>],
+    beliefs: &'a [Belief],
+}
+
+impl<'a> GhostSight<'a> {
+    fn new(
+        tracking: Option<&'a GhostTracking>,
+        agents: &[(u32, CellPosition)],
+        ghosts: &[GhostView],
+    ) -> Self {
+        match tracking {
+            Some(tracking) if tracking.kind.is_some() => Self {
+                tracked: true,
+                pacmen: agents
+                    .iter()
+                    .filter(|(id, _)| ghosts.iter().all(|ghost| ghost.id != *id))
+                    .copied()
+                    .collect(),
+                readings: &tracking.readings,
+                beliefs: &tracking.beliefs,
+            },
+            _ => Self {
+                tracked: false,
+                pacmen: Vec::new(),
+                readings: &[],
+                beliefs: &[],
+            },
+        }
+    }
+}
+
+pub trait AgentController: Send + Sync {
+    fn next_direction(&mut self, view: &GameView) -> Option<Direction>;
+
+    /// Called after the game rules ran on a tick in which the agent moved,
+    /// with the reward it got and whether the episode just ended.
+    fn observe(&mut self, _view: &GameView, _reward: f32, _done: bool) {}
+
+    /// Switches learning controllers between training and exploitation.
+    fn set_training(&mut self, _training: bool) {}
+
+    /// What the controller has learnt, drawn on the grid.
+    fn overlay(&self) -> Option<ValueOverlay> {
+        None
+    }
+
+    /// Generator of the controller's random choices, saved with the game so
+    /// that going back to a snapshot draws the same numbers again.
+    fn rng(&mut self) -> Option<&mut StreamRng> {
+        None
+    }
+}
+
+#[derive(Component)]
+pub struct Controller {
+    pub kind: ControllerKind,
+    pub brain: Box<dyn AgentController>,
+}
+
+impl Controller {
+    pub fn new(kind: &ControllerKind, rng: StreamRng) -> Self {
+        Self {
+            kind: kind.clone(),
+            brain: kind.build(rng),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControllerKind {
+    Keyboard,
+    /// Moves are sent from outside the simulation, for example by a training loop.
+    External,
+    Random,
+    Scripted(Vec<Direction>),
+    Search,
+    QLearning(LearningSettings),
+    Sarsa(LearningSettings),
+    ApproximateQ(ApproximateSettings),
+    Dqn(DqnSettings),
+    /// Plays a policy cloned from recorded keyboard play, loaded from this file.
+    Imitation(PathBuf),
+}
+
+impl ControllerKind {
+    /// Controllers drawing random numbers draw them from `rng`.
+    pub fn build(&self, rng: StreamRng) -> Box<dyn AgentController> {
+        match self {
+            ControllerKind::Keyboard => Box::new(KeyboardController),
+            ControllerKind::External => Box::new(ExternalController),
+            ControllerKind::Random => Box::new(RandomController { rng }),
+            ControllerKind::Scripted(path) => Box::new(ScriptedController::new(path.clone())),
+            ControllerKind::Search => Box::new(SearchController),
+            ControllerKind::QLearning(settings) => Box::new(TabularController::new(
+                TabularAlgorithm::QLearning,
+                *settings,
+                rng,
+            )),
+            ControllerKind::Sarsa(settings) => Box::new(TabularController::new(
+                TabularAlgorithm::Sarsa,
+                *settings,
+                rng,
+            )),
+            ControllerKind::ApproximateQ(settings) => {
+                Box::new(ApproximateQController::new(settings.clone(), rng))
+            }
+            ControllerKind::Dqn(settings) => Box::new(DqnController::new(settings.clone(), rng)),
+            ControllerKind::Imitation(path) => Box::new(ImitationController::new(path)),
+        }
+    }
+
+    /// Next kind in the order the `C` key goes through them.
+    pub fn next(&self, settings: &LearningSettings) -> ControllerKind {
+        match self {
+            ControllerKind::Keyboard => ControllerKind::Random,
+            ControllerKind::Random => ControllerKind::Search,
+            ControllerKind::Search => ControllerKind::QLearning(*settings),
+            ControllerKind::QLearning(_) => ControllerKind::Sarsa(*settings),
+            ControllerKind::Sarsa(_) => ControllerKind::ApproximateQ(ApproximateSettings {
+                learning: *settings,
+                ..Default::default()
+            }),
+            ControllerKind::ApproximateQ(_) => ControllerKind::Dqn(DqnSettings::default()),
+            ControllerKind::Dqn(_) => ControllerKind::Imitation(PathBuf::from("imitation.json")),
+            _ => ControllerKind::Keyboard,
+        }
+    }
+}
+
+/// Agents choosing their moves and the moves being applied, with or without a window.
+pub struct ControllerPlugin;
+
+impl Plugin for ControllerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ControllerConfig>()
+            .init_resource::<SelectedAgent>()
+            .init_resource::<TransitionModel>()
+            .add_event::<Movement>()
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(
+                        drive_agents
+                            .after(simulation::advance_clock)
+                            .after(simulation::buffer_keyboard),
+                    )
+                    .with_system(movement::movement.after(drive_agents))
+                    .with_system(
+                        observe_rewards
+                            .after(game::track_visited)
+                            .before(game::reset_episode),
+                    ),
+            );
+    }
+}
+
+/// Which controller each agent gets when the layout is spawned.
+#[derive(Resource, Debug, Clone)]
+pub struct ControllerConfig {
+    pub default: ControllerKind,
+    pub ghosts: ControllerKind,
+    pub agents: HashMap<u32, ControllerKind>,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            default: ControllerKind::Keyboard,
+            ghosts: ControllerKind::Random,
+            agents: HashMap::new(),
+        }
+    }
+}
+
+impl ControllerConfig {
+    pub fn kind_for(&self, id: u32) -> &ControllerKind {
+        self.agents.get(&id).unwrap_or(&self.default)
+    }
+
+    pub fn ghost_kind_for(&self, id: u32) -> &ControllerKind {
+        self.agents.get(&id).unwrap_or(&self.ghosts)
+    }
+}
+
+pub struct KeyboardController;
+
+impl AgentController for KeyboardController {
+    fn next_direction(&mut self, view: &GameView) -> Option<Direction> {
+        if !view.selected {
+            return None;
+        }
+        view.keyboard
+    }
+}
+
+pub struct RandomController {
+    rng: StreamRng,
+}
+
+impl AgentController for RandomController {
+    fn next_direction(&mut self, view: &GameView) -> Option<Direction> {
+        view.actions
+            .legal_directions(&view.position)
+            .choose(&mut self.rng)
+            .copied()
+    }
+
+    fn rng(&mut self) -> Option<&mut StreamRng> {
+        Some(&mut self.rng)
+    }
+}
+
+/// Replays a fixed list of directions, one per move, then stops.
+pub struct ScriptedController {
+    path: VecDeque<Direction>,
+}
+
+impl ScriptedController {
+    pub fn new(path: Vec<Direction>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl AgentController for ScriptedController {
+    fn next_direction(&mut self, _view: &GameView) -> Option<Direction> {
+        self.path.pop_front()
+    }
+}
+
+pub struct ExternalController;
+
+impl AgentController for ExternalController {
+    fn next_direction(&mut self, _view: &GameView) -> Option<Direction> {
+        None
+    }
+}
+
+/// Walks the shortest path to the closest objective.
+pub struct SearchController;
+
+impl AgentController for SearchController {
+    fn next_direction(&mut self, view: &GameView) -> Option<Direction> {
+        let objectives = view.actions.get_objectives();
+        shortest_path(view.actions, &view.position, |position| {
+            *position != view.position && objectives.contains(position)
+        })
+        .and_then(|path| path.first().copied())
+    }
+}
+
+/// The agent driven by the keyboard controller.
+#[derive(Resource, Debug, Default, Clone, Copy)]
+pub struct SelectedAgent(pub u32);
+
+/// Breadth first search from `start` to the closest cell accepted by `is_goal`.
+pub fn shortest_path(
+    actions: &Actions,
+    start: &CellPosition,
+    is_goal: impl Fn(&CellPosition) -> bool,
+) -> Option<Vec<Direction>> {
+    let mut parents: HashMap<CellPosition, (CellPosition, Direction)> = HashMap::new();
+    let mut frontier = VecDeque::from([*start]);
+
+    while let Some(position) = frontier.pop_front() {
+        if is_goal(&position) {
+            let mut path = Vec::new();
+            let mut current = position;
+            while let Some((parent, direction)) = parents.get(&current) {
+                path.push(*direction);
+                current = *parent;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        for direction in actions.legal_directions(&position) {
+            let next = actions.next_position(&position, direction);
+            if next != *start && !parents.contains_key(&next) {
+                parents.insert(next, (position, direction));
+                frontier.push_back(next);
+            }
+        }
+    }
+    None
+}
+
+#[allow(clippy::type_complexity)]
+pub fn drive_agents(
+    actions: Res<Actions>,
+    clock: Res<SimulationClock>,
+    mut keyboard_buffer: ResMut<KeyboardBuffer>,
+    selected_agent: Res<SelectedAgent>,
+    mut movement_event: EventWriter<Movement>,
+    tracking: Option<Res<GhostTracking>>,
+    mut agent_query: Query<(
+        &Agent,
+        &CellPosition,
+        &Visited,
+        &mut Controller,
+        Option<&Ghost>,
+        Option<&Memory>,
+    )>,
+) {
+    if !clock.just_ticked() {
+        return;
+    }
+    let keyboard = keyboard_buffer.0.take();
+    let agents = agent_query
+        .iter()
+        .map(|(agent, position, _, _, _, _)| (agent.id, *position))
+        .collect::<Vec<_>>();
+    let ghosts = ghost_views(
+        agent_query
+            .iter()
+            .map(|(agent, position, _, _, ghost, _)| (agent, position, ghost)),
+    );
+
+    let sight = GhostSight::new(tracking.as_deref(), &agents, &ghosts);
+
+    for (agent, position, visited, mut controller, ghost, memory) in agent_query.iter_mut() {
+        let (agents, ghosts) = if sight.tracked && ghost.is_none() {
+            (&sight.pacmen, &[][..])
+        } else {
+            (&agents, &ghosts[..])
+        };
+        let seen =
+            memory.map(|memory| (memory.visible_agents(agents), memory.visible_ghosts(ghosts)));
+        let view = GameView {
+            agent,
+            position: *position,
+            actions: memory.map_or(&actions, |memory| &memory.actions),
+            agents: seen.as_ref().map_or(agents, |(agents, _)| agents),
+            ghosts: seen.as_ref().map_or(ghosts, |(_, ghosts)| ghosts),
+            readings: sight.readings,
+            beliefs: sight.beliefs,
+            visited: &visited.0,
+            keyboard,
+            selected: agent.id == selected_agent.0,
+        };
+        if let Some(direction) = controller.brain.next_direction(&view) {
+            movement_event.send(Movement::new(agent.id, direction));
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn observe_rewards(
+    actions: Res<Actions>,
+    clock: Res<SimulationClock>,
+    episode: Res<Episode>,
+    selected_agent: Res<SelectedAgent>,
+    tracking: Option<Res<GhostTracking>>,
+    mut agent_query: Query<(
+        &Agent,
+        &CellPosition,
+        &AgentScore,
+        &Visited,
+        &mut Controller,
+        Option<&Ghost>,
+        Option<&Memory>,
+    )>,
+) {
+    if !clock.just_ticked() {
+        return;
+    }
+    let agents = agent_query
+        .iter()
+        .map(|(agent, position, _, _, _, _, _)| (agent.id, *position))
+        .collect::<Vec<_>>();
+    let ghosts = ghost_views(
+        agent_query
+            .iter()
+            .map(|(agent, position, _, _, _, ghost, _)| (agent, position, ghost)),
+    );
+
+    let sight = GhostSight::new(tracking.as_deref(), &agents, &ghosts);
+
+    for (agent, position, score, visited, mut controller, ghost, memory) in agent_query.iter_mut() {
+        let (agents, ghosts) = if sight.tracked && ghost.is_none() {
+            (&sight.pacmen, &[][..])
+        } else {
+            (&agents, &ghosts[..])
+        };
+        let seen =
+            memory.map(|memory| (memory.visible_agents(agents), memory.visible_ghosts(ghosts)));
+        let view = GameView {
+            agent,
+            position: *position,
+            actions: memory.map_or(&actions, |memory| &memory.actions),
+            agents: seen.as_ref().map_or(agents, |(agents, _)| agents),
+            ghosts: seen.as_ref().map_or(ghosts, |(_, ghosts)| ghosts),
+            readings: sight.readings,
+            beliefs: sight.beliefs,
+            visited: &visited.0,
+            keyboard: None,
+            selected: agent.id == selected_agent.0,
+        };
+        controller
+            .brain
+            .observe(&view, score.last_reward, episode.done);
+    }
+}
+
+/// Gives the selected agent the next kind of controller.
+pub fn cycle_controller(
+    keyboard_input: Res<Input<KeyCode>>,
+    selected_agent: Res<SelectedAgent>,
+    learning_settings: Res<LearningSettings>,
+    mut rng: ResMut<GameRng>,
+    mut controller_query: Query<(&Agent, &mut Controller, Option<&Ghost>)>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::C) {
+        return;
+    }
+    for (agent, mut controller, ghost) in controller_query.iter_mut() {
+        if agent.id == selected_agent.0 {
+            let stream = match ghost {
+                Some(_) => Stream::Ghosts,
+                None => Stream::Agents,
+            };
+            *controller =
+                Controller::new(&controller.kind.next(&learning_settings), rng.fork(stream));
+            info!("Agent {} is now driven by {:?}", agent.id, controller.kind);
+        }
+    }
+}
+
+pub fn cycle_selected_agent(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut selected_agent: ResMut<SelectedAgent>,
+    agent_query: Query<&Agent>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::Tab) {
+        return;
+    }
+    let mut ids = agent_query.iter().map(|agent| agent.id).collect::<Vec<_>>();
+    ids.sort_unstable();
+    if let Some(next) = ids
+        .iter()
+        .find(|id| **id > selected_agent.0)
+        .or_else(|| ids.first())
+    {
+        selected_agent.0 = *next;
+    }
+}
+
+pub fn click_selected_agent(
+    mouse_input: Res<Input<MouseButton>>,
+    windows: Res<Windows>,
+    grid_config: Res<GridConfig>,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    agent_query: Query<(&Agent, &CellPosition)>,
+    mut selected_agent: ResMut<SelectedAgent>,
+) {
+    if !mouse_input.just_pressed(MouseButton::Left) {
+        return;
+    }
+    let Some(cursor) = windows
+        .get_primary()
+        .and_then(|window| window.cursor_position())
+    else {
+        return;
+    };
+    for (camera, camera_transform) in camera_query.iter() {
+        let Some(ray) = camera.viewport_to_world(camera_transform, cursor) else {
+            continue;
+        };
+        let clicked = CellPosition::from_screen_position(ray.origin.x, ray.origin.y, &grid_config);
+        if let Some((agent, _)) = agent_query
+            .iter()
+            .find(|(_, position)| Some(**position) == clicked)
+        {
+            selected_agent.0 = agent.id;
+        }
+    }
+}
+
+pub fn highlight_selected_agent(
+    mut commands: Commands,
+    selected_agent: Res<SelectedAgent>,
+    added_query: Query<(), Added<Agent>>,
+    ghost_changes: Query<(), Changed<Ghost>>,
+    agent_query: Query<(Entity, &Agent, Option<&Ghost>)>,
+) {
+    if !selected_agent.is_changed() && added_query.is_empty() && ghost_changes.is_empty() {
+        return;
+    }
+    for (entity, agent, ghost) in agent_query.iter() {
+        let color = grid::agent_color(agent.id == selected_agent.0, ghost);
+        commands.entity(entity).insert(UpdateCell { color });
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::info;
+use ndarray::prelude::*;
+use rand::{seq::SliceRandom, Rng};
+use serde::Serialize;
+
+use crate::{
+    layout::Layout,
+    rng::{GameRng, Stream},
+};
+
+/// Parameters of a procedurally generated maze.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeneratorSettings {
+    pub width: usize,
+    pub height: usize,
+    pub ghosts: usize,
+    pub capsules: usize,
+    /// Share of the free cells holding food.
+    pub food: f32,
+    /// Share of the walls left by the maze that are knocked down to open loops.
+    pub loops: f32,
+}
+
+impl Default for GeneratorSettings {
+    fn default() -> Self {
+        Self::with_difficulty(0.5)
+    }
+}
+
+impl GeneratorSettings {
+    /// From a tiny open maze without ghosts at `0.0` to a large one with
+    /// three ghosts and few ways around them at `1.0`.
+    pub fn with_difficulty(difficulty: f32) -> Self {
+        let difficulty = difficulty.clamp(0.0, 1.0);
+        Self {
+            width: 7 + 2 * (6.0 * difficulty).round() as usize,
+            height: 5 + 2 * (2.0 * difficulty).round() as usize,
+            ghosts: (3.0 * difficulty).round() as usize,
+            capsules: 2 - difficulty.round() as usize,
+            food: 0.3 + 0.7 * difficulty,
+            loops: 0.6 - 0.4 * difficulty,
+        }
+    }
+
+    /// A random maze: walls sit between the cells of even coordinates, every
+    /// free cell can be reached and the ghosts start far from the pacman.
+    pub fn generate(&self, rng: &mut impl Rng) -> Layout {
+        let (width, height) = (self.width.max(3), self.height.max(3));
+        let mut grid = Array2::<i8>::zeros((width, height));
+        let cells = iproduct!((0..width).step_by(2), (0..height).step_by(2)).collect::<Vec<_>>();
+        let neighbours = |(x, y): (usize, usize)| {
+            [(2, 0), (-2, 0), (0, 2), (0, -2)]
+                .into_iter()
+                .map(move |(dx, dy)| (x as i64 + dx, y as i64 + dy))
+                .filter(|(x, y)| (0..width as i64).contains(x) && (0..height as i64).contains(y))
+                .map(|(x, y)| (x as usize, y as usize))
+        };
+
+        // Depth first maze over the cells.
+        let start = *cells.choose(rng).unwrap();
+        grid[start] = 2;
+        let mut stack = vec![start];
+        while let Some(&cell) = stack.last() {
+            let unvisited = neighbours(cell)
+                .filter(|next| grid[*next] == 0)
+                .collect::<Vec<_>>();
+            match unvisited.choose(rng) {
+                Some(&next) => {
+                    grid[((cell.0 + next.0) / 2, (cell.1 + next.1) / 2)] = 2;
+                    grid[next] = 2;
+                    stack.push(next);
+                }
+                None => {
+                    stack.pop();
+                }
+            }
+        }
+        for cell in cells.iter() {
+            for next in neighbours(*cell) {
+                let between = ((cell.0 + next.0) / 2, (cell.1 + next.1) / 2);
+                if grid[between] == 0 && rng.gen::<f32>() < self.loops {
+                    grid[between] = 2;
+                }
+            }
+        }
+
+        let mut free = grid
+            .indexed_iter()
+            .filter(|(_, value)| **value == 2)
+            .map(|(position, _)| position)
+            .collect::<Vec<_>>();
+        free.shuffle(rng);
+        let pacman = free.pop().unwrap();
+        grid[pacman] = -1;
+
+        let distances = distances_from(&grid, pacman);
+        free.sort_by_key(|position| std::cmp::Reverse(distances[*position]));
+        let far = free.len().div_ceil(3).max(self.ghosts.min(free.len()));
+        let mut ghosts = free.drain(..far).collect::<Vec<_>>();
+        ghosts.shuffle(rng);
+        for ghost in ghosts.drain(..self.ghosts.min(ghosts.len())) {
+            grid[ghost] = 3;
+        }
+        free.extend(ghosts);
+        free.shuffle(rng);
+
+        for capsule in free.drain(..self.capsules.min(free.len())) {
+            grid[capsule] = 4;
+        }
+        let mut food = 0;
+        for position in free.iter() {
+            if rng.gen::<f32>() < self.food {
+                grid[*position] = 1;
+                food += 1;
+            }
+        }
+        if food == 0 {
+            if let Some(position) = free.first() {
+                grid[*position] = 1;
+            }
+        }
+
+        Layout {
+            name: format!("generated_{}x{}_{}g", width, height, self.ghosts),
+            grid,
+            terminals: Vec::new(),
+            mdp: None,
+        }
+    }
+}
+
+/// Number of moves from `start` to every cell, `usize::MAX` for walls.
+fn distances_from(grid: &Array2<i8>, start: (usize, usize)) -> Array2<usize> {
+    let (width, height) = grid.dim();
+    let mut distances = Array2::from_elem(grid.raw_dim(), usize::MAX);
+    distances[start] = 0;
+    let mut queue = VecDeque::from([start]);
+    while let Some((x, y)) = queue.pop_front() {
+        let next = [
+            (x.wrapping_sub(1), y),
+            (x + 1, y),
+            (x, y.wrapping_sub(1)),
+            (x, y + 1),
+        ];
+        for position in next {
+            if position.0 < width
+                && position.1 < height
+                && grid[position] != 0
+                && distances[position] == usize::MAX
+            {
+                distances[position] = distances[(x, y)] + 1;
+                queue.push_back(position);
+            }
+        }
+    }
+    distances
+}
+
+/// Where the layout of an episode comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutSource {
+    File(PathBuf),
+    Generated(GeneratorSettings),
+}
+
+impl LayoutSource {
+    pub fn sample(&self, rng: &mut impl Rng) -> io::Result<Layout> {
+        match self {
+            LayoutSource::File(path) => Layout::load(path),
+            LayoutSource::Generated(settings) => Ok(settings.generate(rng)),
+        }
+    }
+}
+
+/// Stages played in order, moving on once the agent wins often enough.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurriculumSettings {
+    pub stages: Vec<LayoutSource>,
+    /// Success rate over the last `window` episodes needed to advance.
+    pub threshold: f32,
+    pub window: usize,
+}
+
+impl CurriculumSettings {
+    /// `levels` generated stages of increasing difficulty.
+    pub fn generated(levels: usize, threshold: f32, window: usize) -> Self {
+        let stages = (0..levels)
+            .map(|level| {
+                let difficulty = level as f32 / (levels.max(2) - 1) as f32;
+                LayoutSource::Generated(GeneratorSettings::with_difficulty(difficulty))
+            })
+            .collect();
+        Self {
+            stages,
+            threshold,
+            window,
+        }
+    }
+}
+
+/// How the layout of every training episode is chosen.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutSampling {
+    /// Uniformly among layout files.
+    Fixed(Vec<PathBuf>),
+    Generated(GeneratorSettings),
+    Curriculum(CurriculumSettings),
+}
+
+impl LayoutSampling {
+    /// Every pacman layout of a directory such as `assets/layouts`, leaving
+    /// out gridworlds and layouts without food or without a pacman.
+    pub fn from_dir(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut layouts = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let layout = Layout::load(&path)?;
+            let playable = layout.terminals.is_empty()
+                && layout.grid.iter().any(|value| *value == -1)
+                && layout.grid.iter().any(|value| *value == 1);
+            if playable {
+                layouts.push(path);
+            }
+        }
+        layouts.sort();
+        Ok(LayoutSampling::Fixed(layouts))
+    }
+}
+
+/// One line of the schedule log, written when an episode ends.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduleEntry {
+    pub episode: u64,
+    pub layout: String,
+    pub stage: usize,
+    pub won: bool,
+    pub success_rate: f32,
+}
+
+/// Draws the layout of every episode from the [`Stream::Generation`] stream
+/// and follows the results to move along a curriculum.
+pub struct LayoutSampler {
+    sampling: LayoutSampling,
+    rng: GameRng,
+    stage: usize,
+    results: VecDeque<bool>,
+    episode: u64,
+    current: Option<String>,
+    log: Option<File>,
+}
+
+impl LayoutSampler {
+    pub fn new(sampling: LayoutSampling, seed: u64) -> Self {
+        Self {
+            sampling,
+            rng: GameRng::new(seed),
+            stage: 0,
+            results: VecDeque::new(),
+            episode: 0,
+            current: None,
+            log: None,
+        }
+    }
+
+    /// Appends the schedule to `path`, one JSON line per episode.
+    pub fn with_log(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.log = Some(
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        );
+        Ok(self)
+    }
+
+    /// Draws the next layouts from `seed`, staying at the same stage.
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = GameRng::new(seed);
+    }
+
+    /// Curriculum stage being played, always `0` without a curriculum.
+    pub fn stage(&self) -> usize {
+        self.stage
+    }
+
+    pub fn success_rate(&self) -> f32 {
+        match self.results.len() {
+            0 => 0.0,
+            len => self.results.iter().filter(|won| **won).count() as f32 / len as f32,
+        }
+    }
+
+    /// Layout of the next episode.
+    pub fn sample(&mut self) -> io::Result<Layout> {
+        let rng = self.rng.stream(Stream::Generation);
+        let layout = match &self.sampling {
+            LayoutSampling::Fixed(paths) => {
+                let path = paths.choose(rng).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no layout to sample from")
+                })?;
+                Layout::load(path)?
+            }
+            LayoutSampling::Generated(settings) => settings.generate(rng),
+            LayoutSampling::Curriculum(curriculum) => curriculum
+                .stages
+                .get(self.stage)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no curriculum stage to play")
+                })?
+                .sample(rng)?,
+        };
+        self.current = Some(layout.name.clone());
+        Ok(layout)
+    }
+
+    /// Records how the episode on the last sampled layout ended, advancing the
+    /// curriculum when the success rate crosses its threshold.
+    pub fn record(&mut self, won: bool) -> io::Result<ScheduleEntry> {
+        let window = match &self.sampling {
+            LayoutSampling::Curriculum(curriculum) => curriculum.window.max(1),
+            _ => 100,
+        };
+        self.results.push_back(won);
+        while self.results.len() > window {
+            self.results.pop_front();
+        }
+
+        let entry = ScheduleEntry {
+            episode: self.episode,
+            layout: self.current.take().unwrap_or_default(),
+            stage: self.stage,
+            won,
+            success_rate: self.success_rate(),
+        };
+        if let Some(log) = self.log.as_mut() {
+            serde_json::to_writer(&mut *log, &entry)?;
+            writeln!(log)?;
+        }
+        self.episode += 1;
+
+        if let LayoutSampling::Curriculum(curriculum) = &self.sampling {
+            let full = self.results.len() >= window;
+            if full
+                && entry.success_rate >= curriculum.threshold
+                && self.stage + 1 < curriculum.stages.len()
+            {
+                self.stage += 1;
+                self.results.clear();
+                info!(
+                    "Curriculum advances to stage {} after episode {}",
+                    self.stage, entry.episode
+                );
+            }
+        }
+        Ok(entry)
+    }
+}
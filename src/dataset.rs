@@ -0,0 +1,618 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use ndarray::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// One episode seen from one agent: the observation before every action,
+/// the action taken, the reward it got and whether the episode ended there.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trajectory {
+    pub layout: String,
+    pub seed: u64,
+    /// `(steps, planes, width, height)`.
+    pub observations: Array4<f32>,
+    pub actions: Array1<i64>,
+    pub rewards: Array1<f32>,
+    pub dones: Array1<bool>,
+}
+
+impl Trajectory {
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    fn to_npz_entries(&self) -> io::Result<Vec<(String, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        let mut add = |name: &str, write: &dyn Fn(&mut Vec<u8>) -> io::Result<()>| {
+            let mut bytes = Vec::new();
+            write(&mut bytes)?;
+            entries.push((format!("{}.npy", name), bytes));
+            io::Result::Ok(())
+        };
+        add("observations", &|out| write_npy(out, &self.observations))?;
+        add("actions", &|out| write_npy(out, &self.actions))?;
+        add("rewards", &|out| write_npy(out, &self.rewards))?;
+        add("dones", &|out| write_npy(out, &self.dones))?;
+        add("layout", &|out| write_npy_str(out, &self.layout))?;
+        add("seed", &|out| write_npy(out, &arr0(self.seed)))?;
+        Ok(entries)
+    }
+
+    fn from_npz_entries(entries: &HashMap<String, Vec<u8>>) -> io::Result<Self> {
+        let entry = |name: &str| {
+            entries
+                .get(&format!("{}.npy", name))
+                .map(|bytes| bytes.as_slice())
+                .ok_or_else(|| invalid_data(format!("missing {}.npy", name)))
+        };
+        Ok(Self {
+            layout: read_npy_str(&mut entry("layout")?)?,
+            seed: read_npy::<u64>(&mut entry("seed")?)?
+                .iter()
+                .copied()
+                .next()
+                .unwrap_or_default(),
+            observations: into_dimension(read_npy(&mut entry("observations")?)?)?,
+            actions: into_dimension(read_npy(&mut entry("actions")?)?)?,
+            rewards: into_dimension(read_npy(&mut entry("rewards")?)?)?,
+            dones: into_dimension(read_npy(&mut entry("dones")?)?)?,
+        })
+    }
+}
+
+/// Collects the steps of an episode until it ends.
+#[derive(Debug, Default, Clone)]
+pub struct TrajectoryRecorder {
+    layout: String,
+    seed: u64,
+    observations: Vec<Array3<f32>>,
+    actions: Vec<i64>,
+    rewards: Vec<f32>,
+    dones: Vec<bool>,
+}
+
+impl TrajectoryRecorder {
+    pub fn new(layout: impl Into<String>, seed: u64) -> Self {
+        Self {
+            layout: layout.into(),
+            seed,
+            ..Default::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    pub fn push(&mut self, observation: Array3<f32>, action: usize, reward: f32, done: bool) {
+        self.observations.push(observation);
+        self.actions.push(action as i64);
+        self.rewards.push(reward);
+        self.dones.push(done);
+    }
+
+    pub fn finish(self) -> Trajectory {
+        let views = self
+            .observations
+            .iter()
+            .map(|observation| observation.view())
+            .collect::<Vec<_>>();
+        let observations = if views.is_empty() {
+            Array4::zeros((0, 0, 0, 0))
+        } else {
+            ndarray::stack(Axis(0), &views).unwrap()
+        };
+        Trajectory {
+            layout: self.layout,
+            seed: self.seed,
+            observations,
+            actions: Array1::from(self.actions),
+            rewards: Array1::from(self.rewards),
+            dones: Array1::from(self.dones),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DatasetFormat {
+    /// One `episode_#####.npz` archive per episode.
+    #[default]
+    Npz,
+    /// One `episode_#####` directory of `.npy` files per episode.
+    Npy,
+    /// Every episode on its own line of `trajectories.jsonl`.
+    Jsonl,
+}
+
+/// Writes episodes one after the other into a dataset directory.
+pub struct DatasetWriter {
+    dir: PathBuf,
+    format: DatasetFormat,
+    episodes: usize,
+}
+
+impl DatasetWriter {
+    pub fn new(dir: impl Into<PathBuf>, format: DatasetFormat) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let episodes = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("episode_"))
+            .count();
+        Ok(Self {
+            dir,
+            format,
+            episodes,
+        })
+    }
+
+    pub fn write(&mut self, trajectory: &Trajectory) -> io::Result<()> {
+        let name = format!("episode_{:05}", self.episodes);
+        match self.format {
+            DatasetFormat::Npz => {
+                let file = File::create(self.dir.join(format!("{}.npz", name)))?;
+                write_npz(file, &trajectory.to_npz_entries()?)?;
+            }
+            DatasetFormat::Npy => {
+                let dir = self.dir.join(name);
+                fs::create_dir_all(&dir)?;
+                for (name, bytes) in trajectory.to_npz_entries()? {
+                    fs::write(dir.join(name), bytes)?;
+                }
+            }
+            DatasetFormat::Jsonl => {
+                let mut file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.dir.join("trajectories.jsonl"))?;
+                serde_json::to_writer(&mut file, trajectory)?;
+                writeln!(file)?;
+            }
+        }
+        self.episodes += 1;
+        Ok(())
+    }
+}
+
+/// Every episode of a dataset directory, whatever format it was written in.
+pub fn load_dataset(dir: impl AsRef<Path>) -> io::Result<Vec<Trajectory>> {
+    let mut paths = fs::read_dir(dir.as_ref())?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    let mut trajectories = Vec::new();
+    for path in paths {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if path.is_dir() && name.starts_with("episode_") {
+            let entries = fs::read_dir(&path)?
+                .map(|entry| {
+                    let path = entry?.path();
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    Ok((name.to_string(), fs::read(&path)?))
+                })
+                .collect::<io::Result<HashMap<_, _>>>()?;
+            trajectories.push(Trajectory::from_npz_entries(&entries)?);
+        } else if name.ends_with(".npz") {
+            let entries = read_npz(File::open(&path)?)?;
+            trajectories.push(Trajectory::from_npz_entries(&entries)?);
+        } else if name.ends_with(".jsonl") {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    trajectories.push(serde_json::from_str(&line)?);
+                }
+            }
+        }
+    }
+    Ok(trajectories)
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn into_dimension<T, D: Dimension>(array: ArrayD<T>) -> io::Result<Array<T, D>> {
+    array
+        .into_dimensionality()
+        .map_err(|error| invalid_data(error.to_string()))
+}
+
+/// Element types that can be written to and read from `.npy` files.
+pub trait NpyElement: Copy {
+    const DESCR: &'static str;
+    const SIZE: usize;
+    fn write_le(self, out: &mut Vec<u8>);
+    fn read_le(bytes: &[u8]) -> Self;
+}
+
+impl NpyElement for f32 {
+    const DESCR: &'static str = "<f4";
+    const SIZE: usize = 4;
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend(self.to_le_bytes());
+    }
+    fn read_le(bytes: &[u8]) -> Self {
+        f32::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl NpyElement for i64 {
+    const DESCR: &'static str = "<i8";
+    const SIZE: usize = 8;
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend(self.to_le_bytes());
+    }
+    fn read_le(bytes: &[u8]) -> Self {
+        i64::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl NpyElement for u64 {
+    const DESCR: &'static str = "<u8";
+    const SIZE: usize = 8;
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend(self.to_le_bytes());
+    }
+    fn read_le(bytes: &[u8]) -> Self {
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl NpyElement for bool {
+    const DESCR: &'static str = "|b1";
+    const SIZE: usize = 1;
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.push(self as u8);
+    }
+    fn read_le(bytes: &[u8]) -> Self {
+        bytes[0] != 0
+    }
+}
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+fn write_npy_header(out: &mut impl Write, descr: &str, shape: &[usize]) -> io::Result<()> {
+    let shape = match shape {
+        [] => "()".to_string(),
+        [length] => format!("({},)", length),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|length| length.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    // Magic, version and header length take 10 bytes, the data starts 64 bytes aligned.
+    let padding = 63 - (10 + header.len()) % 64;
+    header.push_str(&" ".repeat(padding));
+    header.push('\n');
+
+    out.write_all(NPY_MAGIC)?;
+    out.write_all(&[1, 0])?;
+    out.write_all(&(header.len() as u16).to_le_bytes())?;
+    out.write_all(header.as_bytes())
+}
+
+/// Writes `array` in the NumPy `.npy` format, in C order.
+pub fn write_npy<T: NpyElement, D: Dimension>(
+    out: &mut impl Write,
+    array: &Array<T, D>,
+) -> io::Result<()> {
+    write_npy_header(out, T::DESCR, array.shape())?;
+    let mut bytes = Vec::with_capacity(array.len() * T::SIZE);
+    for value in array.iter() {
+        value.write_le(&mut bytes);
+    }
+    out.write_all(&bytes)
+}
+
+/// Writes a string as a zero dimensional NumPy unicode array.
+pub fn write_npy_str(out: &mut impl Write, value: &str) -> io::Result<()> {
+    let characters = value.chars().collect::<Vec<_>>();
+    write_npy_header(out, &format!("<U{}", characters.len().max(1)), &[])?;
+    let mut bytes = Vec::new();
+    for character in characters.iter() {
+        bytes.extend((*character as u32).to_le_bytes());
+    }
+    if characters.is_empty() {
+        bytes.extend(0u32.to_le_bytes());
+    }
+    out.write_all(&bytes)
+}
+
+/// Reads the header of a `.npy` file, returning its type and shape.
+fn read_npy_header(input: &mut impl Read) -> io::Result<(String, Vec<usize>)> {
+    let mut preamble = [0; 8];
+    input.read_exact(&mut preamble)?;
+    if &preamble[..6] != NPY_MAGIC {
+        return Err(invalid_data("not a npy file"));
+    }
+    let length = if preamble[6] == 1 {
+        let mut length = [0; 2];
+        input.read_exact(&mut length)?;
+        u16::from_le_bytes(length) as usize
+    } else {
+        let mut length = [0; 4];
+        input.read_exact(&mut length)?;
+        u32::from_le_bytes(length) as usize
+    };
+    let mut header = vec![0; length];
+    input.read_exact(&mut header)?;
+    let header = String::from_utf8_lossy(&header);
+
+    let value_of = |key: &str| {
+        let start = header
+            .find(&format!("'{}':", key))
+            .ok_or_else(|| invalid_data(format!("npy header without {}", key)))?
+            + key.len()
+            + 3;
+        Ok::<_, io::Error>(header[start..].trim_start())
+    };
+    if value_of("fortran_order")?.starts_with("True") {
+        return Err(invalid_data("fortran ordered npy files are not supported"));
+    }
+    let descr = value_of("descr")?;
+    let descr = descr
+        .trim_start_matches('\'')
+        .split('\'')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let shape = value_of("shape")?;
+    let shape = shape[1..shape.find(')').unwrap_or(1)]
+        .split(',')
+        .map(str::trim)
+        .filter(|length| !length.is_empty())
+        .map(|length| length.parse().map_err(|_| invalid_data("bad npy shape")))
+        .collect::<io::Result<Vec<usize>>>()?;
+    Ok((descr, shape))
+}
+
+pub fn read_npy<T: NpyElement>(input: &mut impl Read) -> io::Result<ArrayD<T>> {
+    let (descr, shape) = read_npy_header(input)?;
+    if descr != T::DESCR {
+        return Err(invalid_data(format!(
+            "expected {} values, found {}",
+            T::DESCR,
+            descr
+        )));
+    }
+    let count = shape.iter().product::<usize>();
+    let mut bytes = vec![0; count * T::SIZE];
+    input.read_exact(&mut bytes)?;
+    let values = bytes.chunks_exact(T::SIZE).map(T::read_le).collect();
+    ArrayD::from_shape_vec(IxDyn(&shape), values).map_err(|error| invalid_data(error.to_string()))
+}
+
+pub fn read_npy_str(input: &mut impl Read) -> io::Result<String> {
+    let (descr, _) = read_npy_header(input)?;
+    let length = descr
+        .strip_prefix("<U")
+        .and_then(|length| length.parse::<usize>().ok())
+        .ok_or_else(|| invalid_data(format!("expected a unicode string, found {}", descr)))?;
+    let mut bytes = vec![0; length * 4];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|code| u32::from_le_bytes(code.try_into().unwrap()))
+        .take_while(|code| *code != 0)
+        .filter_map(char::from_u32)
+        .collect())
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Writes an uncompressed zip archive, which is what `numpy.savez` produces.
+pub fn write_npz(mut out: impl Write, entries: &[(String, Vec<u8>)]) -> io::Result<()> {
+    // Version needed, flags, stored method, 1980-01-01 00:00.
+    const COMMON: [u8; 10] = [20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0];
+    let mut offset = 0u32;
+    let mut central = Vec::new();
+    for (name, data) in entries {
+        let crc = crc32(data);
+        let mut sizes = Vec::new();
+        sizes.extend(crc.to_le_bytes());
+        sizes.extend((data.len() as u32).to_le_bytes());
+        sizes.extend((data.len() as u32).to_le_bytes());
+        sizes.extend((name.len() as u16).to_le_bytes());
+        sizes.extend(0u16.to_le_bytes());
+
+        let mut local = Vec::new();
+        local.extend(0x0403_4b50u32.to_le_bytes());
+        local.extend(COMMON);
+        local.extend(&sizes);
+        local.extend(name.as_bytes());
+        out.write_all(&local)?;
+        out.write_all(data)?;
+
+        central.extend(0x0201_4b50u32.to_le_bytes());
+        central.extend(20u16.to_le_bytes());
+        central.extend(COMMON);
+        central.extend(&sizes);
+        // Comment length, disk, internal and external attributes.
+        central.extend([0; 10]);
+        central.extend(offset.to_le_bytes());
+        central.extend(name.as_bytes());
+        offset += (local.len() + data.len()) as u32;
+    }
+    out.write_all(&central)?;
+
+    let mut end = Vec::new();
+    end.extend(0x0605_4b50u32.to_le_bytes());
+    end.extend([0; 4]);
+    end.extend((entries.len() as u16).to_le_bytes());
+    end.extend((entries.len() as u16).to_le_bytes());
+    end.extend((central.len() as u32).to_le_bytes());
+    end.extend(offset.to_le_bytes());
+    end.extend(0u16.to_le_bytes());
+    out.write_all(&end)
+}
+
+/// Reads the files of an uncompressed zip archive such as the ones written by
+/// `numpy.savez` or [`write_npz`].
+pub fn read_npz(mut input: impl Read) -> io::Result<HashMap<String, Vec<u8>>> {
+    let mut archive = Vec::new();
+    input.read_to_end(&mut archive)?;
+    let u16_at = |at: usize| -> io::Result<usize> {
+        archive
+            .get(at..at + 2)
+            .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()) as usize)
+            .ok_or_else(|| invalid_data("truncated npz archive"))
+    };
+    let u32_at = |at: usize| -> io::Result<usize> {
+        archive
+            .get(at..at + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+            .ok_or_else(|| invalid_data("truncated npz archive"))
+    };
+
+    let end = (0..archive.len().saturating_sub(21))
+        .rev()
+        .find(|at| archive[*at..].starts_with(&0x0605_4b50u32.to_le_bytes()))
+        .ok_or_else(|| invalid_data("not a npz archive"))?;
+    let count = u16_at(end + 10)?;
+    let mut at = u32_at(end + 16)?;
+
+    let mut entries = HashMap::new();
+    for _ in 0..count {
+        if u32_at(at)? != 0x0201_4b50 {
+            return Err(invalid_data("corrupted npz directory"));
+        }
+        if u16_at(at + 10)? != 0 {
+            return Err(invalid_data("compressed npz archives are not supported"));
+        }
+        let size = u32_at(at + 20)?;
+        let name_length = u16_at(at + 28)?;
+        let extra_length = u16_at(at + 30)?;
+        let comment_length = u16_at(at + 32)?;
+        let local = u32_at(at + 42)?;
+        let name = String::from_utf8_lossy(
+            archive
+                .get(at + 46..at + 46 + name_length)
+                .ok_or_else(|| invalid_data("truncated npz archive"))?,
+        )
+        .to_string();
+
+        let data = local + 30 + u16_at(local + 26)? + u16_at(local + 28)?;
+        let bytes = archive
+            .get(data..data + size)
+            .ok_or_else(|| invalid_data("truncated npz archive"))?;
+        entries.insert(name, bytes.to_vec());
+        at += 46 + name_length + extra_length + comment_length;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: NpyElement + PartialEq + std::fmt::Debug, D: Dimension>(array: Array<T, D>) {
+        let mut bytes = Vec::new();
+        write_npy(&mut bytes, &array).unwrap();
+        let header_length = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_length) % 64, 0, "data is 64 bytes aligned");
+        let read = read_npy::<T>(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read, array.into_dyn());
+    }
+
+    fn trajectory() -> Trajectory {
+        let mut recorder = TrajectoryRecorder::new("smallGrid", 7);
+        recorder.push(Array3::from_elem((2, 3, 4), 0.5), 1, -1.0, false);
+        recorder.push(Array3::from_elem((2, 3, 4), 1.0), 3, 10.0, true);
+        recorder.finish()
+    }
+
+    #[test]
+    fn npy_round_trips_every_element_type() {
+        round_trip(Array::from_shape_fn((2, 3, 4), |(i, j, k)| {
+            i as f32 - j as f32 * 0.25 + k as f32 * 1e-3
+        }));
+        round_trip(array![-3i64, 0, i64::MAX, i64::MIN]);
+        round_trip(arr0(u64::MAX));
+        round_trip(array![[true, false], [false, true]]);
+        round_trip(Array2::<f32>::zeros((0, 5)));
+    }
+
+    #[test]
+    fn npy_rejects_another_element_type() {
+        let mut bytes = Vec::new();
+        write_npy(&mut bytes, &array![1.0f32, 2.0]).unwrap();
+        assert!(read_npy::<i64>(&mut bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn npy_strings_round_trip() {
+        for value in ["mediumClassic", "", "énigme"] {
+            let mut bytes = Vec::new();
+            write_npy_str(&mut bytes, value).unwrap();
+            assert_eq!(read_npy_str(&mut bytes.as_slice()).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+    }
+
+    #[test]
+    fn npz_round_trips() {
+        let entries = vec![
+            ("a.npy".to_string(), vec![1, 2, 3]),
+            ("b.npy".to_string(), Vec::new()),
+            ("longer_name.npy".to_string(), (0..=255).collect()),
+        ];
+        let mut archive = Vec::new();
+        write_npz(&mut archive, &entries).unwrap();
+        let read = read_npz(archive.as_slice()).unwrap();
+        assert_eq!(read, entries.into_iter().collect::<HashMap<_, _>>());
+    }
+
+    #[test]
+    fn trajectories_round_trip_through_npz_entries() {
+        let trajectory = trajectory();
+        let entries = trajectory.to_npz_entries().unwrap();
+        let mut archive = Vec::new();
+        write_npz(&mut archive, &entries).unwrap();
+        let read = Trajectory::from_npz_entries(&read_npz(archive.as_slice()).unwrap()).unwrap();
+        assert_eq!(read, trajectory);
+    }
+}
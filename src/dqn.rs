@@ -0,0 +1,610 @@
+use std::{collections::VecDeque, fs, io, path::PathBuf};
+
+use bevy::prelude::{info, warn};
+use ndarray::prelude::*;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    controller::{AgentController, GameView},
+    movement::Direction,
+    observation::ObservationConfig,
+    rng::StreamRng,
+};
+
+/// Fully connected layer, `output = input . weights + bias`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dense {
+    pub weights: Array2<f32>,
+    pub bias: Array1<f32>,
+}
+
+impl Dense {
+    /// He uniform initialisation, suited to the ReLU activations.
+    pub fn new(inputs: usize, outputs: usize, rng: &mut impl Rng) -> Self {
+        let limit = (6.0 / inputs as f32).sqrt();
+        Self {
+            weights: Array2::from_shape_fn((inputs, outputs), |_| rng.gen_range(-limit..limit)),
+            bias: Array1::zeros(outputs),
+        }
+    }
+}
+
+/// Adam moment estimates of one layer.
+#[derive(Debug, Clone)]
+struct Moments {
+    weights: (Array2<f32>, Array2<f32>),
+    bias: (Array1<f32>, Array1<f32>),
+}
+
+impl Moments {
+    fn new(layer: &Dense) -> Self {
+        Self {
+            weights: (
+                Array2::zeros(layer.weights.raw_dim()),
+                Array2::zeros(layer.weights.raw_dim()),
+            ),
+            bias: (
+                Array1::zeros(layer.bias.raw_dim()),
+                Array1::zeros(layer.bias.raw_dim()),
+            ),
+        }
+    }
+}
+
+/// Multi layer perceptron with ReLU hidden layers and a linear output,
+/// trained with Adam.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mlp {
+    pub layers: Vec<Dense>,
+    #[serde(skip)]
+    moments: Vec<Moments>,
+    #[serde(skip)]
+    updates: i32,
+}
+
+impl Mlp {
+    /// `sizes` lists the input size, every hidden size and the output size.
+    pub fn new(sizes: &[usize], rng: &mut impl Rng) -> Self {
+        let layers = sizes
+            .windows(2)
+            .map(|pair| Dense::new(pair[0], pair[1], rng))
+            .collect();
+        Self {
+            layers,
+            moments: Vec::new(),
+            updates: 0,
+        }
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.layers.first().map_or(0, |layer| layer.weights.nrows())
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.layers.last().map_or(0, |layer| layer.weights.ncols())
+    }
+
+    /// Outputs for a batch of inputs, one per row.
+    pub fn forward(&self, inputs: ArrayView2<f32>) -> Array2<f32> {
+        self.activations(inputs).pop().unwrap()
+    }
+
+    /// Number of weights and biases of every layer together.
+    pub fn parameter_count(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|layer| layer.weights.len() + layer.bias.len())
+            .sum()
+    }
+
+    /// Weights then bias of every layer, flattened in order.
+    pub fn parameters(&self) -> Vec<f32> {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.weights.iter().chain(layer.bias.iter()))
+            .copied()
+            .collect()
+    }
+
+    /// Replaces the parameters with values laid out as [`Mlp::parameters`] returns them.
+    pub fn set_parameters(&mut self, parameters: &[f32]) {
+        assert_eq!(parameters.len(), self.parameter_count());
+        let mut values = parameters.iter();
+        for layer in self.layers.iter_mut() {
+            for value in layer.weights.iter_mut().chain(layer.bias.iter_mut()) {
+                *value = *values.next().unwrap();
+            }
+        }
+        self.moments.clear();
+    }
+
+    pub fn predict(&self, input: ArrayView1<f32>) -> Array1<f32> {
+        self.forward(input.insert_axis(Axis(0)))
+            .index_axis_move(Axis(0), 0)
+    }
+
+    /// Input followed by the output of every layer.
+    pub fn activations(&self, inputs: ArrayView2<f32>) -> Vec<Array2<f32>> {
+        let mut activations = vec![inputs.to_owned()];
+        for (index, layer) in self.layers.iter().enumerate() {
+            let mut output = activations.last().unwrap().dot(&layer.weights) + &layer.bias;
+            if index + 1 < self.layers.len() {
+                output.mapv_inplace(|value| value.max(0.0));
+            }
+            activations.push(output);
+        }
+        activations
+    }
+
+    /// Gradients of the loss with respect to the weights and bias of every
+    /// layer, from `output_gradient`, its gradient with respect to the last of
+    /// `activations`.
+    pub fn gradients(
+        &self,
+        activations: &[Array2<f32>],
+        output_gradient: Array2<f32>,
+    ) -> Vec<(Array2<f32>, Array1<f32>)> {
+        let mut gradients = Vec::with_capacity(self.layers.len());
+        let mut gradient = output_gradient;
+        for index in (0..self.layers.len()).rev() {
+            let input = &activations[index];
+            gradients.push((input.t().dot(&gradient), gradient.sum_axis(Axis(0))));
+            if index > 0 {
+                let mut previous = gradient.dot(&self.layers[index].weights.t());
+                previous.zip_mut_with(input, |g, a| {
+                    if *a <= 0.0 {
+                        *g = 0.0
+                    }
+                });
+                gradient = previous;
+            }
+        }
+        gradients.reverse();
+        gradients
+    }
+
+    /// One Adam step on `output_gradient`, the gradient of the loss with
+    /// respect to the last of `activations`.
+    pub fn backward(
+        &mut self,
+        activations: &[Array2<f32>],
+        output_gradient: Array2<f32>,
+        learning_rate: f32,
+    ) {
+        let gradients = self.gradients(activations, output_gradient);
+        if self.moments.len() != self.layers.len() {
+            self.moments = self.layers.iter().map(Moments::new).collect();
+            self.updates = 0;
+        }
+        self.updates += 1;
+        let adam = Adam::new(learning_rate, self.updates);
+
+        for ((layer, moments), (weights, bias)) in self
+            .layers
+            .iter_mut()
+            .zip(self.moments.iter_mut())
+            .zip(&gradients)
+        {
+            adam.update(&mut layer.weights, &mut moments.weights, weights);
+            adam.update(&mut layer.bias, &mut moments.bias, bias);
+        }
+    }
+}
+
+struct Adam {
+    learning_rate: f32,
+    correction1: f32,
+    correction2: f32,
+}
+
+impl Adam {
+    const BETA1: f32 = 0.9;
+    const BETA2: f32 = 0.999;
+    const EPSILON: f32 = 1e-8;
+
+    fn new(learning_rate: f32, updates: i32) -> Self {
+        Self {
+            learning_rate,
+            correction1: 1.0 - Self::BETA1.powi(updates),
+            correction2: 1.0 - Self::BETA2.powi(updates),
+        }
+    }
+
+    fn update<D: Dimension>(
+        &self,
+        parameters: &mut Array<f32, D>,
+        moments: &mut (Array<f32, D>, Array<f32, D>),
+        gradient: &Array<f32, D>,
+    ) {
+        azip!((p in parameters, m in &mut moments.0, v in &mut moments.1, g in gradient) {
+            *m = Self::BETA1 * *m + (1.0 - Self::BETA1) * g;
+            *v = Self::BETA2 * *v + (1.0 - Self::BETA2) * g * g;
+            *p -= self.learning_rate * (*m / self.correction1)
+                / ((*v / self.correction2).sqrt() + Self::EPSILON);
+        });
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Transition {
+    pub state: Array1<f32>,
+    pub action: usize,
+    pub reward: f32,
+    pub next_state: Array1<f32>,
+    /// Actions allowed from `next_state`, indexed like [`Direction::ALL`].
+    pub next_legal: [bool; 4],
+    pub done: bool,
+}
+
+/// Fixed size memory of past transitions, oldest dropped first.
+#[derive(Debug, Clone)]
+pub struct ReplayBuffer {
+    capacity: usize,
+    transitions: VecDeque<Transition>,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            transitions: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transitions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transitions.is_empty()
+    }
+
+    pub fn push(&mut self, transition: Transition) {
+        if self.transitions.len() == self.capacity {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(transition);
+    }
+
+    /// `size` transitions drawn uniformly, with replacement.
+    pub fn sample(&self, size: usize, rng: &mut impl Rng) -> Vec<&Transition> {
+        (0..size)
+            .map(|_| &self.transitions[rng.gen_range(0..self.transitions.len())])
+            .collect()
+    }
+}
+
+/// Exploration rate decaying linearly from `start` to `end` over `decay_steps`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EpsilonSchedule {
+    pub start: f32,
+    pub end: f32,
+    pub decay_steps: u32,
+}
+
+impl Default for EpsilonSchedule {
+    fn default() -> Self {
+        Self {
+            start: 1.0,
+            end: 0.05,
+            decay_steps: 10_000,
+        }
+    }
+}
+
+impl EpsilonSchedule {
+    pub fn value(&self, step: u32) -> f32 {
+        let progress = (step as f32 / self.decay_steps.max(1) as f32).min(1.0);
+        self.start + (self.end - self.start) * progress
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DqnSettings {
+    pub observation: ObservationConfig,
+    pub hidden: Vec<usize>,
+    pub learning_rate: f32,
+    pub discount: f32,
+    pub batch_size: usize,
+    pub replay_capacity: usize,
+    /// Transitions collected before the first update.
+    pub train_start: usize,
+    /// Steps between two copies of the online network into the target network.
+    pub target_update: u32,
+    pub epsilon: EpsilonSchedule,
+    /// Checkpoint to start from; the agent starts in evaluation when it is given.
+    pub load: Option<PathBuf>,
+    /// Directory checkpoints are written to after every episode.
+    pub save_dir: PathBuf,
+}
+
+impl Default for DqnSettings {
+    fn default() -> Self {
+        Self {
+            observation: ObservationConfig::default(),
+            hidden: vec![128, 64],
+            learning_rate: 1e-3,
+            discount: 0.95,
+            batch_size: 32,
+            replay_capacity: 10_000,
+            train_start: 500,
+            target_update: 500,
+            epsilon: EpsilonSchedule::default(),
+            load: None,
+            save_dir: PathBuf::from("weights"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub network: Mlp,
+    pub steps: u32,
+    pub episodes: u32,
+}
+
+impl Checkpoint {
+    pub fn load(path: &PathBuf) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save(&self, path: &PathBuf) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)
+    }
+}
+
+fn legal_mask(view: &GameView) -> [bool; 4] {
+    let legal = view.actions.legal_directions(&view.position);
+    Direction::ALL.map(|direction| legal.contains(&direction))
+}
+
+/// Best q-value among the legal actions, with its index.
+fn best_legal(q_values: ArrayView1<f32>, legal: &[bool; 4]) -> Option<(usize, f32)> {
+    q_values
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| legal[*index])
+        .map(|(index, value)| (index, *value))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+}
+
+/// Deep Q-network over the observation planes of the layout.
+pub struct DqnController {
+    settings: DqnSettings,
+    online: Option<Mlp>,
+    target: Option<Mlp>,
+    replay: ReplayBuffer,
+    training: bool,
+    steps: u32,
+    since_target_update: u32,
+    episodes: u32,
+    last: Option<(Array1<f32>, usize)>,
+    rng: StreamRng,
+}
+
+impl DqnController {
+    pub fn new(settings: DqnSettings, rng: StreamRng) -> Self {
+        let checkpoint = settings
+            .load
+            .as_ref()
+            .and_then(|path| match Checkpoint::load(path) {
+                Ok(checkpoint) => Some(checkpoint),
+                Err(error) => {
+                    warn!("Could not load checkpoint from {:?}: {}", path, error);
+                    None
+                }
+            });
+        let (online, steps, episodes) = match checkpoint {
+            Some(checkpoint) => (
+                Some(checkpoint.network),
+                checkpoint.steps,
+                checkpoint.episodes,
+            ),
+            None => (None, 0, 0),
+        };
+        Self {
+            replay: ReplayBuffer::new(settings.replay_capacity),
+            training: settings.load.is_none(),
+            target: online.clone(),
+            online,
+            settings,
+            steps,
+            since_target_update: 0,
+            episodes,
+            last: None,
+            rng,
+        }
+    }
+
+    fn observe_state(&self, view: &GameView) -> Array1<f32> {
+        self.settings
+            .observation
+            .encode(view)
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    /// Builds the networks the first time the observation size is known, or
+    /// again if a loaded checkpoint was made for another layout size.
+    fn ensure_network(&mut self, inputs: usize) {
+        if self.online.as_ref().map(Mlp::inputs) == Some(inputs) {
+            return;
+        }
+        if self.online.is_some() {
+            warn!("Checkpoint does not match the layout, starting from a new network");
+        }
+        let mut sizes = vec![inputs];
+        sizes.extend(&self.settings.hidden);
+        sizes.push(Direction::ALL.len());
+        let network = Mlp::new(&sizes, &mut self.rng);
+        self.target = Some(network.clone());
+        self.online = Some(network);
+    }
+
+    fn train(&mut self) {
+        let (Some(online), Some(target)) = (self.online.as_mut(), self.target.as_ref()) else {
+            return;
+        };
+        let batch = self.replay.sample(self.settings.batch_size, &mut self.rng);
+        let inputs = online.inputs();
+        let states = Array2::from_shape_fn((batch.len(), inputs), |(row, column)| {
+            batch[row].state[column]
+        });
+        let next_states = Array2::from_shape_fn((batch.len(), inputs), |(row, column)| {
+            batch[row].next_state[column]
+        });
+
+        let next_values = target.forward(next_states.view());
+        let activations = online.activations(states.view());
+        let q_values = activations.last().unwrap();
+        let mut gradient = Array2::zeros(q_values.raw_dim());
+        for (row, transition) in batch.iter().enumerate() {
+            let bootstrap = match transition.done {
+                true => 0.0,
+                false => best_legal(next_values.row(row), &transition.next_legal)
+                    .map_or(0.0, |(_, value)| value),
+            };
+            let expected = transition.reward + self.settings.discount * bootstrap;
+            // Huber loss gradient.
+            let error = q_values[[row, transition.action]] - expected;
+            gradient[[row, transition.action]] = error.clamp(-1.0, 1.0) / batch.len() as f32;
+        }
+        online.backward(&activations, gradient, self.settings.learning_rate);
+    }
+
+    fn checkpoint_path(&self, view: &GameView) -> PathBuf {
+        self.settings
+            .save_dir
+            .join(format!("dqn_agent_{}.json", view.agent.id))
+    }
+}
+
+impl AgentController for DqnController {
+    fn next_direction(&mut self, view: &GameView) -> Option<Direction> {
+        let state = self.observe_state(view);
+        self.ensure_network(state.len());
+        let legal = legal_mask(view);
+
+        let explore =
+            self.training && self.rng.gen::<f32>() < self.settings.epsilon.value(self.steps);
+        let action = if explore {
+            (0..Direction::ALL.len())
+                .filter(|index| legal[*index])
+                .collect::<Vec<_>>()
+                .choose(&mut self.rng)
+                .copied()
+        } else {
+            let q_values = self.online.as_ref()?.predict(state.view());
+            best_legal(q_values.view(), &legal).map(|(index, _)| index)
+        }?;
+        self.last = Some((state, action));
+        Some(Direction::ALL[action])
+    }
+
+    fn observe(&mut self, view: &GameView, reward: f32, done: bool) {
+        let Some((state, action)) = self.last.take() else {
+            return;
+        };
+        if self.training {
+            self.replay.push(Transition {
+                state,
+                action,
+                reward,
+                next_state: self.observe_state(view),
+                next_legal: legal_mask(view),
+                done,
+            });
+            self.steps += 1;
+            if self.replay.len() >= self.settings.train_start.max(1) {
+                self.train();
+            }
+            self.since_target_update += 1;
+            if self.since_target_update >= self.settings.target_update {
+                self.since_target_update = 0;
+                self.target = self.online.clone();
+            }
+        }
+        if done {
+            self.episodes += 1;
+            info!(
+                "DQN agent {} episode {} after {} steps, epsilon {:.3}",
+                view.agent.id,
+                self.episodes,
+                self.steps,
+                self.settings.epsilon.value(self.steps)
+            );
+            if let (true, Some(network)) = (self.training, self.online.as_ref()) {
+                let checkpoint = Checkpoint {
+                    network: network.clone(),
+                    steps: self.steps,
+                    episodes: self.episodes,
+                };
+                let path = self.checkpoint_path(view);
+                if let Err(error) = checkpoint.save(&path) {
+                    warn!("Could not save checkpoint to {:?}: {}", path, error);
+                }
+            }
+        }
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn rng(&mut self) -> Option<&mut StreamRng> {
+        Some(&mut self.rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    /// `mlp` with one weight of a layer, or one bias past the weights, moved by `by`.
+    fn shifted(mlp: &Mlp, layer: usize, index: usize, by: f32) -> Mlp {
+        let mut shifted = mlp.clone();
+        let dense = &mut shifted.layers[layer];
+        let mut parameters = dense.weights.iter_mut().chain(dense.bias.iter_mut());
+        *parameters.nth(index).unwrap() += by;
+        shifted
+    }
+
+    #[test]
+    fn gradients_match_finite_differences_of_forward() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mlp = Mlp::new(&[5, 8, 6, 3], &mut rng);
+        let inputs = Array2::from_shape_fn((4, 5), |_| rng.gen_range(-1.0..1.0));
+        // Half the sum of the squared outputs, whose gradient is the outputs.
+        let loss = |mlp: &Mlp| mlp.forward(inputs.view()).mapv(|value| value * value).sum() / 2.0;
+
+        let activations = mlp.activations(inputs.view());
+        let outputs = activations.last().unwrap().clone();
+        let gradients = mlp.gradients(&activations, outputs);
+        assert_eq!(gradients.len(), mlp.layers.len());
+
+        let epsilon = 1e-2;
+        for (layer, (weights, bias)) in gradients.into_iter().enumerate() {
+            for (index, gradient) in weights.into_iter().chain(bias).enumerate() {
+                let up = loss(&shifted(&mlp, layer, index, epsilon));
+                let down = loss(&shifted(&mlp, layer, index, -epsilon));
+                let numeric = (up - down) / (2.0 * epsilon);
+                assert!(
+                    (numeric - gradient).abs() <= 1e-2 * numeric.abs().max(1.0),
+                    "parameter {} of layer {}: backward gives {}, finite differences {}",
+                    index,
+                    layer,
+                    gradient,
+                    numeric
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::{collections::HashMap, io, path::PathBuf};
+
+use bevy::{ecs::system::CommandQueue, prelude::*, time::TimePlugin};
+use ndarray::prelude::*;
+use rand::Rng;
+
+use crate::{
+    cell::CellPosition,
+    controller::{self, ControllerConfig, ControllerKind, GameView},
+    curriculum::{LayoutSampler, LayoutSampling},
+    fog::{self, FogSettings, Memory, SensorModel},
+    game::{self, AgentScore, AutoReset, Episode, Ghost, Rewards, Visited},
+    layout::Layout,
+    movement::{Actions, Direction, Movement, TransitionModel},
+    observation::ObservationConfig,
+    replay::Snapshot,
+    rng::GameSeed,
+    save::SavedGame,
+    simulation::{self, SimulationClock},
+    Agent, AppState,
+};
+
+/// Observation returned by [`Env`], see [`ObservationConfig`].
+pub type Observation = Array3<f32>;
+
+/// Shape and bounds of actions or observations, as in Gym.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Space {
+    /// Integers in `0..n`.
+    Discrete(usize),
+    /// Arrays of `shape` with values in `low..=high`.
+    Box {
+        shape: Vec<usize>,
+        low: f32,
+        high: f32,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct EnvConfig {
+    pub layout: PathBuf,
+    /// Layouts drawn on every reset from the generation stream of the
+    /// episode's seed, instead of always playing `layout`.
+    pub layouts: Option<LayoutSampling>,
+    /// Where the schedule of the sampled layouts is appended, one JSON line
+    /// per episode.
+    pub schedule_log: Option<PathBuf>,
+    pub ghosts: ControllerKind,
+    pub transition_model: TransitionModel,
+    /// The built-in rewards by default, [`Rewards::load_default`] reads those
+    /// the game plays with.
+    pub rewards: Rewards,
+    pub observation: ObservationConfig,
+    /// Agent `0` only observes what this sensor perceives and remembers, unseen
+    /// cells reading as empty, when it is set.
+    pub sensor: Option<SensorModel>,
+    /// Episodes are cut after this many steps, `None` lets them run until the end.
+    pub max_steps: Option<u64>,
+    /// Every episode starts where this game was saved instead of at the start
+    /// of the layout, which is then the saved one.
+    pub start: Option<SavedGame>,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        Self {
+            layout: PathBuf::from("./assets/layouts/smallClassic.json"),
+            layouts: None,
+            schedule_log: None,
+            ghosts: ControllerKind::Random,
+            transition_model: TransitionModel::default(),
+            rewards: Rewards::default(),
+            observation: ObservationConfig::default(),
+            sensor: None,
+            max_steps: None,
+            start: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StepInfo {
+    pub score: f32,
+    pub won: bool,
+    /// The episode was cut by `max_steps` rather than finished.
+    pub truncated: bool,
+    pub steps: u64,
+    /// Actions allowed from the new state, indexed like [`Direction::ALL`].
+    pub legal: [bool; 4],
+}
+
+/// Headless game driven one step at a time: agent `0` takes the actions
+/// given to [`Env::step`], every other agent keeps its controller.
+pub struct Env {
+    config: EnvConfig,
+    layout: Layout,
+    sampler: Option<LayoutSampler>,
+    /// The episode's result went to the sampler already.
+    recorded: bool,
+    app: App,
+    seed: u64,
+    steps: u64,
+}
+
+impl Env {
+    pub fn new(config: EnvConfig) -> io::Result<Self> {
+        let mut sampler = match (&config.layouts, &config.schedule_log) {
+            (Some(sampling), Some(log)) => {
+                Some(LayoutSampler::new(sampling.clone(), 0).with_log(log)?)
+            }
+            (Some(sampling), None) => Some(LayoutSampler::new(sampling.clone(), 0)),
+            (None, _) => None,
+        };
+        let layout = match (&config.start, &mut sampler) {
+            (Some(saved), _) => saved.layout.clone(),
+            // Sampled once here so that layouts that cannot be read fail early.
+            (None, Some(sampler)) => sampler.sample()?,
+            (None, None) => Layout::load(&config.layout)?,
+        };
+        let mut env = Self {
+            app: App::new(),
+            config,
+            layout,
+            sampler,
+            recorded: false,
+            seed: 0,
+            steps: 0,
+        };
+        env.reset(None);
+        Ok(env)
+    }
+
+    /// Plays another layout from the next reset on, unless layouts are sampled.
+    pub fn set_layout(&mut self, path: impl Into<PathBuf>) -> io::Result<()> {
+        let path = path.into();
+        self.layout = Layout::load(&path)?;
+        self.config.layout = path;
+        Ok(())
+    }
+
+    pub fn config(&self) -> &EnvConfig {
+        &self.config
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Sampler of the layouts, to follow the curriculum.
+    pub fn sampler(&self) -> Option<&LayoutSampler> {
+        self.sampler.as_ref()
+    }
+
+    /// Seed of the current episode.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn world(&self) -> &World {
+        &self.app.world
+    }
+
+    pub fn action_space(&self) -> Space {
+        Space::Discrete(Direction::ALL.len())
+    }
+
+    pub fn observation_space(&self) -> Space {
+        let (width, height) = self.layout.grid.dim();
+        let (planes, width, height) = self.config.observation.shape(width, height);
+        Space::Box {
+            shape: vec![planes, width, height],
+            low: 0.0,
+            high: 1.0,
+        }
+    }
+
+    /// Starts a new episode; the same seed always gives the same episode.
+    pub fn reset(&mut self, seed: Option<u64>) -> Observation {
+        let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+        if let (Some(sampler), None) = (&mut self.sampler, &self.config.start) {
+            // Draws from the same generation stream as the game's `GameRng`.
+            sampler.reseed(seed);
+            match sampler.sample() {
+                Ok(layout) => self.layout = layout,
+                Err(error) => warn!("Keeping the last layout, none could be sampled: {}", error),
+            }
+        }
+        self.app = self.build_app(seed);
+        self.seed = seed;
+        self.steps = 0;
+        self.recorded = false;
+        // The first update spawns the agents without ticking the clock.
+        self.app.update();
+        if let Some(saved) = &self.config.start {
+            // The saved game keeps the randomness of this episode's seed.
+            let fresh = Snapshot::capture(&mut self.app.world);
+            saved.restore(&mut self.app.world);
+            fresh.restore_rngs(&mut self.app.world);
+        }
+        if self.config.sensor.is_some() {
+            // And the second gives them the memory of what they see.
+            self.app.update();
+        }
+        self.observe()
+    }
+
+    /// Moves agent `0` in `Direction::ALL[action]` and plays one tick.
+    ///
+    /// # Panics
+    ///
+    /// When `action` is outside of the [`Env::action_space`].
+    pub fn step(&mut self, action: usize) -> (Observation, f32, bool, StepInfo) {
+        assert!(
+            action < Direction::ALL.len(),
+            "action {} is outside of the action space Discrete({})",
+            action,
+            Direction::ALL.len()
+        );
+        let direction = Direction::ALL[action];
+        self.app
+            .world
+            .resource_mut::<Events<Movement>>()
+            .send(Movement::new(0, direction));
+        self.app.world.resource_mut::<SimulationClock>().step();
+        self.app.update();
+        self.steps += 1;
+
+        let episode = *self.app.world.resource::<Episode>();
+        let score = self.learner_score();
+        let truncated = !episode.done && self.config.max_steps.is_some_and(|max| self.steps >= max);
+        if (episode.done || truncated) && !self.recorded {
+            self.recorded = true;
+            if let Some(sampler) = &mut self.sampler {
+                if let Err(error) = sampler.record(episode.won) {
+                    warn!("Could not record the episode: {}", error);
+                }
+            }
+        }
+        let info = StepInfo {
+            score: score.score,
+            won: episode.won,
+            truncated,
+            steps: self.steps,
+            legal: self.legal_actions(),
+        };
+        (
+            self.observe(),
+            score.last_reward,
+            episode.done || truncated,
+            info,
+        )
+    }
+
+    /// Actions allowed to agent `0`, indexed like [`Direction::ALL`].
+    pub fn legal_actions(&mut self) -> [bool; 4] {
+        let position = self.learner_position();
+        let legal = self
+            .app
+            .world
+            .resource::<Actions>()
+            .legal_directions(&position);
+        Direction::ALL.map(|direction| legal.contains(&direction))
+    }
+
+    pub fn observe(&mut self) -> Observation {
+        let observation = self.config.observation;
+        self.view(|view| observation.encode(view))
+    }
+
+    /// Calls `f` with the game as agent `0` sees it, the way controllers do.
+    pub fn view<R>(&mut self, f: impl FnOnce(&GameView) -> R) -> R {
+        let world = &mut self.app.world;
+        let mut query = world.query::<(&Agent, &CellPosition, Option<&Ghost>)>();
+        let agents = query
+            .iter(world)
+            .map(|(agent, position, _)| (agent.id, *position))
+            .collect::<Vec<_>>();
+        let ghosts = controller::ghost_views(query.iter(world));
+        let (agent, position) = query
+            .iter(world)
+            .find(|(agent, _, _)| agent.id == 0)
+            .map(|(agent, position, _)| (agent.clone(), *position))
+            .expect("the layout has no pacman");
+        let mut visited_query = world.query::<(&Agent, &Visited, Option<&Memory>)>();
+        let (visited, memory) = visited_query
+            .iter(world)
+            .find(|(agent, _, _)| agent.id == 0)
+            .map(|(_, visited, memory)| (visited.0.clone(), memory))
+            .unwrap_or_default();
+        let seen = memory.map(|memory| {
+            (
+                memory.visible_agents(&agents),
+                memory.visible_ghosts(&ghosts),
+            )
+        });
+
+        let view = GameView {
+            agent: &agent,
+            position,
+            actions: memory.map_or(world.resource::<Actions>(), |memory| &memory.actions),
+            agents: seen.as_ref().map_or(&agents, |(agents, _)| agents),
+            ghosts: seen.as_ref().map_or(&ghosts, |(_, ghosts)| ghosts),
+            readings: &[],
+            beliefs: &[],
+            visited: &visited,
+            keyboard: None,
+            selected: false,
+        };
+        f(&view)
+    }
+
+    fn learner_position(&mut self) -> CellPosition {
+        let world = &mut self.app.world;
+        let mut query = world.query::<(&Agent, &CellPosition)>();
+        query
+            .iter(world)
+            .find(|(agent, _)| agent.id == 0)
+            .map(|(_, position)| *position)
+            .expect("the layout has no pacman")
+    }
+
+    fn learner_score(&mut self) -> AgentScore {
+        let world = &mut self.app.world;
+        let mut query = world.query::<(&Agent, &AgentScore)>();
+        query
+            .iter(world)
+            .find(|(agent, _)| agent.id == 0)
+            .map(|(_, score)| *score)
+            .unwrap_or_default()
+    }
+
+    fn build_app(&self, seed: u64) -> App {
+        let mut app = App::new();
+        app.add_plugin(CorePlugin::default())
+            .add_plugin(TimePlugin)
+            .init_resource::<Input<KeyCode>>()
+            .add_state(AppState::InGame)
+            .add_plugin(simulation::SimulationPlugin)
+            .add_plugin(game::GamePlugin)
+            .add_plugin(controller::ControllerPlugin)
+            .add_plugin(fog::FogPlugin)
+            .insert_resource(AutoReset(false))
+            .insert_resource(FogSettings(self.config.sensor))
+            .insert_resource(self.config.rewards)
+            .insert_resource(self.config.transition_model)
+            .insert_resource(GameSeed(Some(seed)))
+            .insert_resource(ControllerConfig {
+                default: ControllerKind::External,
+                ghosts: self.config.ghosts.clone(),
+                agents: HashMap::new(),
+            });
+        let mut clock = SimulationClock::default();
+        clock.paused = true;
+        app.insert_resource(clock);
+
+        let mut queue = CommandQueue::default();
+        let mut commands = Commands::new(&mut queue, &app.world);
+        self.layout.insert_resources(&mut commands);
+        queue.apply(&mut app.world);
+        app
+    }
+}
@@ -0,0 +1,423 @@
+use std::{fs, io, path::PathBuf, thread};
+
+use bevy::prelude::info;
+use ndarray::prelude::*;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    controller::{ControllerKind, GameView},
+    dqn::{Checkpoint, Mlp},
+    env::{Env, EnvConfig},
+    features::{ExtractorKind, FeatureExtractor},
+    layout::Layout,
+    learning::Weights,
+    movement::Direction,
+    observation::ObservationConfig,
+    vec_env::instance_seed,
+};
+
+/// What the genes of an individual stand for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GenomeKind {
+    /// One weight per feature of the extractor, scored like an approximate q-agent.
+    Features(ExtractorKind),
+    /// Every parameter of a small network over the observation planes.
+    Network {
+        observation: ObservationConfig,
+        hidden: Vec<usize>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvolutionSettings {
+    pub genome: GenomeKind,
+    /// Fitness is the average score over all of these layouts.
+    pub layouts: Vec<PathBuf>,
+    pub episodes_per_layout: usize,
+    pub max_steps: u64,
+    pub ghosts: ControllerKind,
+    pub population: usize,
+    pub tournament_size: usize,
+    pub crossover_rate: f32,
+    /// Chance of every gene to be mutated.
+    pub mutation_rate: f32,
+    /// Standard deviation of the gaussian noise added by a mutation.
+    pub mutation_scale: f32,
+    /// Best individuals copied unchanged into the next generation.
+    pub elites: usize,
+    pub hall_of_fame: usize,
+    pub threads: usize,
+    pub seed: u64,
+    pub save_dir: PathBuf,
+}
+
+impl Default for EvolutionSettings {
+    fn default() -> Self {
+        Self {
+            genome: GenomeKind::Features(ExtractorKind::Simple),
+            layouts: vec![PathBuf::from("./assets/layouts/smallClassic.json")],
+            episodes_per_layout: 2,
+            max_steps: 300,
+            ghosts: ControllerKind::Random,
+            population: 32,
+            tournament_size: 3,
+            crossover_rate: 0.7,
+            mutation_rate: 0.1,
+            mutation_scale: 0.5,
+            elites: 2,
+            hall_of_fame: 10,
+            threads: thread::available_parallelism().map_or(1, |threads| threads.get()),
+            seed: 0,
+            save_dir: PathBuf::from("evolution"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Individual {
+    pub genes: Vec<f32>,
+    pub fitness: f32,
+    pub generation: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GenerationStats {
+    pub generation: u32,
+    pub best: f32,
+    pub mean: f32,
+}
+
+/// Genes turned into something that plays.
+enum Policy {
+    Features {
+        extractor: Box<dyn FeatureExtractor>,
+        weights: Weights,
+    },
+    Network {
+        observation: ObservationConfig,
+        network: Mlp,
+    },
+}
+
+impl Policy {
+    fn act(&self, view: &GameView) -> usize {
+        let legal = view.actions.legal_directions(&view.position);
+        let values = match self {
+            Policy::Features { extractor, weights } => Direction::ALL
+                .iter()
+                .map(|direction| weights.q_value(&extractor.features(view, *direction)))
+                .collect::<Array1<f32>>(),
+            Policy::Network {
+                observation,
+                network,
+            } => {
+                let input = observation
+                    .encode(view)
+                    .iter()
+                    .copied()
+                    .collect::<Array1<f32>>();
+                network.predict(input.view())
+            }
+        };
+        values
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| legal.contains(&Direction::ALL[*index]))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(index, _)| index)
+    }
+}
+
+/// Genetic algorithm over policies, evaluated in headless games on several threads.
+pub struct Evolution {
+    settings: EvolutionSettings,
+    /// Network the genes are written into, for network genomes.
+    template: Option<Mlp>,
+    population: Vec<Vec<f32>>,
+    hall_of_fame: Vec<Individual>,
+    generation: u32,
+    rng: StdRng,
+}
+
+impl Evolution {
+    pub fn new(settings: EvolutionSettings) -> io::Result<Self> {
+        if settings.layouts.is_empty() || settings.population == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "evolution needs at least one layout and one individual",
+            ));
+        }
+        let mut rng = StdRng::seed_from_u64(settings.seed);
+        let (template, population) = match &settings.genome {
+            GenomeKind::Features(extractor) => {
+                let genes = extractor.feature_names().len();
+                let population = (0..settings.population)
+                    .map(|_| (0..genes).map(|_| rng.gen_range(-1.0..1.0)).collect())
+                    .collect();
+                (None, population)
+            }
+            GenomeKind::Network {
+                observation,
+                hidden,
+            } => {
+                let mut inputs = None;
+                for path in settings.layouts.iter() {
+                    let (width, height) = Layout::load(path)?.grid.dim();
+                    let (planes, width, height) = observation.shape(width, height);
+                    if *inputs.get_or_insert(planes * width * height) != planes * width * height {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "network genomes need layouts of the same size or a window",
+                        ));
+                    }
+                }
+                let mut sizes = vec![inputs.unwrap()];
+                sizes.extend(hidden);
+                sizes.push(Direction::ALL.len());
+                let population = (0..settings.population)
+                    .map(|_| Mlp::new(&sizes, &mut rng).parameters())
+                    .collect();
+                (Some(Mlp::new(&sizes, &mut rng)), population)
+            }
+        };
+
+        Ok(Self {
+            settings,
+            template,
+            population,
+            hall_of_fame: Vec::new(),
+            generation: 0,
+            rng,
+        })
+    }
+
+    pub fn settings(&self) -> &EvolutionSettings {
+        &self.settings
+    }
+
+    /// Best individuals seen so far, best first.
+    pub fn hall_of_fame(&self) -> &[Individual] {
+        &self.hall_of_fame
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Evaluates the current population and breeds the next one.
+    pub fn step(&mut self) -> io::Result<GenerationStats> {
+        let fitness = self.evaluate(&self.population)?;
+        let mut ranked = (0..self.population.len()).collect::<Vec<_>>();
+        ranked.sort_unstable_by(|a, b| fitness[*b].total_cmp(&fitness[*a]));
+
+        for index in ranked.iter().take(self.settings.hall_of_fame) {
+            // Elites come back every generation, only their best score is kept.
+            let genes = &self.population[*index];
+            let fitness = fitness[*index];
+            match self
+                .hall_of_fame
+                .iter_mut()
+                .find(|individual| individual.genes == *genes)
+            {
+                Some(individual) if individual.fitness >= fitness => {}
+                Some(individual) => {
+                    individual.fitness = fitness;
+                    individual.generation = self.generation;
+                }
+                None => self.hall_of_fame.push(Individual {
+                    genes: genes.clone(),
+                    fitness,
+                    generation: self.generation,
+                }),
+            }
+        }
+        self.hall_of_fame
+            .sort_by(|a, b| b.fitness.total_cmp(&a.fitness));
+        self.hall_of_fame.truncate(self.settings.hall_of_fame);
+
+        let stats = GenerationStats {
+            generation: self.generation,
+            best: fitness[ranked[0]],
+            mean: fitness.iter().sum::<f32>() / fitness.len() as f32,
+        };
+        info!(
+            "Generation {}: best {:.1}, mean {:.1}",
+            stats.generation, stats.best, stats.mean
+        );
+
+        let mut next = ranked
+            .iter()
+            .take(self.settings.elites)
+            .map(|index| self.population[*index].clone())
+            .collect::<Vec<_>>();
+        while next.len() < self.population.len() {
+            let first = self.tournament(&fitness);
+            let second = self.tournament(&fitness);
+            let mut child = if self.rng.gen::<f32>() < self.settings.crossover_rate {
+                crossover(
+                    &mut self.rng,
+                    &self.population[first],
+                    &self.population[second],
+                )
+            } else {
+                self.population[first].clone()
+            };
+            self.mutate(&mut child);
+            next.push(child);
+        }
+        self.population = next;
+        self.generation += 1;
+        Ok(stats)
+    }
+
+    /// Runs `generations` generations, saving the hall of fame after each one.
+    pub fn run(&mut self, generations: u32) -> io::Result<Vec<GenerationStats>> {
+        let mut stats = Vec::new();
+        for _ in 0..generations {
+            stats.push(self.step()?);
+            self.save()?;
+        }
+        Ok(stats)
+    }
+
+    /// Writes the hall of fame and the best individual in the format its
+    /// agent loads: approximate q-learning weights or a DQN checkpoint.
+    pub fn save(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.settings.save_dir)?;
+        fs::write(
+            self.settings.save_dir.join("hall_of_fame.json"),
+            serde_json::to_string_pretty(&self.hall_of_fame)?,
+        )?;
+        let Some(best) = self.hall_of_fame.first() else {
+            return Ok(());
+        };
+        match self.policy(&best.genes) {
+            Policy::Features { weights, .. } => {
+                weights.save(&self.settings.save_dir.join("best_weights.json"))
+            }
+            Policy::Network { network, .. } => Checkpoint {
+                network,
+                steps: 0,
+                episodes: 0,
+            }
+            .save(&self.settings.save_dir.join("best_network.json")),
+        }
+    }
+
+    /// Average score of every genome, spread over the evaluation threads.
+    fn evaluate(&self, population: &[Vec<f32>]) -> io::Result<Vec<f32>> {
+        let threads = self.settings.threads.clamp(1, population.len());
+        let chunk = population.len().div_ceil(threads);
+        thread::scope(|scope| {
+            let handles = population
+                .chunks(chunk)
+                .map(|genomes| scope.spawn(move || self.evaluate_chunk(genomes)))
+                .collect::<Vec<_>>();
+            let mut fitness = Vec::new();
+            for handle in handles {
+                fitness.extend(handle.join().unwrap()?);
+            }
+            Ok(fitness)
+        })
+    }
+
+    fn evaluate_chunk(&self, genomes: &[Vec<f32>]) -> io::Result<Vec<f32>> {
+        let mut envs = self
+            .settings
+            .layouts
+            .iter()
+            .map(|layout| {
+                Env::new(EnvConfig {
+                    layout: layout.clone(),
+                    ghosts: self.settings.ghosts.clone(),
+                    max_steps: Some(self.settings.max_steps),
+                    ..Default::default()
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let episodes = self.settings.episodes_per_layout.max(1);
+
+        Ok(genomes
+            .iter()
+            .map(|genes| {
+                let policy = self.policy(genes);
+                let mut total = 0.0;
+                for (index, env) in envs.iter_mut().enumerate() {
+                    for episode in 0..episodes {
+                        // Every individual of a generation plays the same episodes.
+                        let episode = self.generation as u64 * episodes as u64 + episode as u64;
+                        env.reset(Some(instance_seed(self.settings.seed, index, episode)));
+                        loop {
+                            let action = env.view(|view| policy.act(view));
+                            let (_, _, done, info) = env.step(action);
+                            if done {
+                                total += info.score;
+                                break;
+                            }
+                        }
+                    }
+                }
+                total / (envs.len() * episodes) as f32
+            })
+            .collect())
+    }
+
+    fn policy(&self, genes: &[f32]) -> Policy {
+        match (&self.settings.genome, &self.template) {
+            (GenomeKind::Features(extractor), _) => Policy::Features {
+                extractor: extractor.build(),
+                weights: Weights(
+                    extractor
+                        .feature_names()
+                        .into_iter()
+                        .map(String::from)
+                        .zip(genes.iter().copied())
+                        .collect(),
+                ),
+            },
+            (GenomeKind::Network { observation, .. }, Some(template)) => {
+                let mut network = template.clone();
+                network.set_parameters(genes);
+                Policy::Network {
+                    observation: *observation,
+                    network,
+                }
+            }
+            (GenomeKind::Network { .. }, None) => unreachable!(),
+        }
+    }
+
+    /// Index of the fittest of a few individuals drawn at random.
+    fn tournament(&mut self, fitness: &[f32]) -> usize {
+        (0..self.settings.tournament_size.max(1))
+            .map(|_| self.rng.gen_range(0..fitness.len()))
+            .max_by(|a, b| fitness[*a].total_cmp(&fitness[*b]))
+            .unwrap()
+    }
+
+    fn mutate(&mut self, genes: &mut [f32]) {
+        for gene in genes.iter_mut() {
+            if self.rng.gen::<f32>() < self.settings.mutation_rate {
+                *gene += self.settings.mutation_scale * gaussian(&mut self.rng);
+            }
+        }
+    }
+}
+
+/// Uniform crossover, every gene comes from either parent.
+fn crossover(rng: &mut impl Rng, first: &[f32], second: &[f32]) -> Vec<f32> {
+    first
+        .iter()
+        .zip(second)
+        .map(|(a, b)| *[a, b].choose(rng).unwrap())
+        .copied()
+        .collect()
+}
+
+/// Standard normal sample, with the Box-Muller transform.
+fn gaussian(rng: &mut impl Rng) -> f32 {
+    let u = 1.0 - rng.gen::<f32>();
+    let v = rng.gen::<f32>();
+    (-2.0 * u.ln()).sqrt() * (2.0 * std::f32::consts::PI * v).cos()
+}
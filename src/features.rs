@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use crate::{
+    cell::CellPosition,
+    controller::{shortest_path, GameView},
+    movement::Direction,
+};
+
+/// Named feature values of a (state, action) pair.
+pub type Features = HashMap<String, f32>;
+
+pub trait FeatureExtractor: Send + Sync {
+    fn features(&self, view: &GameView, direction: Direction) -> Features;
+}
+
+/// Always `1.0`, lets the linear model learn a constant offset.
+pub struct BiasExtractor;
+
+impl FeatureExtractor for BiasExtractor {
+    fn features(&self, _view: &GameView, _direction: Direction) -> Features {
+        Features::from([("bias".to_string(), 1.0)])
+    }
+}
+
+/// Maze distance from the next cell to the closest food, scaled by the layout size.
+pub struct ClosestFoodExtractor;
+
+impl FeatureExtractor for ClosestFoodExtractor {
+    fn features(&self, view: &GameView, direction: Direction) -> Features {
+        let next = view.actions.next_position(&view.position, direction);
+        closest_food(view, &next)
+            .map(|distance| Features::from([("closest-food".to_string(), distance)]))
+            .unwrap_or_default()
+    }
+}
+
+/// Number of ghosts that are not scared and can reach the next cell in one step.
+pub struct GhostsOneStepAwayExtractor;
+
+impl FeatureExtractor for GhostsOneStepAwayExtractor {
+    fn features(&self, view: &GameView, direction: Direction) -> Features {
+        let next = view.actions.next_position(&view.position, direction);
+        Features::from([(
+            "#-of-ghosts-1-step-away".to_string(),
+            ghosts_one_step_away(view, &next) as f32,
+        )])
+    }
+}
+
+/// `1.0` when the next cell holds food.
+pub struct EatsFoodExtractor;
+
+impl FeatureExtractor for EatsFoodExtractor {
+    fn features(&self, view: &GameView, direction: Direction) -> Features {
+        let next = view.actions.next_position(&view.position, direction);
+        Features::from([("eats-food".to_string(), eats_food(view, &next))])
+    }
+}
+
+/// The four built-in features together. Food is only considered worth eating
+/// when no ghost is about to catch the agent, and every value is divided by
+/// ten to keep the weights from diverging.
+pub struct SimpleExtractor;
+
+impl FeatureExtractor for SimpleExtractor {
+    fn features(&self, view: &GameView, direction: Direction) -> Features {
+        let next = view.actions.next_position(&view.position, direction);
+        let ghosts = ghosts_one_step_away(view, &next);
+
+        let mut features = Features::from([
+            ("bias".to_string(), 1.0),
+            ("#-of-ghosts-1-step-away".to_string(), ghosts as f32),
+        ]);
+        if ghosts == 0 {
+            features.insert("eats-food".to_string(), eats_food(view, &next));
+        }
+        if let Some(distance) = closest_food(view, &next) {
+            features.insert("closest-food".to_string(), distance);
+        }
+        for value in features.values_mut() {
+            *value /= 10.0;
+        }
+        features
+    }
+}
+
+/// Which extractor an approximate agent is built with.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExtractorKind {
+    Bias,
+    ClosestFood,
+    GhostsOneStepAway,
+    EatsFood,
+    #[default]
+    Simple,
+}
+
+impl ExtractorKind {
+    pub fn build(&self) -> Box<dyn FeatureExtractor> {
+        match self {
+            ExtractorKind::Bias => Box::new(BiasExtractor),
+            ExtractorKind::ClosestFood => Box::new(ClosestFoodExtractor),
+            ExtractorKind::GhostsOneStepAway => Box::new(GhostsOneStepAwayExtractor),
+            ExtractorKind::EatsFood => Box::new(EatsFoodExtractor),
+            ExtractorKind::Simple => Box::new(SimpleExtractor),
+        }
+    }
+
+    /// Every feature the extractor can produce.
+    pub fn feature_names(&self) -> Vec<&'static str> {
+        match self {
+            ExtractorKind::Bias => vec!["bias"],
+            ExtractorKind::ClosestFood => vec!["closest-food"],
+            ExtractorKind::GhostsOneStepAway => vec!["#-of-ghosts-1-step-away"],
+            ExtractorKind::EatsFood => vec!["eats-food"],
+            ExtractorKind::Simple => vec![
+                "bias",
+                "#-of-ghosts-1-step-away",
+                "eats-food",
+                "closest-food",
+            ],
+        }
+    }
+}
+
+fn closest_food(view: &GameView, start: &CellPosition) -> Option<f32> {
+    let (width, height) = view.actions.grid.dim();
+    shortest_path(view.actions, start, |position| {
+        view.actions.grid[[position.x as usize, position.y as usize]] == 1
+    })
+    .map(|path| path.len() as f32 / (width * height) as f32)
+}
+
+fn ghosts_one_step_away(view: &GameView, position: &CellPosition) -> usize {
+    view.ghosts
+        .iter()
+        .filter(|ghost| ghost.id != view.agent.id && ghost.scared_timer == 0)
+        .filter(|ghost| {
+            ghost.position == *position
+                || view
+                    .actions
+                    .legal_directions(&ghost.position)
+                    .into_iter()
+                    .any(|direction| {
+                        view.actions.next_position(&ghost.position, direction) == *position
+                    })
+        })
+        .count()
+}
+
+fn eats_food(view: &GameView, position: &CellPosition) -> f32 {
+    if view.actions.grid[[position.x as usize, position.y as usize]] == 1 {
+        1.0
+    } else {
+        0.0
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::HashSet;
+
+use bevy::{prelude::*, sprite::MaterialMesh2dBundle};
+use ndarray::prelude::*;
+use rand::Rng;
+
+use crate::{
+    cell::{self, CellPosition},
+    controller::{self, GhostView, SelectedAgent},
+    game::{self, Episode, Ghost},
+    grid::{self, GridConfig},
+    movement::Actions,
+    overlay::{self, darken, CellLayers},
+    rng::{GameRng, Stream},
+    simulation::SimulationClock,
+    Agent, AppState, UpdateCell, HEIGHT, WIDTH,
+};
+
+/// Value of the cells an agent has never seen in its [`Memory`].
+pub const UNKNOWN: i8 = -2;
+
+/// What a pacman can perceive from its cell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorModel {
+    /// Cells further than this many cells are not seen, `None` sees the whole layout.
+    pub radius: Option<u32>,
+    /// Walls hide what is behind them.
+    pub line_of_sight: bool,
+    /// Chance of misreading food on a seen cell and of missing a seen agent.
+    pub noise: f32,
+}
+
+impl SensorModel {
+    pub const RADIUS: Self = Self {
+        radius: Some(3),
+        line_of_sight: false,
+        noise: 0.0,
+    };
+    pub const LINE_OF_SIGHT: Self = Self {
+        radius: Some(6),
+        line_of_sight: true,
+        noise: 0.0,
+    };
+    pub const NOISY: Self = Self {
+        radius: Some(6),
+        line_of_sight: true,
+        noise: 0.1,
+    };
+
+    /// Cells seen from `from`.
+    pub fn visible(&self, grid: &Array2<i8>, from: CellPosition) -> Array2<bool> {
+        let (width, height) = grid.dim();
+        Array2::from_shape_fn((width, height), |(x, y)| {
+            let dx = x as i64 - from.x as i64;
+            let dy = y as i64 - from.y as i64;
+            let in_range = self
+                .radius
+                .is_none_or(|radius| dx * dx + dy * dy <= (radius * radius) as i64);
+            in_range && (!self.line_of_sight || clear_line(grid, from, (x, y)))
+        })
+    }
+}
+
+/// Whether no wall stands strictly between the two cells, along a Bresenham line.
+fn clear_line(grid: &Array2<i8>, from: CellPosition, to: (usize, usize)) -> bool {
+    let (mut x, mut y) = (from.x as i64, from.y as i64);
+    let (x1, y1) = (to.0 as i64, to.1 as i64);
+    let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+    let (sx, sy) = ((x1 - x).signum(), (y1 - y).signum());
+    let mut error = dx + dy;
+    loop {
+        if (x, y) == (x1, y1) {
+            return true;
+        }
+        if (x, y) != (from.x as i64, from.y as i64) && grid[[x as usize, y as usize]] == 0 {
+            return false;
+        }
+        let double = 2 * error;
+        if double >= dy {
+            error += dy;
+            x += sx;
+        }
+        if double <= dx {
+            error += dx;
+            y += sy;
+        }
+    }
+}
+
+/// Sensor every pacman plays with, `None` lets them see everything.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq)]
+pub struct FogSettings(pub Option<SensorModel>);
+
+/// What a pacman knows of the layout under fog of war. Controllers of an
+/// agent with a memory are given this map instead of the real one.
+#[derive(Component, Debug, Clone)]
+pub struct Memory {
+    /// Last reading of every cell, [`UNKNOWN`] for the ones never seen.
+    pub grid: Array2<i8>,
+    /// Cells seen on the last reading.
+    pub visible: Array2<bool>,
+    /// Agents seen on the last reading.
+    pub agents: HashSet<u32>,
+    /// The remembered layout, unknown cells taken as empty.
+    pub actions: Actions,
+    episode: u32,
+}
+
+impl Memory {
+    fn new(actions: &Actions, episode: u32) -> Self {
+        let grid = Array2::from_elem(actions.grid.raw_dim(), UNKNOWN);
+        Self {
+            visible: Array2::from_elem(grid.raw_dim(), false),
+            actions: Actions::new(grid.mapv(belief)),
+            grid,
+            agents: HashSet::new(),
+            episode,
+        }
+    }
+
+    /// Reads the cells and agents the sensor sees from `position`.
+    fn sense(
+        &mut self,
+        sensor: &SensorModel,
+        actions: &Actions,
+        position: CellPosition,
+        agents: &[(u32, CellPosition)],
+        rng: &mut impl Rng,
+    ) {
+        self.visible = sensor.visible(&actions.grid, position);
+        for ((x, y), seen) in self.visible.indexed_iter() {
+            if !seen {
+                continue;
+            }
+            let value = actions.grid[[x, y]];
+            self.grid[[x, y]] = match value {
+                1 if rng.gen::<f32>() < sensor.noise => 2,
+                2 if rng.gen::<f32>() < sensor.noise => 1,
+                _ => value,
+            };
+        }
+        self.agents = agents
+            .iter()
+            .filter(|(_, other)| self.visible[[other.x as usize, other.y as usize]])
+            .filter(|(_, other)| *other == position || rng.gen::<f32>() >= sensor.noise)
+            .map(|(id, _)| *id)
+            .collect();
+        self.actions = Actions::new(self.grid.mapv(belief));
+    }
+
+    /// The agents among `agents` that were seen.
+    pub fn visible_agents(&self, agents: &[(u32, CellPosition)]) -> Vec<(u32, CellPosition)> {
+        agents
+            .iter()
+            .filter(|(id, _)| self.agents.contains(id))
+            .copied()
+            .collect()
+    }
+
+    /// The ghosts among `ghosts` that were seen.
+    pub fn visible_ghosts(&self, ghosts: &[GhostView]) -> Vec<GhostView> {
+        ghosts
+            .iter()
+            .filter(|ghost| self.agents.contains(&ghost.id))
+            .copied()
+            .collect()
+    }
+}
+
+fn belief(value: i8) -> i8 {
+    match value {
+        UNKNOWN => 2,
+        value => value,
+    }
+}
+
+/// Keeps a memory on every pacman while a sensor is set. It works without a
+/// window, [`FogDisplayPlugin`] draws it.
+pub struct FogPlugin;
+
+impl Plugin for FogPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<FogSettings>().add_system_set(
+            SystemSet::on_update(AppState::InGame)
+                .with_system(attach_memory.before(controller::drive_agents))
+                .with_system(
+                    sense
+                        .after(game::track_visited)
+                        .before(controller::observe_rewards),
+                ),
+        );
+    }
+}
+
+/// Gives pacmen a memory of what they see from their start, or takes it
+/// away when the sensor is turned off.
+#[allow(clippy::type_complexity)]
+fn attach_memory(
+    mut commands: Commands,
+    fog: Res<FogSettings>,
+    actions: Res<Actions>,
+    episode: Res<Episode>,
+    mut rng: ResMut<GameRng>,
+    agent_query: Query<(&Agent, &CellPosition)>,
+    pacman_query: Query<
+        (Entity, &Agent, &CellPosition, Option<&Memory>),
+        (With<Agent>, Without<Ghost>),
+    >,
+) {
+    let Some(sensor) = fog.0 else {
+        for (entity, _, _, memory) in pacman_query.iter() {
+            if memory.is_some() {
+                commands.entity(entity).remove::<Memory>();
+            }
+        }
+        return;
+    };
+    let agents = agent_query
+        .iter()
+        .map(|(agent, position)| (agent.id, *position))
+        .collect::<Vec<_>>();
+    for (entity, _agent, position, memory) in pacman_query.iter() {
+        if memory.is_some() && !fog.is_changed() {
+            continue;
+        }
+        let mut memory = Memory::new(&actions, episode.number);
+        memory.sense(
+            &sensor,
+            &actions,
+            *position,
+            &agents,
+            rng.stream(Stream::Sensor),
+        );
+        commands.entity(entity).insert(memory);
+    }
+}
+
+/// Updates every memory after the rules ran, forgetting the previous episode.
+fn sense(
+    clock: Res<SimulationClock>,
+    fog: Res<FogSettings>,
+    actions: Res<Actions>,
+    episode: Res<Episode>,
+    mut rng: ResMut<GameRng>,
+    agent_query: Query<(&Agent, &CellPosition)>,
+    mut memory_query: Query<(&CellPosition, &mut Memory)>,
+) {
+    let Some(sensor) = fog.0 else {
+        return;
+    };
+    if !clock.just_ticked() && !episode.is_changed() {
+        return;
+    }
+    let agents = agent_query
+        .iter()
+        .map(|(agent, position)| (agent.id, *position))
+        .collect::<Vec<_>>();
+    for (position, mut memory) in memory_query.iter_mut() {
+        if memory.episode != episode.number {
+            *memory = Memory::new(&actions, episode.number);
+        }
+        memory.sense(
+            &sensor,
+            &actions,
+            *position,
+            &agents,
+            rng.stream(Stream::Sensor),
+        );
+    }
+}
+
+/// Darkens what the selected pacman does not see and draws its memory next
+/// to the layout. `F` goes through the sensors.
+pub struct FogDisplayPlugin;
+
+impl Plugin for FogDisplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_update(AppState::InGame)
+                .with_system(cycle_sensor)
+                .with_system(render_fog.before(overlay::render_values))
+                .with_system(spawn_memory_map)
+                .with_system(render_memory_map.after(spawn_memory_map)),
+        )
+        .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(despawn_memory_map));
+    }
+}
+
+fn cycle_sensor(keyboard_input: Res<Input<KeyCode>>, mut fog: ResMut<FogSettings>) {
+    if !keyboard_input.just_pressed(KeyCode::F) {
+        return;
+    }
+    fog.0 = match fog.0 {
+        None => Some(SensorModel::RADIUS),
+        Some(SensorModel::RADIUS) => Some(SensorModel::LINE_OF_SIGHT),
+        Some(SensorModel::LINE_OF_SIGHT) => Some(SensorModel::NOISY),
+        Some(_) => None,
+    };
+    info!("Sensor {:?}", fog.0);
+}
+
+/// Cells and ghosts hidden from the selected pacman are drawn in the dark.
+fn render_fog(
+    fog: Res<FogSettings>,
+    selected_agent: Res<SelectedAgent>,
+    actions: Res<Actions>,
+    memory_query: Query<(&Agent, &Memory, ChangeTrackers<Memory>)>,
+    mut layers: ResMut<CellLayers>,
+) {
+    let selected = memory_query
+        .iter()
+        .find(|(agent, _, _)| agent.id == selected_agent.0);
+    let changed = fog.is_changed()
+        || (fog.0.is_some() && (selected_agent.is_changed() || actions.is_changed()))
+        || selected.is_some_and(|(_, _, tracker)| tracker.is_changed());
+    if !changed {
+        return;
+    }
+
+    let memory = selected.map(|(_, memory, _)| memory);
+    layers.visible_ghosts = memory.map(|memory| memory.agents.clone());
+    layers.fog = memory.map(|memory| {
+        Array2::from_shape_fn(memory.grid.dim(), |(x, y)| {
+            if memory.visible[[x, y]] {
+                1.0
+            } else if memory.grid[[x, y]] != UNKNOWN {
+                0.45
+            } else {
+                0.15
+            }
+        })
+    });
+}
+
+#[derive(Component, Debug, Clone, Copy)]
+struct MemoryMap;
+
+#[derive(Component, Debug, Clone, Copy)]
+struct MemoryCell(CellPosition);
+
+/// Size in pixels of the memory map, drawn in the margin right of the layout.
+const MEMORY_MAP_SIZE: f32 = 150.0;
+
+fn spawn_memory_map(
+    mut commands: Commands,
+    fog: Res<FogSettings>,
+    grid_config: Option<Res<GridConfig>>,
+    map_query: Query<Entity, With<MemoryMap>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<cell::CellMaterial>>,
+) {
+    if !fog.is_changed() && (fog.0.is_none() || !map_query.is_empty()) {
+        return;
+    }
+    for entity in map_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    let (Some(_), Some(grid_config)) = (fog.0, grid_config) else {
+        return;
+    };
+    let size = MEMORY_MAP_SIZE / grid_config.grid_width.max(grid_config.grid_height) as f32;
+    let mesh = meshes.add(cell::Cell::new(size, size).into());
+    commands
+        .spawn(SpatialBundle::from_transform(Transform::from_xyz(
+            WIDTH / 2.0 + 12.0,
+            HEIGHT / 2.0,
+            0.0,
+        )))
+        .insert(MemoryMap)
+        .insert(Name::new("Memory map"))
+        .with_children(|parent| {
+            for (x, y) in iproduct!(0..grid_config.grid_width, 0..grid_config.grid_height) {
+                parent
+                    .spawn(MaterialMesh2dBundle {
+                        mesh: mesh.clone().into(),
+                        material: materials.add(cell::CellMaterial::new(Color::DARK_GRAY)),
+                        transform: Transform::from_xyz(
+                            size * (x as f32 + 0.5),
+                            -size * (y as f32 + 0.5),
+                            0.0,
+                        ),
+                        ..default()
+                    })
+                    .insert(MemoryCell(CellPosition::new(x, y)));
+            }
+        });
+}
+
+/// The memory of the selected pacman, the cells it sees right now brighter.
+fn render_memory_map(
+    mut commands: Commands,
+    selected_agent: Res<SelectedAgent>,
+    memory_query: Query<(&Agent, &CellPosition, &Memory, ChangeTrackers<Memory>)>,
+    cell_query: Query<(Entity, &MemoryCell), Added<MemoryCell>>,
+    all_cells_query: Query<(Entity, &MemoryCell)>,
+) {
+    let Some((_, position, memory, tracker)) = memory_query
+        .iter()
+        .find(|(agent, _, _, _)| agent.id == selected_agent.0)
+    else {
+        return;
+    };
+    let cell_query = match tracker.is_changed() || selected_agent.is_changed() {
+        true => all_cells_query.iter().collect::<Vec<_>>(),
+        false => cell_query.iter().collect(),
+    };
+    for (entity, cell) in cell_query {
+        let (x, y) = (cell.0.x as usize, cell.0.y as usize);
+        let color = match memory.grid.get([x, y]) {
+            _ if cell.0 == *position => grid::agent_color(false, None),
+            Some(&UNKNOWN) | None => Color::DARK_GRAY,
+            Some(value) if memory.visible[[x, y]] => grid::cell_color(*value),
+            Some(value) => darken(grid::cell_color(*value), 0.6),
+        };
+        commands.entity(entity).insert(UpdateCell { color });
+    }
+}
+
+fn despawn_memory_map(mut commands: Commands, map_query: Query<Entity, With<MemoryMap>>) {
+    for entity in map_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -0,0 +1,399 @@
+use std::{collections::HashSet, fs, io, path::Path};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cell::CellPosition,
+    controller::{shortest_path, Controller, ControllerConfig},
+    mdp::{MdpSettings, TerminalRewards},
+    movement::{self, Bumped, PreviousPosition},
+    rng::{GameRng, GameSeed, Stream},
+    simulation::SimulationClock,
+    Agent, AppState,
+};
+
+/// Pacman layouts are won by eating every objective, gridworld layouts end
+/// as soon as an agent reaches one of their terminal cells.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GameMode {
+    #[default]
+    Pacman,
+    Gridworld,
+}
+
+/// Number of ticks ghosts stay scared after a capsule is eaten.
+pub const SCARED_TIME: u32 = 40;
+
+/// What pacmen are rewarded for, read from a file such as `assets/rewards.json`
+/// where missing entries keep their default value.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rewards {
+    pub food: f32,
+    pub capsule: f32,
+    pub ghost: f32,
+    pub win: f32,
+    pub death: f32,
+    /// Given on every tick of a pacman layout.
+    pub step: f32,
+    /// Given when a move runs into a wall.
+    pub wall_bump: f32,
+    /// Given when a move ends on a cell already visited during the episode.
+    pub revisit: f32,
+    /// Scale of the potential-based shaping `discount * phi(s') - phi(s)`
+    /// where `phi` is minus the maze distance to the closest food, `0.0` turns it off.
+    pub shaping: f32,
+    pub shaping_discount: f32,
+}
+
+impl Default for Rewards {
+    fn default() -> Self {
+        Self {
+            food: 10.0,
+            capsule: 0.0,
+            ghost: 200.0,
+            win: 500.0,
+            death: -500.0,
+            step: -1.0,
+            wall_bump: 0.0,
+            revisit: 0.0,
+            shaping: 0.0,
+            shaping_discount: 0.95,
+        }
+    }
+}
+
+impl Rewards {
+    /// Where the rewards of the game, headless or not, are written down.
+    pub const PATH: &'static str = "./assets/rewards.json";
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// The rewards of [`Rewards::PATH`], the defaults when it can't be read.
+    pub fn load_default() -> Self {
+        Self::load(Self::PATH).unwrap_or_else(|error| {
+            warn!(
+                "Could not load the rewards, keeping the defaults: {}",
+                error
+            );
+            Self::default()
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    /// Shaping potential of a cell, `0.0` when there is no food left.
+    fn potential(&self, actions: &movement::Actions, position: &CellPosition) -> f32 {
+        shortest_path(actions, position, |cell| {
+            actions.grid[[cell.x as usize, cell.y as usize]] == 1
+        })
+        .map_or(0.0, |path| -self.shaping * path.len() as f32)
+    }
+}
+
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct Ghost {
+    /// Ticks left before the ghost stops being scared.
+    pub scared_timer: u32,
+}
+
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct AgentScore {
+    pub score: f32,
+    pub last_reward: f32,
+}
+
+/// Cells an agent went through during the current episode.
+#[derive(Component, Debug, Default, Clone)]
+pub struct Visited(pub HashSet<CellPosition>);
+
+#[derive(Resource, Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Episode {
+    pub number: u32,
+    pub done: bool,
+    pub won: bool,
+}
+
+/// Layout as it was loaded, used to start every new episode.
+#[derive(Resource, Clone)]
+pub struct InitialState(pub movement::Actions);
+
+impl InitialState {
+    /// Start cell of every agent, pacman first and ghosts after them.
+    pub fn agent_positions(&self) -> Vec<(u32, CellPosition)> {
+        self.0
+            .get_agents()
+            .into_iter()
+            .chain(self.0.get_ghosts())
+            .enumerate()
+            .map(|(id, position)| (id as u32, position))
+            .collect()
+    }
+}
+
+/// Whether a finished episode restarts on its own. Headless environments
+/// turn it off and leave the restart to their caller.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct AutoReset(pub bool);
+
+impl Default for AutoReset {
+    fn default() -> Self {
+        Self(true)
+    }
+}
+
+pub struct GamePlugin;
+
+impl Plugin for GamePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GameMode>()
+            .init_resource::<Rewards>()
+            .init_resource::<MdpSettings>()
+            .init_resource::<Episode>()
+            .init_resource::<AutoReset>()
+            .init_resource::<GameSeed>()
+            .init_resource::<GameRng>()
+            .add_system_set(
+                SystemSet::on_enter(AppState::InGame)
+                    .with_system(start_game)
+                    .with_system(spawn_agents.after(start_game)),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(apply_rules.after(movement::movement))
+                    .with_system(track_visited.after(apply_rules).before(reset_episode))
+                    .with_system(reset_episode.after(apply_rules)),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(despawn_agents));
+    }
+}
+
+/// Seeds the game, printing the seed so that it can be played again.
+pub fn start_game(seed: Res<GameSeed>, mut rng: ResMut<GameRng>, mut episode: ResMut<Episode>) {
+    *rng = GameRng::seeded(seed.0);
+    info!("Game seed {}", rng.seed());
+    *episode = Episode::default();
+}
+
+/// Spawns every agent of the layout with the components the rules need, the
+/// grid gives them a body when there is a window.
+pub fn spawn_agents(
+    mut commands: Commands,
+    initial_state: Res<InitialState>,
+    controller_config: Res<ControllerConfig>,
+    mut rng: ResMut<GameRng>,
+) {
+    let pacman_count = initial_state.0.get_agents().len() as u32;
+    for (id, cell_position) in initial_state.agent_positions() {
+        let mut agent = commands.spawn((
+            Agent { id },
+            cell_position,
+            PreviousPosition(cell_position),
+            Bumped::default(),
+            AgentScore::default(),
+            Visited(HashSet::from([cell_position])),
+        ));
+        if id >= pacman_count {
+            agent
+                .insert(Ghost::default())
+                .insert(Controller::new(
+                    controller_config.ghost_kind_for(id),
+                    rng.fork(Stream::Ghosts),
+                ))
+                .insert(Name::new(format!("Ghost {}", id)));
+        } else {
+            agent
+                .insert(Controller::new(
+                    controller_config.kind_for(id),
+                    rng.fork(Stream::Agents),
+                ))
+                .insert(Name::new(format!("Agent {}", id)));
+        }
+    }
+}
+
+fn despawn_agents(mut commands: Commands, agent_query: Query<Entity, With<Agent>>) {
+    for entity in agent_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+/// Runs after the rules so that they can tell a revisit from a first visit.
+pub fn track_visited(mut agent_query: Query<(&CellPosition, &mut Visited), Changed<CellPosition>>) {
+    for (position, mut visited) in agent_query.iter_mut() {
+        visited.0.insert(*position);
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn apply_rules(
+    clock: Res<SimulationClock>,
+    mode: Res<GameMode>,
+    rewards: Res<Rewards>,
+    terminals: Res<TerminalRewards>,
+    mdp_settings: Res<MdpSettings>,
+    initial_state: Res<InitialState>,
+    mut actions: ResMut<movement::Actions>,
+    mut episode: ResMut<Episode>,
+    mut pacman_query: Query<
+        (
+            &CellPosition,
+            &PreviousPosition,
+            &Bumped,
+            &Visited,
+            &mut AgentScore,
+        ),
+        (With<Agent>, Without<Ghost>),
+    >,
+    mut ghost_query: Query<(&Agent, &mut CellPosition, &PreviousPosition, &mut Ghost)>,
+) {
+    if !clock.just_ticked() || episode.done {
+        return;
+    }
+
+    for (_position, _previous, _bumped, _visited, mut score) in pacman_query.iter_mut() {
+        score.last_reward = 0.0;
+    }
+    for (_agent, _position, _previous, mut ghost) in ghost_query.iter_mut() {
+        ghost.scared_timer = ghost.scared_timer.saturating_sub(1);
+    }
+
+    for (position, previous, bumped, visited, mut score) in pacman_query.iter_mut() {
+        let cell = [position.x as usize, position.y as usize];
+        let shaping = *mode == GameMode::Pacman && rewards.shaping != 0.0;
+        let previous_potential = match shaping {
+            true => rewards.potential(&actions, &previous.0),
+            false => 0.0,
+        };
+        let mut reward = match *mode {
+            GameMode::Gridworld => match terminals.0.get(position) {
+                Some(reward) => {
+                    episode.done = true;
+                    episode.won = *reward > 0.0;
+                    mdp_settings.living_reward + reward
+                }
+                None => mdp_settings.living_reward,
+            },
+            GameMode::Pacman => rewards.step,
+        };
+        if bumped.0 {
+            reward += rewards.wall_bump;
+        }
+        if *position != previous.0 && visited.0.contains(position) {
+            reward += rewards.revisit;
+        }
+
+        if *mode == GameMode::Pacman {
+            match actions.grid[cell] {
+                1 => {
+                    actions.grid[cell] = 2;
+                    reward += rewards.food;
+                }
+                4 => {
+                    actions.grid[cell] = 2;
+                    reward += rewards.capsule;
+                    for (_agent, _position, _previous, mut ghost) in ghost_query.iter_mut() {
+                        ghost.scared_timer = SCARED_TIME;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (agent, mut ghost_position, ghost_previous, mut ghost) in ghost_query.iter_mut() {
+            let collided = *ghost_position == *position
+                || (*ghost_position == previous.0 && ghost_previous.0 == *position);
+            if !collided {
+                continue;
+            }
+            if ghost.scared_timer > 0 {
+                reward += rewards.ghost;
+                ghost.scared_timer = 0;
+                if let Some((_, start)) = initial_state
+                    .agent_positions()
+                    .into_iter()
+                    .find(|(id, _)| *id == agent.id)
+                {
+                    *ghost_position = start;
+                }
+            } else if !episode.done {
+                reward += rewards.death;
+                episode.done = true;
+                episode.won = false;
+            }
+        }
+
+        if shaping {
+            // The potential of the end of an episode is zero.
+            let potential = match episode.done {
+                true => 0.0,
+                false => rewards.potential(&actions, position),
+            };
+            reward += rewards.shaping_discount * potential - previous_potential;
+        }
+
+        score.last_reward += reward;
+        score.score += reward;
+    }
+
+    if *mode == GameMode::Pacman && !episode.done && actions.indices_of(1).next().is_none() {
+        episode.done = true;
+        episode.won = true;
+        for (_position, _previous, _bumped, _visited, mut score) in pacman_query.iter_mut() {
+            score.last_reward += rewards.win;
+            score.score += rewards.win;
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn reset_episode(
+    auto_reset: Res<AutoReset>,
+    initial_state: Res<InitialState>,
+    mut actions: ResMut<movement::Actions>,
+    mut episode: ResMut<Episode>,
+    mut agent_query: Query<(
+        &Agent,
+        &mut CellPosition,
+        &mut PreviousPosition,
+        &mut AgentScore,
+        &mut Visited,
+        Option<&mut Ghost>,
+    )>,
+) {
+    if !episode.done || !auto_reset.0 {
+        return;
+    }
+    let starts = initial_state.agent_positions();
+    for (agent, mut position, mut previous, mut score, mut visited, ghost) in agent_query.iter_mut()
+    {
+        if ghost.is_none() {
+            info!(
+                "Episode {} {} agent {} scored {}",
+                episode.number,
+                if episode.won { "won" } else { "lost" },
+                agent.id,
+                score.score
+            );
+        }
+        if let Some((_, start)) = starts.iter().find(|(id, _)| *id == agent.id) {
+            *position = *start;
+            previous.0 = *start;
+            visited.0 = HashSet::from([*start]);
+        }
+        if let Some(mut ghost) = ghost {
+            *ghost = Ghost::default();
+        }
+        *score = AgentScore::default();
+    }
+    *actions = initial_state.0.clone();
+    episode.number += 1;
+    episode.done = false;
+    episode.won = false;
+}
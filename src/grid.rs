@@ -1,4 +1,4 @@
-use crate::{cell, movement, Agent, AppState, UpdateCell};
+use crate::{cell, game, movement, simulation::SimulationClock, Agent, AppState, UpdateCell};
 use bevy::{
     prelude::*,
     sprite::{Material2dPlugin, MaterialMesh2dBundle},
@@ -83,20 +83,41 @@ pub struct GridData {
     pub grid_id: Entity,
 }
 
+/// Color of a cell from its value in the layout.
+pub fn cell_color(value: i8) -> Color {
+    match value {
+        0 => Color::BLACK,
+        1 => Color::BISQUE,
+        4 => Color::ORANGE,
+        _ => Color::ALICE_BLUE,
+    }
+}
+
+pub fn agent_color(selected: bool, ghost: Option<&game::Ghost>) -> Color {
+    match (selected, ghost) {
+        (true, _) => Color::FUCHSIA,
+        (false, Some(ghost)) if ghost.scared_timer > 0 => Color::BLUE,
+        (false, Some(_)) => Color::RED,
+        (false, None) => Color::VIOLET,
+    }
+}
+
 pub struct GridPlugin;
 
 impl Plugin for GridPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.add_plugin(Material2dPlugin::<cell::CellMaterial>::default())
             .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(spawn_cells))
-            .add_system_set(SystemSet::on_update(AppState::InGame).with_system(update_agents))
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(attach_agent_bodies)
+                    .with_system(start_agent_motion.after(attach_agent_bodies))
+                    .with_system(update_agents.after(start_agent_motion)),
+            )
             .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(cleanup_game));
     }
 }
 
-#[derive(Component)]
-pub struct LastUpdate(pub f64);
-
 fn spawn_cells(
     grid_config: ResMut<GridConfig>,
     mut commands: Commands,
@@ -134,24 +155,6 @@ fn spawn_cells(
         }
     });
 
-    parent_grid.with_children(|parent| {
-        for (id, cell_position) in actions.get_agents().iter().enumerate() {
-            let handle = materials.add(cell::CellMaterial::new(Color::VIOLET));
-            let (x, y) = cell_position.to_screen_position(&grid_config);
-
-            parent
-                .spawn(MaterialMesh2dBundle {
-                    mesh: meshes.add(cell::Cell::new(x_size, y_size).into()).into(),
-                    material: handle,
-                    transform: Transform::from_xyz(x, y, 1.),
-                    ..default()
-                })
-                .insert(Agent { id: id as u32 })
-                .insert(*cell_position)
-                .insert(Name::new(format!("Agent {}", id)));
-        }
-    });
-
     for cell_position in actions.get_walls().iter() {
         if cell_position.within_map_bounds(&grid_config) {
             let cell_entity = grid.get(&cell_position).unwrap();
@@ -178,7 +181,6 @@ fn spawn_cells(
             grid_size: grid_config.clone(),
             grid: grid,
         })
-        .insert(LastUpdate(0.0))
         .insert(Name::new("Grid"));
 
     commands.insert_resource(GridData {
@@ -186,17 +188,101 @@ fn spawn_cells(
     });
 }
 
+/// Draws the agents spawned by the game on top of the grid.
+fn attach_agent_bodies(
+    grid_config: Res<GridConfig>,
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<cell::CellMaterial>>,
+    agent_query: Query<(Entity, &cell::CellPosition, Option<&game::Ghost>), Added<Agent>>,
+) {
+    let x_size = (grid_config.window_width / grid_config.grid_width) as f32;
+    let y_size = (grid_config.window_height / grid_config.grid_height) as f32;
+    for (entity, cell_position, ghost) in agent_query.iter() {
+        let (x, y) = cell_position.to_screen_position(&grid_config);
+        commands
+            .entity(entity)
+            .insert(MaterialMesh2dBundle {
+                mesh: meshes.add(cell::Cell::new(x_size, y_size).into()).into(),
+                material: materials.add(cell::CellMaterial::new(agent_color(false, ghost))),
+                transform: Transform::from_xyz(x, y, 1.),
+                ..default()
+            })
+            .insert(AgentMotion::still(*cell_position));
+    }
+}
+
+/// Animates an agent from the cell it left to the cell it is now on.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct AgentMotion {
+    pub from: cell::CellPosition,
+    pub to: cell::CellPosition,
+    pub progress: f32,
+}
+
+impl AgentMotion {
+    pub fn still(position: cell::CellPosition) -> Self {
+        Self {
+            from: position,
+            to: position,
+            progress: 1.0,
+        }
+    }
+
+    /// Screen position at the current progress, eased in and out.
+    pub fn screen_position(&self, config: &GridConfig) -> Vec2 {
+        let t = self.progress.clamp(0.0, 1.0);
+        let t = t * t * (3.0 - 2.0 * t);
+        let from = Vec2::from(self.from.to_screen_position(config));
+        let to = Vec2::from(self.to.to_screen_position(config));
+
+        // Agents sent back to their start snap there instead of sliding across.
+        if self.is_jump() {
+            to
+        } else {
+            from.lerp(to, t)
+        }
+    }
+
+    fn is_jump(&self) -> bool {
+        self.from.x.abs_diff(self.to.x) + self.from.y.abs_diff(self.to.y) > 1
+    }
+}
+
+fn start_agent_motion(
+    mut agent_query: Query<(&cell::CellPosition, &mut AgentMotion), Changed<cell::CellPosition>>,
+) {
+    for (cell_position, mut motion) in agent_query.iter_mut() {
+        if motion.to != *cell_position {
+            motion.from = motion.to;
+            motion.to = *cell_position;
+            motion.progress = 0.0;
+        }
+    }
+}
+
 fn update_agents(
-    grid_query: Query<&mut Grid>,
-    mut agent_query: Query<(&Agent, &cell::CellPosition, &mut Transform)>,
+    time: Res<Time>,
+    clock: Res<SimulationClock>,
+    grid_query: Query<&Grid>,
+    mut agent_query: Query<(&Agent, &mut AgentMotion, &mut Transform)>,
 ) {
+    let duration = clock.tick_length / clock.speed;
     for grid in grid_query.iter() {
-        for (_agent, cell_position, mut transform) in agent_query.iter_mut() {
-            if cell_position.within_map_bounds(&grid.config) {
-                let (x, y) = cell_position.to_screen_position(&grid.config);
-                transform.translation.x = x;
-                transform.translation.y = y;
+        for (_agent, mut motion, mut transform) in agent_query.iter_mut() {
+            if !motion.to.within_map_bounds(&grid.config) {
+                continue;
+            }
+            if motion.progress < 1.0 {
+                motion.progress = if duration > 0.0 {
+                    motion.progress + time.delta_seconds() / duration
+                } else {
+                    1.0
+                };
             }
+            let position = motion.screen_position(&grid.config);
+            transform.translation.x = position.x;
+            transform.translation.y = position.y;
         }
     }
 }
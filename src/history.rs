@@ -0,0 +1,318 @@
+use bevy::prelude::*;
+
+use crate::{
+    controller::{self, Controller, ControllerKind},
+    movement::{self, Direction, Movement},
+    replay::{self, ReplayRecorder, Snapshot},
+    simulation::{self, SimulationClock},
+    timeline::Timeline,
+    Agent, AppState,
+};
+
+/// The moves of one tick in which someone played from the keyboard, with the
+/// state they were made from so that they can be taken back.
+#[derive(Debug, Clone)]
+pub struct MoveCommand {
+    /// Tick the moves were made on.
+    pub tick: u64,
+    pub moves: Vec<(u32, Direction)>,
+    before: Snapshot,
+}
+
+impl MoveCommand {
+    /// Puts the game back as it was before the moves, paused so that it does
+    /// not go on without the player.
+    pub fn undo(&self, world: &mut World) {
+        self.before.restore(world);
+        let mut clock = world.resource_mut::<SimulationClock>();
+        clock.tick = self.tick - 1;
+        if !clock.paused {
+            clock.toggle_pause();
+        }
+    }
+
+    /// Makes the moves again from the same state, and the same random numbers,
+    /// so that they end the same way. They are sent on the next tick.
+    pub fn redo(&self, world: &mut World) {
+        self.undo(world);
+        world.resource_mut::<SimulationClock>().step();
+    }
+}
+
+/// Keyboard moves that can be undone with `U` and redone with `Y`. Undoing a
+/// move also takes back whatever happened after it, ghosts included.
+#[derive(Resource, Debug, Clone)]
+pub struct MoveHistory {
+    pub capacity: usize,
+    done: Vec<MoveCommand>,
+    undone: Vec<MoveCommand>,
+    moves: Vec<(u32, Direction)>,
+    /// State at the end of the last frame, the one the next moves start from.
+    latest: Option<Snapshot>,
+    redoing: Option<MoveCommand>,
+}
+
+impl Default for MoveHistory {
+    fn default() -> Self {
+        Self::new(200)
+    }
+}
+
+impl MoveHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            done: Vec::new(),
+            undone: Vec::new(),
+            moves: Vec::new(),
+            latest: None,
+            redoing: None,
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.done.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.done.clear();
+        self.undone.clear();
+        self.moves.clear();
+        self.latest = None;
+        self.redoing = None;
+    }
+
+    fn push(&mut self, command: MoveCommand) {
+        self.done.push(command);
+        if self.done.len() > self.capacity.max(1) {
+            self.done.remove(0);
+        }
+    }
+}
+
+pub struct HistoryPlugin;
+
+impl Plugin for HistoryPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MoveHistory>()
+            .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(clear_history))
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(
+                        redo_moves
+                            .after(controller::drive_agents)
+                            .before(replay::record_moves)
+                            .before(movement::movement),
+                    )
+                    .with_system(collect_moves.after(redo_moves).before(movement::movement))
+                    .with_system(record_command.at_end())
+                    .with_system(undo_keys.before(simulation::advance_clock)),
+            );
+    }
+}
+
+fn clear_history(mut history: ResMut<MoveHistory>) {
+    history.clear();
+}
+
+/// On the tick of a redo, the recorded moves replace whatever the controllers
+/// chose.
+fn redo_moves(
+    clock: Res<SimulationClock>,
+    history: Res<MoveHistory>,
+    mut movement_events: ResMut<Events<Movement>>,
+) {
+    if !clock.just_ticked() {
+        return;
+    }
+    let Some(command) = history.redoing.as_ref() else {
+        return;
+    };
+    movement_events.clear();
+    for &(agent, direction) in command.moves.iter() {
+        movement_events.send(Movement::new(agent, direction));
+    }
+}
+
+/// Keeps the moves of a tick in which a keyboard controlled agent moved. Any
+/// tick but a redo plays on from the current state, so the moves that were
+/// undone cannot be made again.
+fn collect_moves(
+    clock: Res<SimulationClock>,
+    mut history: ResMut<MoveHistory>,
+    mut movement_event: EventReader<Movement>,
+    controller_query: Query<(&Agent, &Controller)>,
+) {
+    let moves = movement_event
+        .iter()
+        .map(|movement| (movement.agent(), movement.direction()))
+        .collect::<Vec<_>>();
+    history.moves.clear();
+    if !clock.just_ticked() {
+        return;
+    }
+    if history.redoing.is_none() {
+        history.undone.clear();
+    }
+    let played = controller_query.iter().any(|(agent, controller)| {
+        controller.kind == ControllerKind::Keyboard && moves.iter().any(|(id, _)| *id == agent.id)
+    });
+    if played || history.redoing.is_some() {
+        history.moves = moves;
+    }
+}
+
+/// Once the frame is over, the collected moves become a command starting from
+/// the state the previous frame ended in. Only kept while someone plays from
+/// the keyboard.
+fn record_command(world: &mut World) {
+    let mut controller_query = world.query::<&Controller>();
+    let manual = controller_query
+        .iter(world)
+        .any(|controller| controller.kind == ControllerKind::Keyboard);
+    if !manual {
+        return;
+    }
+    let snapshot = Snapshot::capture(world);
+    let tick = world.resource::<SimulationClock>().tick;
+    let mut history = world.resource_mut::<MoveHistory>();
+    let before = history.latest.replace(snapshot);
+    if history.moves.is_empty() {
+        return;
+    }
+    let moves = std::mem::take(&mut history.moves);
+    history.redoing = None;
+    if let Some(before) = before {
+        history.push(MoveCommand {
+            tick,
+            moves,
+            before,
+        });
+    }
+}
+
+/// `U` takes back the last keyboard move, `Y` makes it again. The replay
+/// being recorded and the timeline forget the ticks that were taken back.
+fn undo_keys(world: &mut World) {
+    let keyboard_input = world.resource::<Input<KeyCode>>();
+    let undo = keyboard_input.just_pressed(KeyCode::U);
+    let redo = keyboard_input.just_pressed(KeyCode::Y);
+    if !undo && !redo {
+        return;
+    }
+    let mut history = world.resource_mut::<MoveHistory>();
+    if history.redoing.is_some() {
+        return;
+    }
+    let command = if undo {
+        history.done.pop()
+    } else {
+        history.undone.pop()
+    };
+    let Some(command) = command else {
+        return;
+    };
+    history.latest = Some(command.before.clone());
+    if undo {
+        history.undone.push(command.clone());
+        command.undo(world);
+        info!("Undid the moves of tick {}", command.tick);
+    } else {
+        history.redoing = Some(command.clone());
+        command.redo(world);
+        info!("Redoing the moves of tick {}", command.tick);
+    }
+
+    let tick = command.tick - 1;
+    let mut recorder = world.get_resource_mut::<ReplayRecorder>();
+    if let Some(replay) = recorder.as_mut().and_then(|recorder| recorder.replay_mut()) {
+        replay.ticks.truncate(tick as usize);
+    }
+    if let Some(mut timeline) = world.get_resource_mut::<Timeline>() {
+        timeline.forget_after(tick);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        cell::CellPosition, controller::ControllerConfig, game::Rewards, layout::Layout,
+        movement::TransitionModel, replay::Replay, simulation::KeyboardBuffer,
+    };
+
+    fn keyboard_game() -> App {
+        let layout = Layout::load("assets/layouts/testMaze.json").unwrap();
+        let replay = Replay::new(layout, 3, TransitionModel::default(), Rewards::default());
+        let mut app = replay.headless_app();
+        app.insert_resource(ControllerConfig {
+            default: ControllerKind::Keyboard,
+            ghosts: ControllerKind::Random,
+            agents: HashMap::new(),
+        })
+        .add_plugin(HistoryPlugin);
+        app.update();
+        app
+    }
+
+    fn play(app: &mut App, direction: Direction) {
+        app.world.resource_mut::<KeyboardBuffer>().0 = Some(direction);
+        app.world.resource_mut::<SimulationClock>().step();
+        app.update();
+    }
+
+    fn press(app: &mut App, key: KeyCode) {
+        app.world.resource_mut::<Input<KeyCode>>().press(key);
+        app.update();
+        let mut keyboard_input = app.world.resource_mut::<Input<KeyCode>>();
+        keyboard_input.release(key);
+        keyboard_input.clear();
+    }
+
+    fn pacman(app: &mut App) -> u32 {
+        let mut query = app.world.query::<(&Agent, &CellPosition)>();
+        query
+            .iter(&app.world)
+            .find(|(agent, _)| agent.id == 0)
+            .map(|(_, position)| position.x)
+            .unwrap()
+    }
+
+    #[test]
+    fn undo_redo_then_a_new_move() {
+        let mut app = keyboard_game();
+        assert_eq!(pacman(&mut app), 7);
+        play(&mut app, Direction::LEFT);
+        play(&mut app, Direction::LEFT);
+        assert_eq!(pacman(&mut app), 5);
+
+        press(&mut app, KeyCode::U);
+        assert_eq!(pacman(&mut app), 6);
+        assert!(app.world.resource::<SimulationClock>().paused);
+        let history = app.world.resource::<MoveHistory>();
+        assert!(history.can_undo() && history.can_redo());
+
+        press(&mut app, KeyCode::Y);
+        assert_eq!(pacman(&mut app), 5);
+        assert!(app.world.resource::<SimulationClock>().paused);
+        assert!(!app.world.resource::<MoveHistory>().can_redo());
+
+        press(&mut app, KeyCode::U);
+        assert_eq!(pacman(&mut app), 6);
+        play(&mut app, Direction::RIGHT);
+        assert_eq!(pacman(&mut app), 7);
+        assert!(!app.world.resource::<MoveHistory>().can_redo());
+
+        press(&mut app, KeyCode::Y);
+        assert_eq!(pacman(&mut app), 7);
+        press(&mut app, KeyCode::U);
+        assert_eq!(pacman(&mut app), 6);
+        assert_eq!(app.world.resource::<SimulationClock>().tick, 1);
+    }
+}
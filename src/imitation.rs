@@ -0,0 +1,415 @@
+use std::{collections::HashMap, fs, io, path::PathBuf};
+
+use bevy::prelude::*;
+use ndarray::prelude::*;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cell::CellPosition,
+    controller::{self, AgentController, Controller, ControllerKind, GameView},
+    dataset::{DatasetFormat, DatasetWriter, Trajectory, TrajectoryRecorder},
+    dqn::Mlp,
+    game::{self, AgentScore, Episode, Ghost, Visited},
+    layout::Layout,
+    movement::{self, Actions, Direction, Movement},
+    observation::{GhostPlanes, ObservationConfig},
+    simulation::SimulationClock,
+    Agent, AppState, MainLayout,
+};
+
+/// Where keyboard sessions are recorded and how they are seen.
+#[derive(Resource, Debug, Clone)]
+pub struct DemonstrationSettings {
+    pub recording: bool,
+    pub dir: PathBuf,
+    pub format: DatasetFormat,
+    pub observation: ObservationConfig,
+}
+
+impl Default for DemonstrationSettings {
+    fn default() -> Self {
+        Self {
+            recording: false,
+            dir: PathBuf::from("demonstrations"),
+            format: DatasetFormat::Npz,
+            observation: local_observation(),
+        }
+    }
+}
+
+/// A window of five by five cells around the agent, which is what the
+/// cloned policies look at by default.
+pub fn local_observation() -> ObservationConfig {
+    ObservationConfig {
+        ghosts: GhostPlanes::Shared,
+        visited: false,
+        window: Some(2),
+    }
+}
+
+/// Episodes being recorded, per agent, with the move chosen on the current tick.
+#[derive(Resource, Default)]
+struct Demonstrations {
+    recorders: HashMap<u32, TrajectoryRecorder>,
+    pending: HashMap<u32, (Array3<f32>, usize)>,
+}
+
+pub struct ImitationPlugin;
+
+impl Plugin for ImitationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DemonstrationSettings>()
+            .init_resource::<Demonstrations>()
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(toggle_recording)
+                    .with_system(
+                        capture_moves
+                            .after(controller::drive_agents)
+                            .before(movement::movement),
+                    )
+                    .with_system(
+                        store_steps
+                            .after(game::apply_rules)
+                            .before(game::reset_episode),
+                    ),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(flush_demonstrations));
+    }
+}
+
+fn toggle_recording(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut settings: ResMut<DemonstrationSettings>,
+) {
+    if keyboard_input.just_pressed(KeyCode::R) {
+        settings.recording = !settings.recording;
+        info!(
+            "Recording demonstrations {}",
+            if settings.recording { "on" } else { "off" }
+        );
+    }
+}
+
+/// Remembers what keyboard driven agents saw when they were given a move.
+#[allow(clippy::type_complexity)]
+fn capture_moves(
+    settings: Res<DemonstrationSettings>,
+    actions: Res<Actions>,
+    mut demonstrations: ResMut<Demonstrations>,
+    mut movement_event: EventReader<Movement>,
+    agent_query: Query<(&Agent, &CellPosition, &Visited, &Controller, Option<&Ghost>)>,
+) {
+    let moves = movement_event
+        .iter()
+        .map(|movement| (movement.agent(), movement.direction()))
+        .collect::<HashMap<_, _>>();
+    if !settings.recording || moves.is_empty() {
+        return;
+    }
+    let agents = agent_query
+        .iter()
+        .map(|(agent, position, _, _, _)| (agent.id, *position))
+        .collect::<Vec<_>>();
+    let ghosts = controller::ghost_views(
+        agent_query
+            .iter()
+            .map(|(agent, position, _, _, ghost)| (agent, position, ghost)),
+    );
+
+    for (agent, position, visited, controller, _) in agent_query.iter() {
+        let Some(direction) = moves.get(&agent.id) else {
+            continue;
+        };
+        if controller.kind != ControllerKind::Keyboard {
+            continue;
+        }
+        let view = GameView {
+            agent,
+            position: *position,
+            actions: &actions,
+            agents: &agents,
+            ghosts: &ghosts,
+            readings: &[],
+            beliefs: &[],
+            visited: &visited.0,
+            keyboard: Some(*direction),
+            selected: true,
+        };
+        let action = Direction::ALL
+            .iter()
+            .position(|candidate| candidate == direction)
+            .unwrap();
+        demonstrations
+            .pending
+            .insert(agent.id, (settings.observation.encode(&view), action));
+    }
+}
+
+fn store_steps(
+    clock: Res<SimulationClock>,
+    settings: Res<DemonstrationSettings>,
+    main_layout: Res<MainLayout>,
+    episode: Res<Episode>,
+    mut demonstrations: ResMut<Demonstrations>,
+    agent_query: Query<(&Agent, &AgentScore)>,
+) {
+    if !clock.just_ticked() {
+        return;
+    }
+    let demonstrations = &mut *demonstrations;
+    for (agent, score) in agent_query.iter() {
+        if let Some((observation, action)) = demonstrations.pending.remove(&agent.id) {
+            demonstrations
+                .recorders
+                .entry(agent.id)
+                .or_insert_with(|| TrajectoryRecorder::new(layout_name(&main_layout), 0))
+                .push(observation, action, score.last_reward, episode.done);
+        }
+    }
+    if episode.done {
+        write_recordings(&settings, demonstrations);
+    }
+}
+
+fn flush_demonstrations(
+    settings: Res<DemonstrationSettings>,
+    mut demonstrations: ResMut<Demonstrations>,
+) {
+    demonstrations.pending.clear();
+    write_recordings(&settings, &mut demonstrations);
+}
+
+fn layout_name(main_layout: &MainLayout) -> String {
+    Layout::load(&main_layout.path)
+        .map(|layout| layout.name)
+        .unwrap_or_else(|_| main_layout.path.clone())
+}
+
+fn write_recordings(settings: &DemonstrationSettings, demonstrations: &mut Demonstrations) {
+    let recorders = std::mem::take(&mut demonstrations.recorders);
+    if recorders.values().all(TrajectoryRecorder::is_empty) {
+        return;
+    }
+    let mut writer = match DatasetWriter::new(&settings.dir, settings.format) {
+        Ok(writer) => writer,
+        Err(error) => {
+            warn!("Could not open {:?}: {}", settings.dir, error);
+            return;
+        }
+    };
+    for (id, recorder) in recorders {
+        let steps = recorder.len();
+        if steps == 0 {
+            continue;
+        }
+        match writer.write(&recorder.finish()) {
+            Ok(()) => info!("Recorded {} steps of agent {}", steps, id),
+            Err(error) => warn!("Could not record agent {}: {}", id, error),
+        }
+    }
+}
+
+/// A classifier from observations to the move a human would have made.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClonedPolicy {
+    pub observation: ObservationConfig,
+    pub network: Mlp,
+}
+
+impl ClonedPolicy {
+    pub fn load(path: &PathBuf) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save(&self, path: &PathBuf) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)
+    }
+
+    /// Probability of every action, indexed like [`Direction::ALL`].
+    pub fn probabilities(&self, observation: ArrayView1<f32>) -> Array1<f32> {
+        softmax(self.network.predict(observation).view())
+    }
+
+    /// Most likely action among the allowed ones.
+    pub fn act(&self, observation: ArrayView1<f32>, legal: &[bool; 4]) -> Option<usize> {
+        self.probabilities(observation)
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| legal[*index])
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, _)| index)
+    }
+
+    /// Share of the recorded moves the policy predicts.
+    pub fn accuracy(&self, trajectories: &[Trajectory]) -> f32 {
+        let (inputs, targets) = flatten(trajectories, self.network.inputs());
+        if targets.is_empty() {
+            return 0.0;
+        }
+        let outputs = self.network.forward(inputs.view());
+        let correct = outputs
+            .outer_iter()
+            .zip(targets.iter())
+            .filter(|(output, target)| argmax(output.view()) == **target)
+            .count();
+        correct as f32 / targets.len() as f32
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloningSettings {
+    pub hidden: Vec<usize>,
+    pub learning_rate: f32,
+    pub batch_size: usize,
+    pub epochs: usize,
+}
+
+impl Default for CloningSettings {
+    fn default() -> Self {
+        Self {
+            hidden: vec![64],
+            learning_rate: 1e-3,
+            batch_size: 64,
+            epochs: 20,
+        }
+    }
+}
+
+/// Splits episodes between training and evaluation, keeping whole episodes together.
+pub fn split_episodes(
+    mut trajectories: Vec<Trajectory>,
+    held_out: f32,
+    rng: &mut impl Rng,
+) -> (Vec<Trajectory>, Vec<Trajectory>) {
+    trajectories.shuffle(rng);
+    let evaluation = ((trajectories.len() as f32 * held_out).round() as usize)
+        .min(trajectories.len().saturating_sub(1));
+    let training = trajectories.split_off(evaluation);
+    (training, trajectories)
+}
+
+/// Trains a policy to predict the recorded actions with a cross entropy loss.
+/// Every trajectory must use the observations described by `observation`;
+/// the ones of another size than the first are skipped.
+pub fn clone_behavior(
+    trajectories: &[Trajectory],
+    observation: ObservationConfig,
+    settings: &CloningSettings,
+    rng: &mut impl Rng,
+) -> ClonedPolicy {
+    let width = trajectories
+        .iter()
+        .find(|trajectory| !trajectory.is_empty())
+        .map_or(0, observation_width);
+    let (inputs, targets) = flatten(trajectories, width);
+    let mut sizes = vec![inputs.ncols()];
+    sizes.extend(&settings.hidden);
+    sizes.push(Direction::ALL.len());
+    let mut network = Mlp::new(&sizes, rng);
+
+    let mut order = (0..targets.len()).collect::<Vec<_>>();
+    for epoch in 0..settings.epochs {
+        order.shuffle(rng);
+        let mut loss = 0.0;
+        for batch in order.chunks(settings.batch_size.max(1)) {
+            let batch_inputs = inputs.select(Axis(0), batch);
+            let activations = network.activations(batch_inputs.view());
+            let mut gradient = activations.last().unwrap().clone();
+            for (row, index) in batch.iter().enumerate() {
+                let mut probabilities = gradient.row_mut(row);
+                let softened = softmax(probabilities.view());
+                loss -= softened[targets[*index]].max(1e-8).ln();
+                probabilities.assign(&softened);
+                probabilities[targets[*index]] -= 1.0;
+            }
+            gradient /= batch.len() as f32;
+            network.backward(&activations, gradient, settings.learning_rate);
+        }
+        info!(
+            "Behavior cloning epoch {} loss {:.4}",
+            epoch + 1,
+            loss / targets.len().max(1) as f32
+        );
+    }
+    ClonedPolicy {
+        observation,
+        network,
+    }
+}
+
+fn observation_width(trajectory: &Trajectory) -> usize {
+    trajectory.observations.len() / trajectory.len().max(1)
+}
+
+/// Stacks the observations of every trajectory `width` values wide. Recordings
+/// of layouts of another size don't fit and are left out.
+fn flatten(trajectories: &[Trajectory], width: usize) -> (Array2<f32>, Vec<usize>) {
+    let mut inputs = Vec::new();
+    let mut targets = Vec::new();
+    for trajectory in trajectories {
+        if trajectory.is_empty() {
+            continue;
+        }
+        if observation_width(trajectory) != width {
+            warn!(
+                "Skipping a demonstration of {} with {} observation values instead of {}",
+                trajectory.layout,
+                observation_width(trajectory),
+                width
+            );
+            continue;
+        }
+        inputs.extend(trajectory.observations.iter());
+        targets.extend(trajectory.actions.iter().map(|action| *action as usize));
+    }
+    let inputs = Array2::from_shape_vec((targets.len(), width), inputs).unwrap();
+    (inputs, targets)
+}
+
+fn softmax(logits: ArrayView1<f32>) -> Array1<f32> {
+    let max = logits.fold(f32::NEG_INFINITY, |max, value| max.max(*value));
+    let exponentials = logits.mapv(|value| (value - max).exp());
+    let sum = exponentials.sum();
+    exponentials / sum
+}
+
+fn argmax(values: ArrayView1<f32>) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(index, _)| index)
+}
+
+/// Plays the moves a cloned policy predicts.
+pub struct ImitationController {
+    policy: Option<ClonedPolicy>,
+}
+
+impl ImitationController {
+    pub fn new(path: &PathBuf) -> Self {
+        let policy = ClonedPolicy::load(path)
+            .map_err(|error| warn!("Could not load policy from {:?}: {}", path, error))
+            .ok();
+        Self { policy }
+    }
+}
+
+impl AgentController for ImitationController {
+    fn next_direction(&mut self, view: &GameView) -> Option<Direction> {
+        let policy = self.policy.as_ref()?;
+        let observation = policy.observation.encode(view);
+        let observation = observation.iter().copied().collect::<Array1<f32>>();
+        let legal = view.actions.legal_directions(&view.position);
+        let legal = Direction::ALL.map(|direction| legal.contains(&direction));
+        policy
+            .act(observation.view(), &legal)
+            .map(|action| Direction::ALL[action])
+    }
+}
@@ -0,0 +1,513 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
+
+use crate::{
+    cell::CellPosition,
+    controller::SelectedAgent,
+    game::{self, Episode, Ghost},
+    movement::{Actions, Direction},
+    overlay::{self, CellLayers},
+    rng::{GameRng, Stream, StreamRng},
+    simulation::SimulationClock,
+    Agent, AppState,
+};
+
+/// Probability of a ghost being on every cell.
+pub type Belief = HashMap<CellPosition, f32>;
+
+/// Noisy sonar: a reading is the Manhattan distance to a ghost plus a noise
+/// in `-range..=range` following a binomial distribution, never below zero.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SonarModel {
+    range: i32,
+    /// Probability of every noise value, from `-range` to `range`.
+    noise: Vec<f32>,
+}
+
+impl Default for SonarModel {
+    fn default() -> Self {
+        Self::new(7)
+    }
+}
+
+impl SonarModel {
+    pub fn new(range: i32) -> Self {
+        let range = range.max(0);
+        let n = 2 * range as usize;
+        let mut weights = vec![1.0f32; n + 1];
+        for k in 1..=n {
+            weights[k] = weights[k - 1] * (n + 1 - k) as f32 / k as f32;
+        }
+        let total = weights.iter().sum::<f32>();
+        let noise = weights.into_iter().map(|weight| weight / total).collect();
+        Self { range, noise }
+    }
+
+    pub fn range(&self) -> i32 {
+        self.range
+    }
+
+    /// `P(reading | distance)`.
+    pub fn probability(&self, reading: u32, distance: u32) -> f32 {
+        self.noise
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| noisy(distance, *index as i32 - self.range) == reading)
+            .map(|(_, probability)| probability)
+            .sum()
+    }
+
+    pub fn sample(&self, distance: u32, rng: &mut impl Rng) -> u32 {
+        let noise = WeightedIndex::new(&self.noise).unwrap().sample(rng) as i32 - self.range;
+        noisy(distance, noise)
+    }
+}
+
+fn noisy(distance: u32, noise: i32) -> u32 {
+    (distance as i32 + noise).max(0) as u32
+}
+
+pub fn manhattan(a: &CellPosition, b: &CellPosition) -> u32 {
+    a.x.abs_diff(b.x) + a.y.abs_diff(b.y)
+}
+
+/// Cells a ghost can be on.
+fn legal_positions(actions: &Actions) -> Vec<CellPosition> {
+    actions
+        .grid
+        .indexed_iter()
+        .filter(|(_, value)| **value != 0)
+        .map(|((x, y), _)| CellPosition::new(x as u32, y as u32))
+        .collect()
+}
+
+/// Where a randomly moving ghost can be after one tick, each equally likely.
+fn successors(actions: &Actions, position: &CellPosition) -> Vec<CellPosition> {
+    let next = actions
+        .legal_directions(position)
+        .into_iter()
+        .map(|direction: Direction| actions.next_position(position, direction))
+        .collect::<Vec<_>>();
+    match next.is_empty() {
+        true => vec![*position],
+        false => next,
+    }
+}
+
+fn uniform(positions: &[CellPosition]) -> Belief {
+    let probability = 1.0 / positions.len().max(1) as f32;
+    positions
+        .iter()
+        .map(|position| (*position, probability))
+        .collect()
+}
+
+fn normalized(mut belief: Belief) -> Option<Belief> {
+    let total = belief.values().sum::<f32>();
+    if total <= 0.0 {
+        return None;
+    }
+    for probability in belief.values_mut() {
+        *probability /= total;
+    }
+    Some(belief)
+}
+
+fn histogram(positions: impl Iterator<Item = CellPosition>) -> Belief {
+    let mut belief = Belief::new();
+    for position in positions {
+        *belief.entry(position).or_default() += 1.0;
+    }
+    normalized(belief).unwrap_or_default()
+}
+
+/// Keeps beliefs over the positions of ghosts that cannot be seen, from the
+/// way they move and from sonar readings.
+pub trait GhostTracker: Send + Sync {
+    fn initialize(&mut self, actions: &Actions, ghosts: usize, rng: &mut StreamRng);
+
+    /// Moves the beliefs one tick forward, ghosts walking at random.
+    fn elapse_time(&mut self, actions: &Actions, rng: &mut StreamRng);
+
+    /// Weighs the beliefs with one reading per ghost, `None` when a ghost gave none.
+    fn observe(
+        &mut self,
+        actions: &Actions,
+        pacman: &CellPosition,
+        readings: &[Option<u32>],
+        sonar: &SonarModel,
+        rng: &mut StreamRng,
+    );
+
+    fn beliefs(&self) -> Vec<Belief>;
+}
+
+/// Forward algorithm over every cell, one distribution per ghost.
+#[derive(Default)]
+pub struct ExactInference {
+    beliefs: Vec<Belief>,
+    positions: Vec<CellPosition>,
+}
+
+impl GhostTracker for ExactInference {
+    fn initialize(&mut self, actions: &Actions, ghosts: usize, _rng: &mut StreamRng) {
+        self.positions = legal_positions(actions);
+        self.beliefs = vec![uniform(&self.positions); ghosts];
+    }
+
+    fn elapse_time(&mut self, actions: &Actions, _rng: &mut StreamRng) {
+        for belief in self.beliefs.iter_mut() {
+            let mut next = Belief::new();
+            for (position, probability) in belief.iter() {
+                let successors = successors(actions, position);
+                let share = probability / successors.len() as f32;
+                for successor in successors {
+                    *next.entry(successor).or_default() += share;
+                }
+            }
+            *belief = next;
+        }
+    }
+
+    fn observe(
+        &mut self,
+        _actions: &Actions,
+        pacman: &CellPosition,
+        readings: &[Option<u32>],
+        sonar: &SonarModel,
+        _rng: &mut StreamRng,
+    ) {
+        for (belief, reading) in self.beliefs.iter_mut().zip(readings) {
+            let Some(reading) = reading else {
+                continue;
+            };
+            let weighted = belief
+                .iter()
+                .map(|(position, probability)| {
+                    let likelihood = sonar.probability(*reading, manhattan(pacman, position));
+                    (*position, probability * likelihood)
+                })
+                .collect();
+            // A reading the belief cannot explain starts it over.
+            *belief = normalized(weighted).unwrap_or_else(|| uniform(&self.positions));
+        }
+    }
+
+    fn beliefs(&self) -> Vec<Belief> {
+        self.beliefs.clone()
+    }
+}
+
+/// Approximate inference with independent particles for every ghost.
+pub struct ParticleFilter {
+    count: usize,
+    positions: Vec<CellPosition>,
+    particles: Vec<Vec<CellPosition>>,
+}
+
+impl ParticleFilter {
+    pub fn new(count: usize) -> Self {
+        Self {
+            count,
+            positions: Vec::new(),
+            particles: Vec::new(),
+        }
+    }
+
+    /// Particles spread evenly over the cells.
+    fn spread(&self) -> Vec<CellPosition> {
+        (0..self.count)
+            .map(|index| self.positions[index % self.positions.len()])
+            .collect()
+    }
+}
+
+impl GhostTracker for ParticleFilter {
+    fn initialize(&mut self, actions: &Actions, ghosts: usize, _rng: &mut StreamRng) {
+        self.positions = legal_positions(actions);
+        self.particles = vec![self.spread(); ghosts];
+    }
+
+    fn elapse_time(&mut self, actions: &Actions, rng: &mut StreamRng) {
+        for particle in self.particles.iter_mut().flatten() {
+            let successors = successors(actions, particle);
+            *particle = successors[rng.gen_range(0..successors.len())];
+        }
+    }
+
+    fn observe(
+        &mut self,
+        _actions: &Actions,
+        pacman: &CellPosition,
+        readings: &[Option<u32>],
+        sonar: &SonarModel,
+        rng: &mut StreamRng,
+    ) {
+        for ghost in 0..self.particles.len() {
+            let Some(reading) = readings.get(ghost).copied().flatten() else {
+                continue;
+            };
+            let particles = &self.particles[ghost];
+            let weights = particles
+                .iter()
+                .map(|particle| sonar.probability(reading, manhattan(pacman, particle)));
+            self.particles[ghost] = match WeightedIndex::new(weights) {
+                Ok(weights) => (0..self.count)
+                    .map(|_| particles[weights.sample(rng)])
+                    .collect(),
+                Err(_) => self.spread(),
+            };
+        }
+    }
+
+    fn beliefs(&self) -> Vec<Belief> {
+        self.particles
+            .iter()
+            .map(|particles| histogram(particles.iter().copied()))
+            .collect()
+    }
+}
+
+/// Approximate inference where every particle places all the ghosts at once,
+/// so that the readings of several ghosts are weighed together.
+pub struct JointParticleFilter {
+    count: usize,
+    ghosts: usize,
+    positions: Vec<CellPosition>,
+    particles: Vec<Vec<CellPosition>>,
+}
+
+impl JointParticleFilter {
+    pub fn new(count: usize) -> Self {
+        Self {
+            count,
+            ghosts: 0,
+            positions: Vec::new(),
+            particles: Vec::new(),
+        }
+    }
+
+    fn scatter(&self, rng: &mut StreamRng) -> Vec<Vec<CellPosition>> {
+        (0..self.count)
+            .map(|_| {
+                (0..self.ghosts)
+                    .map(|_| self.positions[rng.gen_range(0..self.positions.len())])
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl GhostTracker for JointParticleFilter {
+    fn initialize(&mut self, actions: &Actions, ghosts: usize, rng: &mut StreamRng) {
+        self.positions = legal_positions(actions);
+        self.ghosts = ghosts;
+        self.particles = self.scatter(rng);
+    }
+
+    fn elapse_time(&mut self, actions: &Actions, rng: &mut StreamRng) {
+        for position in self.particles.iter_mut().flatten() {
+            let successors = successors(actions, position);
+            *position = successors[rng.gen_range(0..successors.len())];
+        }
+    }
+
+    fn observe(
+        &mut self,
+        _actions: &Actions,
+        pacman: &CellPosition,
+        readings: &[Option<u32>],
+        sonar: &SonarModel,
+        rng: &mut StreamRng,
+    ) {
+        let weights = self.particles.iter().map(|particle| {
+            particle
+                .iter()
+                .zip(readings)
+                .filter_map(|(position, reading)| {
+                    reading.map(|reading| sonar.probability(reading, manhattan(pacman, position)))
+                })
+                .product::<f32>()
+        });
+        self.particles = match WeightedIndex::new(weights) {
+            Ok(weights) => (0..self.count)
+                .map(|_| self.particles[weights.sample(rng)].clone())
+                .collect(),
+            Err(_) => self.scatter(rng),
+        };
+    }
+
+    fn beliefs(&self) -> Vec<Belief> {
+        (0..self.ghosts)
+            .map(|ghost| histogram(self.particles.iter().map(|particle| particle[ghost])))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerKind {
+    Exact,
+    Particles(usize),
+    JointParticles(usize),
+}
+
+impl TrackerKind {
+    pub fn build(&self) -> Box<dyn GhostTracker> {
+        match self {
+            TrackerKind::Exact => Box::<ExactInference>::default(),
+            TrackerKind::Particles(count) => Box::new(ParticleFilter::new(*count)),
+            TrackerKind::JointParticles(count) => Box::new(JointParticleFilter::new(*count)),
+        }
+    }
+}
+
+/// Ghost tracking of the selected pacman: ghosts are hidden, from the screen
+/// and from the pacmen's [`GameView`](crate::controller::GameView), and a
+/// heatmap of where they are believed to be is drawn instead.
+#[derive(Resource, Default)]
+pub struct GhostTracking {
+    pub kind: Option<TrackerKind>,
+    pub sonar: SonarModel,
+    /// Last reading of every ghost, in id order.
+    pub readings: Vec<Option<u32>>,
+    pub beliefs: Vec<Belief>,
+    tracker: Option<Box<dyn GhostTracker>>,
+    episode: u32,
+}
+
+pub struct InferencePlugin;
+
+impl Plugin for InferencePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GhostTracking>()
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(cycle_tracker)
+                    .with_system(
+                        track_ghosts
+                            .after(cycle_tracker)
+                            .after(game::apply_rules)
+                            .before(game::reset_episode),
+                    )
+                    .with_system(render_beliefs.before(overlay::render_values)),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(stop_tracking));
+    }
+}
+
+/// `B` goes through exact inference, particles and joint particles.
+fn cycle_tracker(keyboard_input: Res<Input<KeyCode>>, mut tracking: ResMut<GhostTracking>) {
+    if !keyboard_input.just_pressed(KeyCode::B) {
+        return;
+    }
+    tracking.kind = match tracking.kind {
+        None => Some(TrackerKind::Exact),
+        Some(TrackerKind::Exact) => Some(TrackerKind::Particles(300)),
+        Some(TrackerKind::Particles(_)) => Some(TrackerKind::JointParticles(600)),
+        Some(TrackerKind::JointParticles(_)) => None,
+    };
+    tracking.tracker = None;
+    tracking.beliefs.clear();
+    info!("Ghost tracking {:?}", tracking.kind);
+}
+
+/// Gives the selected pacman a reading of every ghost on each tick and
+/// updates the beliefs with them.
+fn track_ghosts(
+    clock: Res<SimulationClock>,
+    episode: Res<Episode>,
+    actions: Res<Actions>,
+    selected_agent: Res<SelectedAgent>,
+    mut rng: ResMut<GameRng>,
+    mut tracking: ResMut<GhostTracking>,
+    agent_query: Query<(&Agent, &CellPosition, Option<&Ghost>)>,
+) {
+    let Some(kind) = tracking.kind else {
+        return;
+    };
+    let mut ghosts = agent_query
+        .iter()
+        .filter(|(_, _, ghost)| ghost.is_some())
+        .map(|(agent, position, _)| (agent.id, *position))
+        .collect::<Vec<_>>();
+    ghosts.sort_unstable_by_key(|(id, _)| *id);
+    let pacman = agent_query
+        .iter()
+        .filter(|(_, _, ghost)| ghost.is_none())
+        .min_by_key(|(agent, _, _)| (agent.id != selected_agent.0, agent.id))
+        .map(|(_, position, _)| *position);
+    let Some(pacman) = pacman else {
+        return;
+    };
+
+    let tracking = &mut *tracking;
+    let rng = rng.stream(Stream::Sensor);
+    let started = tracking.tracker.is_none() || tracking.episode != episode.number;
+    if !started && !clock.just_ticked() {
+        return;
+    }
+    let tracker = match &mut tracking.tracker {
+        Some(tracker) if !started => {
+            tracker.elapse_time(&actions, rng);
+            tracker
+        }
+        tracker => {
+            let mut new = kind.build();
+            new.initialize(&actions, ghosts.len(), rng);
+            tracking.episode = episode.number;
+            tracker.insert(new)
+        }
+    };
+    tracking.readings = ghosts
+        .iter()
+        .map(|(_, ghost)| Some(tracking.sonar.sample(manhattan(&pacman, ghost), rng)))
+        .collect();
+    tracker.observe(&actions, &pacman, &tracking.readings, &tracking.sonar, rng);
+    tracking.beliefs = tracker.beliefs();
+}
+
+/// Heat of a cell from dark, nothing believed there, to red, the most likely cell.
+fn heat_color(intensity: f32) -> Color {
+    let intensity = intensity.clamp(0.0, 1.0);
+    Color::rgb(0.1 + 0.9 * intensity, 0.1 + 0.3 * intensity, 0.15)
+}
+
+/// Paints where the ghosts are believed to be instead of showing them.
+fn render_beliefs(
+    tracking: Res<GhostTracking>,
+    actions: Res<Actions>,
+    mut layers: ResMut<CellLayers>,
+) {
+    if !tracking.is_changed() {
+        return;
+    }
+    if tracking.kind.is_none() {
+        layers.heat = None;
+        return;
+    }
+
+    let mut heat = Belief::new();
+    for belief in tracking.beliefs.iter() {
+        for (position, probability) in belief {
+            *heat.entry(*position).or_default() += probability;
+        }
+    }
+    let hottest = heat.values().copied().fold(0.0, f32::max);
+    layers.heat = Some(
+        legal_positions(&actions)
+            .into_iter()
+            .map(|position| {
+                let intensity = heat.get(&position).copied().unwrap_or(0.0) / hottest.max(1e-6);
+                (position, heat_color(intensity))
+            })
+            .collect(),
+    );
+}
+
+fn stop_tracking(mut tracking: ResMut<GhostTracking>) {
+    tracking.tracker = None;
+    tracking.beliefs.clear();
+    tracking.readings.clear();
+}
@@ -0,0 +1,58 @@
+use std::{fs, io, path::Path};
+
+use bevy::prelude::{Commands, Resource};
+use ndarray::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{game, mdp, movement};
+
+/// A layout file from `assets/layouts`.
+#[derive(Resource, Debug, Clone, Serialize, Deserialize)]
+pub struct Layout {
+    pub name: String,
+    pub grid: Array2<i8>,
+    #[serde(default)]
+    pub terminals: Vec<mdp::Terminal>,
+    /// Discount, noise and living reward of a gridworld, the defaults when `None`.
+    #[serde(default)]
+    pub mdp: Option<mdp::MdpSettings>,
+}
+
+impl Layout {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, serde_json::to_string(self)?)
+    }
+
+    /// Keeps the first `count` ghosts, in the order they get their ids, and
+    /// empties the cells of the others.
+    pub fn keep_ghosts(&mut self, count: usize) {
+        let mut kept = 0;
+        for value in self.grid.iter_mut().filter(|value| **value == 3) {
+            if kept < count {
+                kept += 1;
+            } else {
+                *value = 2;
+            }
+        }
+    }
+
+    /// Inserts the resources the game rules need to play this layout.
+    pub fn insert_resources(&self, commands: &mut Commands) {
+        let actions = movement::Actions::new(self.grid.clone());
+        commands.insert_resource(if self.terminals.is_empty() {
+            game::GameMode::Pacman
+        } else {
+            game::GameMode::Gridworld
+        });
+        commands.insert_resource(mdp::TerminalRewards::from_layout(&actions, &self.terminals));
+        commands.insert_resource(self.mdp.unwrap_or_default());
+        commands.insert_resource(game::InitialState(actions.clone()));
+        commands.insert_resource(actions);
+        commands.insert_resource(self.clone());
+    }
+}
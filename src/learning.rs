@@ -0,0 +1,429 @@
+use std::{collections::HashMap, fs, io, path::PathBuf};
+
+use bevy::prelude::*;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cell::CellPosition,
+    controller::{AgentController, Controller, GameView, SelectedAgent},
+    features::{ExtractorKind, FeatureExtractor, Features},
+    mdp::MdpMode,
+    movement::Direction,
+    overlay::ValueOverlay,
+    rng::StreamRng,
+    simulation::SimulationClock,
+    Agent, AppState,
+};
+
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct LearningSettings {
+    /// Probability of taking a random action while training.
+    pub epsilon: f32,
+    /// Learning rate.
+    pub alpha: f32,
+    pub discount: f32,
+}
+
+impl Default for LearningSettings {
+    fn default() -> Self {
+        Self {
+            epsilon: 0.05,
+            alpha: 0.2,
+            discount: 0.8,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct QTable {
+    values: HashMap<(CellPosition, Direction), f32>,
+}
+
+impl QTable {
+    pub fn get(&self, state: &CellPosition, direction: Direction) -> f32 {
+        self.values
+            .get(&(*state, direction))
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    pub fn set(&mut self, state: &CellPosition, direction: Direction, value: f32) {
+        self.values.insert((*state, direction), value);
+    }
+
+    /// Highest q-value among `directions`, zero when there is none.
+    pub fn max_value(&self, state: &CellPosition, directions: &[Direction]) -> f32 {
+        directions
+            .iter()
+            .map(|direction| self.get(state, *direction))
+            .max_by(|a, b| a.total_cmp(b))
+            .unwrap_or(0.0)
+    }
+
+    /// Best direction among `directions`, ties broken at random.
+    pub fn best_direction(
+        &self,
+        state: &CellPosition,
+        directions: &[Direction],
+        rng: &mut impl Rng,
+    ) -> Option<Direction> {
+        let best = self.max_value(state, directions);
+        let ties = directions
+            .iter()
+            .copied()
+            .filter(|direction| self.get(state, *direction) >= best)
+            .collect::<Vec<_>>();
+        ties.choose(rng).copied()
+    }
+
+    pub fn to_overlay(&self) -> ValueOverlay {
+        let mut overlay = ValueOverlay::default();
+        for ((state, direction), value) in self.values.iter() {
+            let best = overlay.values.entry(*state).or_insert(f32::NEG_INFINITY);
+            if *value > *best {
+                *best = *value;
+                overlay.policy.insert(*state, *direction);
+            }
+        }
+        overlay
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TabularAlgorithm {
+    QLearning,
+    Sarsa,
+}
+
+/// Learns a q-value per (cell, direction) while it plays.
+pub struct TabularController {
+    algorithm: TabularAlgorithm,
+    settings: LearningSettings,
+    table: QTable,
+    training: bool,
+    episodes: u32,
+    last: Option<(CellPosition, Direction)>,
+    pending: Option<(CellPosition, Direction, f32)>,
+    rng: StreamRng,
+}
+
+impl TabularController {
+    pub fn new(algorithm: TabularAlgorithm, settings: LearningSettings, rng: StreamRng) -> Self {
+        Self {
+            algorithm,
+            settings,
+            table: QTable::default(),
+            training: true,
+            episodes: 0,
+            last: None,
+            pending: None,
+            rng,
+        }
+    }
+
+    pub fn table(&self) -> &QTable {
+        &self.table
+    }
+
+    fn update(&mut self, state: CellPosition, direction: Direction, target: f32) {
+        let value = self.table.get(&state, direction);
+        self.table.set(
+            &state,
+            direction,
+            value + self.settings.alpha * (target - value),
+        );
+    }
+
+    fn choose(&mut self, view: &GameView, directions: &[Direction]) -> Option<Direction> {
+        if self.training && self.rng.gen::<f32>() < self.settings.epsilon {
+            directions.choose(&mut self.rng).copied()
+        } else {
+            self.table
+                .best_direction(&view.position, directions, &mut self.rng)
+        }
+    }
+}
+
+impl AgentController for TabularController {
+    fn next_direction(&mut self, view: &GameView) -> Option<Direction> {
+        let directions = view.actions.legal_directions(&view.position);
+        let direction = self.choose(view, &directions)?;
+
+        // SARSA waits for the next action to bootstrap from it.
+        if let Some((state, previous, reward)) = self.pending.take() {
+            let target =
+                reward + self.settings.discount * self.table.get(&view.position, direction);
+            self.update(state, previous, target);
+        }
+        self.last = Some((view.position, direction));
+        Some(direction)
+    }
+
+    fn observe(&mut self, view: &GameView, reward: f32, done: bool) {
+        let Some((state, direction)) = self.last.take() else {
+            return;
+        };
+        if done {
+            self.episodes += 1;
+            info!("{:?} finished episode {}", self.algorithm, self.episodes);
+        }
+        if !self.training {
+            return;
+        }
+
+        match (self.algorithm, done) {
+            (_, true) => self.update(state, direction, reward),
+            (TabularAlgorithm::QLearning, false) => {
+                let directions = view.actions.legal_directions(&view.position);
+                let target = reward
+                    + self.settings.discount * self.table.max_value(&view.position, &directions);
+                self.update(state, direction, target);
+            }
+            (TabularAlgorithm::Sarsa, false) => {
+                self.pending = Some((state, direction, reward));
+            }
+        }
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+        self.pending = None;
+    }
+
+    fn overlay(&self) -> Option<ValueOverlay> {
+        Some(self.table.to_overlay())
+    }
+
+    fn rng(&mut self) -> Option<&mut StreamRng> {
+        Some(&mut self.rng)
+    }
+}
+
+/// Linear q-function weights, one per feature name.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Weights(pub HashMap<String, f32>);
+
+impl Weights {
+    pub fn load(path: &PathBuf) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save(&self, path: &PathBuf) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn q_value(&self, features: &Features) -> f32 {
+        features
+            .iter()
+            .map(|(name, value)| self.0.get(name).copied().unwrap_or(0.0) * value)
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApproximateSettings {
+    pub learning: LearningSettings,
+    pub extractor: ExtractorKind,
+    /// Weights to start from; the agent starts in evaluation when they are given.
+    pub load: Option<PathBuf>,
+    /// Directory the weights are written to after every episode.
+    pub save_dir: PathBuf,
+}
+
+impl Default for ApproximateSettings {
+    fn default() -> Self {
+        Self {
+            learning: LearningSettings::default(),
+            extractor: ExtractorKind::default(),
+            load: None,
+            save_dir: PathBuf::from("weights"),
+        }
+    }
+}
+
+/// Q-learning on a linear combination of features instead of a table.
+pub struct ApproximateQController {
+    settings: ApproximateSettings,
+    extractor: Box<dyn FeatureExtractor>,
+    weights: Weights,
+    training: bool,
+    episodes: u32,
+    last: Option<Features>,
+    rng: StreamRng,
+}
+
+impl ApproximateQController {
+    pub fn new(settings: ApproximateSettings, rng: StreamRng) -> Self {
+        let weights = match &settings.load {
+            Some(path) => Weights::load(path).unwrap_or_else(|error| {
+                warn!("Could not load weights from {:?}: {}", path, error);
+                Weights::default()
+            }),
+            None => Weights::default(),
+        };
+        Self {
+            extractor: settings.extractor.build(),
+            training: settings.load.is_none(),
+            settings,
+            weights,
+            episodes: 0,
+            last: None,
+            rng,
+        }
+    }
+
+    pub fn weights(&self) -> &Weights {
+        &self.weights
+    }
+
+    fn q_values(&self, view: &GameView) -> Vec<(Direction, f32, Features)> {
+        view.actions
+            .legal_directions(&view.position)
+            .into_iter()
+            .map(|direction| {
+                let features = self.extractor.features(view, direction);
+                (direction, self.weights.q_value(&features), features)
+            })
+            .collect()
+    }
+}
+
+impl AgentController for ApproximateQController {
+    fn next_direction(&mut self, view: &GameView) -> Option<Direction> {
+        let mut q_values = self.q_values(view);
+        let index = if self.training && self.rng.gen::<f32>() < self.settings.learning.epsilon {
+            (0..q_values.len())
+                .collect::<Vec<_>>()
+                .choose(&mut self.rng)
+                .copied()
+        } else {
+            let best = q_values
+                .iter()
+                .map(|(_, value, _)| *value)
+                .max_by(|a, b| a.total_cmp(b))?;
+            (0..q_values.len())
+                .filter(|index| q_values[*index].1 >= best)
+                .collect::<Vec<_>>()
+                .choose(&mut self.rng)
+                .copied()
+        }?;
+        let (direction, _, features) = q_values.swap_remove(index);
+        self.last = Some(features);
+        Some(direction)
+    }
+
+    fn observe(&mut self, view: &GameView, reward: f32, done: bool) {
+        let Some(features) = self.last.take() else {
+            return;
+        };
+        if self.training {
+            let next_value = if done {
+                0.0
+            } else {
+                self.q_values(view)
+                    .iter()
+                    .map(|(_, value, _)| *value)
+                    .max_by(|a, b| a.total_cmp(b))
+                    .unwrap_or(0.0)
+            };
+            let learning = self.settings.learning;
+            let difference =
+                reward + learning.discount * next_value - self.weights.q_value(&features);
+            for (name, value) in features {
+                *self.weights.0.entry(name).or_insert(0.0) += learning.alpha * difference * value;
+            }
+        }
+        if done {
+            self.episodes += 1;
+            info!(
+                "Approximate Q agent {} episode {} weights {:?}",
+                view.agent.id, self.episodes, self.weights.0
+            );
+            if self.training {
+                let path = self
+                    .settings
+                    .save_dir
+                    .join(format!("agent_{}.json", view.agent.id));
+                if let Err(error) = self.weights.save(&path) {
+                    warn!("Could not save weights to {:?}: {}", path, error);
+                }
+            }
+        }
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn rng(&mut self) -> Option<&mut StreamRng> {
+        Some(&mut self.rng)
+    }
+}
+
+/// Whether learning controllers keep exploring and updating, or only exploit.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct Training(pub bool);
+
+impl Default for Training {
+    fn default() -> Self {
+        Self(true)
+    }
+}
+
+pub struct LearningPlugin;
+
+impl Plugin for LearningPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LearningSettings>()
+            .init_resource::<Training>()
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(toggle_training)
+                    .with_system(show_learning.after(crate::simulation::advance_clock)),
+            );
+    }
+}
+
+fn toggle_training(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut training: ResMut<Training>,
+    mut controller_query: Query<&mut Controller>,
+) {
+    let toggled = keyboard_input.just_pressed(KeyCode::X);
+    if toggled {
+        training.0 = !training.0;
+        info!("Training {}", if training.0 { "on" } else { "off" });
+    }
+    // New controllers keep their own mode unless training is off, so agents
+    // loaded for evaluation don't start learning again.
+    for mut controller in controller_query.iter_mut() {
+        if toggled || (controller.is_added() && !training.0) {
+            controller.brain.set_training(training.0);
+        }
+    }
+}
+
+/// Draws what the selected agent has learnt so far.
+fn show_learning(
+    clock: Res<SimulationClock>,
+    mdp_mode: Res<MdpMode>,
+    selected_agent: Res<SelectedAgent>,
+    controller_query: Query<(&Agent, &Controller)>,
+    mut overlay: ResMut<ValueOverlay>,
+) {
+    if !clock.just_ticked() || mdp_mode.is_active() {
+        return;
+    }
+    if let Some(learnt) = controller_query
+        .iter()
+        .find(|(agent, _)| agent.id == selected_agent.0)
+        .and_then(|(_, controller)| controller.brain.overlay())
+    {
+        *overlay = learnt;
+    }
+}
@@ -0,0 +1,68 @@
+#[macro_use]
+extern crate itertools;
+use bevy::prelude::*;
+pub mod cell;
+pub mod cli;
+pub mod controller;
+pub mod curriculum;
+pub mod dataset;
+pub mod dqn;
+pub mod env;
+pub mod evolution;
+pub mod features;
+pub mod fog;
+pub mod game;
+pub mod grid;
+pub mod history;
+pub mod imitation;
+pub mod inference;
+pub mod layout;
+pub mod learning;
+pub mod mdp;
+pub mod menu;
+pub mod movement;
+pub mod observation;
+pub mod overlay;
+pub mod replay;
+pub mod rng;
+pub mod save;
+pub mod simulation;
+pub mod timeline;
+pub mod vec_env;
+pub const HEIGHT: f32 = 1000.0;
+pub const WIDTH: f32 = 1000.0;
+
+#[derive(Debug, Default, Clone, Component)]
+pub struct UpdateCell {
+    pub color: Color,
+}
+
+#[derive(Debug, Default, Clone, Component)]
+pub struct Agent {
+    pub id: u32,
+}
+
+#[derive(Resource)]
+pub struct MainLayout {
+    pub path: String,
+}
+
+impl Default for MainLayout {
+    fn default() -> Self {
+        Self {
+            path: "./assets/layouts/capsuleClassic.json".to_string(),
+        }
+    }
+}
+
+#[derive(Component, Default)]
+pub struct AssetPath {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum AppState {
+    Menu,
+    Loading,
+    InGame,
+}
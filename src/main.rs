@@ -1,33 +1,35 @@
-#[macro_use]
-extern crate itertools;
 use bevy::prelude::*;
-use ndarray::prelude::*;
-use serde::Deserialize;
-use std::fs;
-pub mod cell;
-pub mod grid;
-pub mod menu;
-pub mod movement;
-pub const HEIGHT: f32 = 1000.0;
-pub const WIDTH: f32 = 1000.0;
-
-#[derive(Debug, Deserialize)]
-struct TestStruct {
-    name: String,
-    grid: Array2<i8>,
-}
-
-#[derive(Debug, Default, Clone, Component)]
-pub struct UpdateCell {
-    pub color: Color,
-}
-
-#[derive(Debug, Default, Clone, Component)]
-pub struct Agent {
-    pub id: u32,
-}
+use rixel::{
+    cell, cli, controller, fog, game, grid, history, imitation, inference, layout, learning, mdp,
+    menu, movement, overlay, replay, rng, save, simulation, timeline, Agent, AppState, MainLayout,
+    UpdateCell, HEIGHT, WIDTH,
+};
 
 fn main() {
+    let options = cli::Options::from_env();
+    if options.layout.is_some() {
+        if let Err(error) = options.check_layout() {
+            eprintln!(
+                "Could not load {}: {}\n\n{}",
+                options.layout_path().display(),
+                error,
+                cli::USAGE
+            );
+            std::process::exit(2);
+        }
+    }
+    if options.display != cli::Display::Window {
+        if let Err(error) = cli::run_headless(&options) {
+            eprintln!(
+                "Could not play {}: {}",
+                options.layout_path().display(),
+                error
+            );
+            std::process::exit(1);
+        }
+        return;
+    }
+
     App::new()
         .insert_resource(ClearColor(Color::BLACK))
         .add_plugins(
@@ -47,50 +49,52 @@ fn main() {
                     ..Default::default()
                 }),
         )
-        .add_state(AppState::Menu)
-        .init_resource::<MainLayout>()
+        .add_state(if options.skips_menu() {
+            AppState::Loading
+        } else {
+            AppState::Menu
+        })
+        .insert_resource(MainLayout {
+            path: options.layout_path().to_string_lossy().into_owned(),
+        })
+        .insert_resource(rng::GameSeed(options.seed))
+        .insert_resource(options.controller_config())
+        .insert_resource(options.clock())
+        .insert_resource(cli::Games::new(options.num_games, options.max_steps))
+        .insert_resource(options)
         .add_startup_system(setup)
         .add_plugin(menu::LayoutsMenu)
-        .add_event::<movement::Movement>()
         .add_system_set(SystemSet::on_enter(AppState::Loading).with_system(setup_game))
         .add_system_set(SystemSet::on_update(AppState::Loading).with_system(game_loaded))
         .add_system_set(
             SystemSet::on_update(AppState::InGame)
-                .with_system(movement::keyboard_movement)
-                .with_system(movement::movement)
+                .with_system(controller::cycle_selected_agent.before(controller::drive_agents))
+                .with_system(controller::click_selected_agent.before(controller::drive_agents))
+                .with_system(controller::cycle_controller)
+                .with_system(controller::highlight_selected_agent)
                 .with_system(selected_cell)
                 .with_system(update_cell)
                 .with_system(keyboard_return),
         )
+        .add_plugin(simulation::SimulationPlugin)
+        .add_plugin(game::GamePlugin)
+        .add_plugin(controller::ControllerPlugin)
+        .add_plugin(learning::LearningPlugin)
+        .add_plugin(imitation::ImitationPlugin)
         .add_plugin(grid::GridPlugin)
+        .add_plugin(overlay::OverlayPlugin)
+        .add_plugin(mdp::MdpPlugin)
+        .add_plugin(fog::FogPlugin)
+        .add_plugin(fog::FogDisplayPlugin)
+        .add_plugin(inference::InferencePlugin)
+        .add_plugin(replay::ReplayPlugin)
+        .add_plugin(timeline::TimelinePlugin)
+        .add_plugin(save::SavePlugin)
+        .add_plugin(history::HistoryPlugin)
+        .add_plugin(cli::GamesPlugin)
         .run();
 }
 
-#[derive(Resource)]
-struct MainLayout {
-    path: String,
-}
-
-impl Default for MainLayout {
-    fn default() -> Self {
-        Self {
-            path: "./assets/layouts/capsuleClassic.json".to_string(),
-        }
-    }
-}
-
-#[derive(Component, Default)]
-struct AssetPath {
-    path: String,
-}
-
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-enum AppState {
-    Menu,
-    Loading,
-    InGame,
-}
-
 fn setup(mut commands: Commands) {
     commands.spawn(Camera2dBundle {
         projection: OrthographicProjection {
@@ -100,12 +104,49 @@ fn setup(mut commands: Commands) {
         ..Default::default()
     });
 }
-fn setup_game(mut commands: Commands, main_layout: Res<MainLayout>) {
-    let file_content = fs::read_to_string(main_layout.path.clone()).unwrap();
-    let test = serde_json::from_str::<TestStruct>(&file_content).unwrap();
-    println!("Name of the test {:?}", test.name);
-    println!("Serde encode {:?} => {:?}", test, file_content);
-    let (grid_width, grid_height) = test.grid.dim();
+/// Loads the layout, replay or saved game picked from the menu or the command
+/// line, going back to the menu when it cannot be read.
+fn setup_game(
+    mut commands: Commands,
+    mut state: ResMut<State<AppState>>,
+    main_layout: Res<MainLayout>,
+    options: Res<cli::Options>,
+) {
+    let extension = std::path::Path::new(&main_layout.path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    // Replays and saved games come with their own rules.
+    let loaded = match extension {
+        replay::EXTENSION => replay::Replay::load(&main_layout.path).map(|replay| {
+            let rules = (replay.rewards, replay.transition_model);
+            let layout = replay.layout.clone();
+            commands.insert_resource(replay::ReplayViewer::new(replay));
+            (layout, Some(rules))
+        }),
+        save::EXTENSION => save::SavedGame::load(&main_layout.path).map(|saved| {
+            let rules = (saved.rewards, saved.transition_model);
+            let layout = saved.layout.clone();
+            commands.insert_resource(save::PendingLoad(saved));
+            (layout, Some(rules))
+        }),
+        _ => layout::Layout::load(&main_layout.path).map(|mut layout| {
+            if let Some(count) = options.num_ghosts {
+                layout.keep_ghosts(count);
+            }
+            (layout, None)
+        }),
+    };
+    let (layout, rules) = match loaded {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            error!("Could not load {}: {}", main_layout.path, error);
+            state.set(AppState::Menu).unwrap();
+            return;
+        }
+    };
+    info!("Playing layout {}", layout.name);
+    let (grid_width, grid_height) = layout.grid.dim();
 
     commands.insert_resource(grid::GridConfig {
         window_height: HEIGHT as u32,
@@ -113,8 +154,15 @@ fn setup_game(mut commands: Commands, main_layout: Res<MainLayout>) {
         grid_height: grid_height as u32,
         grid_width: grid_width as u32,
     });
-
-    commands.insert_resource(movement::Actions::new(test.grid));
+    layout.insert_resources(&mut commands);
+    commands.insert_resource(options.mdp_settings(&layout));
+    if let Some((rewards, transition_model)) = rules {
+        commands.insert_resource(rewards);
+        commands.insert_resource(transition_model);
+        return;
+    }
+    commands.insert_resource(movement::TransitionModel::default());
+    commands.insert_resource(game::Rewards::load_default());
 }
 
 fn game_loaded(mut state: ResMut<State<AppState>>) {
@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cell::CellPosition,
+    movement::{Actions, Direction, TransitionModel},
+    overlay::ValueOverlay,
+    simulation::SimulationClock,
+    AppState,
+};
+
+/// Reward given when an agent reaches a terminal cell, as listed in a layout file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Terminal {
+    pub x: u32,
+    pub y: u32,
+    pub reward: f32,
+}
+
+/// Terminal cells of the current layout: every objective, worth `1.0` unless
+/// the layout gives it another reward.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct TerminalRewards(pub HashMap<CellPosition, f32>);
+
+impl TerminalRewards {
+    pub fn from_layout(actions: &Actions, terminals: &[Terminal]) -> Self {
+        let mut rewards = actions
+            .get_objectives()
+            .into_iter()
+            .map(|position| (position, 1.0))
+            .collect::<HashMap<_, _>>();
+        for terminal in terminals {
+            rewards.insert(CellPosition::new(terminal.x, terminal.y), terminal.reward);
+        }
+        Self(rewards)
+    }
+}
+
+/// Rules of a gridworld, set by its layout file like BridgeGrid or
+/// DiscountGrid need, the missing entries keeping their default value.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MdpSettings {
+    /// Given on every step, the one reaching a terminal cell included.
+    pub living_reward: f32,
+    pub discount: f32,
+    pub noise: f32,
+}
+
+impl Default for MdpSettings {
+    fn default() -> Self {
+        Self {
+            living_reward: 0.0,
+            discount: 0.9,
+            noise: 0.2,
+        }
+    }
+}
+
+/// A layout seen as a Markov decision process over its free cells.
+pub struct GridMdp {
+    actions: Actions,
+    terminals: HashMap<CellPosition, f32>,
+    states: Vec<CellPosition>,
+    transition_model: TransitionModel,
+    pub living_reward: f32,
+    pub discount: f32,
+}
+
+impl GridMdp {
+    pub fn new(actions: &Actions, terminals: &TerminalRewards, settings: &MdpSettings) -> Self {
+        let states = actions
+            .grid
+            .indexed_iter()
+            .filter(|(_, &value)| value != 0)
+            .map(|((x, y), _)| CellPosition::new(x as u32, y as u32))
+            .collect();
+
+        Self {
+            actions: actions.clone(),
+            terminals: terminals.0.clone(),
+            states,
+            transition_model: TransitionModel::noisy(settings.noise, 0.0),
+            living_reward: settings.living_reward,
+            discount: settings.discount,
+        }
+    }
+
+    pub fn states(&self) -> &[CellPosition] {
+        &self.states
+    }
+
+    pub fn terminal_reward(&self, state: &CellPosition) -> Option<f32> {
+        self.terminals.get(state).copied()
+    }
+
+    pub fn is_terminal(&self, state: &CellPosition) -> bool {
+        self.terminals.contains_key(state)
+    }
+
+    pub fn transitions(
+        &self,
+        state: &CellPosition,
+        direction: Direction,
+    ) -> Vec<(CellPosition, f32)> {
+        self.transition_model
+            .outcomes(&self.actions, state, direction)
+    }
+
+    pub fn q_value(
+        &self,
+        values: &HashMap<CellPosition, f32>,
+        state: &CellPosition,
+        direction: Direction,
+    ) -> f32 {
+        self.transitions(state, direction)
+            .iter()
+            .map(|(next, probability)| {
+                let value = values.get(next).copied().unwrap_or(0.0);
+                probability * (self.living_reward + self.discount * value)
+            })
+            .sum()
+    }
+
+    /// Best direction and its q-value, `None` for terminal states.
+    pub fn best_action(
+        &self,
+        values: &HashMap<CellPosition, f32>,
+        state: &CellPosition,
+    ) -> Option<(Direction, f32)> {
+        if self.is_terminal(state) {
+            return None;
+        }
+        Direction::ALL
+            .into_iter()
+            .map(|direction| (direction, self.q_value(values, state, direction)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+
+    pub fn greedy_policy(
+        &self,
+        values: &HashMap<CellPosition, f32>,
+    ) -> HashMap<CellPosition, Direction> {
+        self.states
+            .iter()
+            .filter_map(|state| {
+                self.best_action(values, state)
+                    .map(|(direction, _)| (*state, direction))
+            })
+            .collect()
+    }
+
+    /// Value of a state once it is known which direction is taken from it.
+    fn backup(
+        &self,
+        values: &HashMap<CellPosition, f32>,
+        state: &CellPosition,
+        direction: Option<Direction>,
+    ) -> f32 {
+        match (self.terminal_reward(state), direction) {
+            (Some(reward), _) => reward,
+            (None, Some(direction)) => self.q_value(values, state, direction),
+            (None, None) => 0.0,
+        }
+    }
+}
+
+pub trait MdpSolver: Send + Sync {
+    /// Runs one more iteration of the algorithm.
+    fn iterate(&mut self, mdp: &GridMdp);
+    fn iteration(&self) -> usize;
+    fn values(&self) -> &HashMap<CellPosition, f32>;
+
+    fn policy(&self, mdp: &GridMdp) -> HashMap<CellPosition, Direction> {
+        mdp.greedy_policy(self.values())
+    }
+}
+
+#[derive(Default)]
+pub struct ValueIteration {
+    values: HashMap<CellPosition, f32>,
+    iteration: usize,
+}
+
+impl MdpSolver for ValueIteration {
+    fn iterate(&mut self, mdp: &GridMdp) {
+        self.values = mdp
+            .states()
+            .iter()
+            .map(|state| {
+                let direction = mdp
+                    .best_action(&self.values, state)
+                    .map(|(direction, _)| direction);
+                (*state, mdp.backup(&self.values, state, direction))
+            })
+            .collect();
+        self.iteration += 1;
+    }
+
+    fn iteration(&self) -> usize {
+        self.iteration
+    }
+
+    fn values(&self) -> &HashMap<CellPosition, f32> {
+        &self.values
+    }
+}
+
+/// Alternates a full policy evaluation with a greedy policy improvement.
+pub struct PolicyIteration {
+    values: HashMap<CellPosition, f32>,
+    policy: HashMap<CellPosition, Direction>,
+    iteration: usize,
+    tolerance: f32,
+    max_sweeps: usize,
+}
+
+impl Default for PolicyIteration {
+    fn default() -> Self {
+        Self {
+            values: HashMap::new(),
+            policy: HashMap::new(),
+            iteration: 0,
+            tolerance: 1e-4,
+            max_sweeps: 1000,
+        }
+    }
+}
+
+impl PolicyIteration {
+    fn evaluate(&mut self, mdp: &GridMdp) {
+        for _ in 0..self.max_sweeps {
+            let values = mdp
+                .states()
+                .iter()
+                .map(|state| {
+                    let direction = self
+                        .policy
+                        .get(state)
+                        .copied()
+                        .or((!mdp.is_terminal(state)).then_some(Direction::TOP));
+                    (*state, mdp.backup(&self.values, state, direction))
+                })
+                .collect::<HashMap<_, _>>();
+            let delta = values
+                .iter()
+                .map(|(state, value)| (value - self.values.get(state).unwrap_or(&0.0)).abs())
+                .fold(0.0, f32::max);
+            self.values = values;
+            if delta < self.tolerance {
+                break;
+            }
+        }
+    }
+}
+
+impl MdpSolver for PolicyIteration {
+    fn iterate(&mut self, mdp: &GridMdp) {
+        self.evaluate(mdp);
+        self.policy = mdp.greedy_policy(&self.values);
+        self.iteration += 1;
+    }
+
+    fn iteration(&self) -> usize {
+        self.iteration
+    }
+
+    fn values(&self) -> &HashMap<CellPosition, f32> {
+        &self.values
+    }
+
+    fn policy(&self, _mdp: &GridMdp) -> HashMap<CellPosition, Direction> {
+        self.policy.clone()
+    }
+}
+
+/// The solver currently running on the layout, if the MDP mode is active.
+#[derive(Resource, Default)]
+pub struct MdpMode {
+    solver: Option<(GridMdp, Box<dyn MdpSolver>)>,
+    /// Key the solver was started with, pressing it again stops it.
+    key: Option<KeyCode>,
+}
+
+impl MdpMode {
+    pub fn is_active(&self) -> bool {
+        self.solver.is_some()
+    }
+}
+
+pub struct MdpPlugin;
+
+impl Plugin for MdpPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MdpMode>()
+            .init_resource::<TerminalRewards>()
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(toggle_mdp_mode)
+                    .with_system(
+                        iterate_mdp
+                            .after(toggle_mdp_mode)
+                            .after(crate::simulation::advance_clock),
+                    ),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(stop_mdp_mode));
+    }
+}
+
+fn toggle_mdp_mode(
+    keyboard_input: Res<Input<KeyCode>>,
+    actions: Res<Actions>,
+    terminals: Res<TerminalRewards>,
+    settings: Res<MdpSettings>,
+    mut mode: ResMut<MdpMode>,
+    mut overlay: ResMut<ValueOverlay>,
+) {
+    let (key, solver): (_, Box<dyn MdpSolver>) = if keyboard_input.just_pressed(KeyCode::V) {
+        (KeyCode::V, Box::<ValueIteration>::default())
+    } else if keyboard_input.just_pressed(KeyCode::P) {
+        (KeyCode::P, Box::<PolicyIteration>::default())
+    } else {
+        return;
+    };
+
+    overlay.clear();
+    if mode.key == Some(key) {
+        mode.solver = None;
+        mode.key = None;
+    } else {
+        // The other solver replaces the running one and starts over.
+        let mdp = GridMdp::new(&actions, &terminals, &settings);
+        mode.solver = Some((mdp, solver));
+        mode.key = Some(key);
+    }
+}
+
+fn iterate_mdp(
+    clock: Res<SimulationClock>,
+    mut mode: ResMut<MdpMode>,
+    mut overlay: ResMut<ValueOverlay>,
+) {
+    if !clock.just_ticked() {
+        return;
+    }
+    if let Some((mdp, solver)) = mode.solver.as_mut() {
+        solver.iterate(mdp);
+        overlay.values = solver.values().clone();
+        overlay.policy = solver.policy(mdp);
+        info!("MDP iteration {}", solver.iteration());
+    }
+}
+
+fn stop_mdp_mode(mut mode: ResMut<MdpMode>) {
+    *mode = MdpMode::default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Layout;
+
+    /// Values of the grid of Russell and Norvig's book, with the default
+    /// discount of `0.9` and noise of `0.2`, after enough iterations.
+    fn book_grid_values(solver: &mut dyn MdpSolver) {
+        let layout = Layout::load("assets/layouts/bookGrid.json").unwrap();
+        let actions = Actions::new(layout.grid.clone());
+        let terminals = TerminalRewards::from_layout(&actions, &layout.terminals);
+        let mdp = GridMdp::new(&actions, &terminals, &MdpSettings::default());
+        for _ in 0..100 {
+            solver.iterate(&mdp);
+        }
+        // y = 0 is the top row, the start is in the bottom left corner.
+        for ((x, y), expected) in [((0, 2), 0.49), ((3, 2), 0.28), ((0, 0), 0.64)] {
+            let value = solver.values()[&CellPosition::new(x, y)];
+            assert!(
+                (value - expected).abs() < 0.005,
+                "value of ({}, {}) is {}, not {}",
+                x,
+                y,
+                value,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn value_iteration_solves_the_book_grid() {
+        book_grid_values(&mut ValueIteration::default());
+    }
+
+    #[test]
+    fn policy_iteration_solves_the_book_grid() {
+        book_grid_values(&mut PolicyIteration::default());
+    }
+}
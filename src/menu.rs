@@ -15,7 +15,7 @@ use bevy::{
 };
 
 use crate::AssetPath;
-use crate::{AppState, MainLayout};
+use crate::{replay, save, AppState, MainLayout};
 
 #[derive(Resource)]
 struct MenuData {
@@ -117,8 +117,33 @@ fn setup_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
                         ))
                         .with_children(|parent| {
                             // List items
-                            for file in fs::read_dir("./assets/layouts").unwrap() {
-                                let file_name = file.unwrap().path().display().to_string();
+                            let saved = [
+                                ("./replays", replay::EXTENSION),
+                                ("./saves", save::EXTENSION),
+                            ]
+                            .into_iter()
+                            .flat_map(|(dir, wanted)| {
+                                fs::read_dir(dir)
+                                    .into_iter()
+                                    .flatten()
+                                    .filter_map(|file| file.ok())
+                                    .filter(move |file| {
+                                        file.path()
+                                            .extension()
+                                            .is_some_and(|extension| extension == wanted)
+                                    })
+                            });
+                            for file in fs::read_dir("./assets/layouts")
+                                .unwrap()
+                                .map(|file| file.unwrap())
+                                .chain(saved)
+                            {
+                                let file_name = file.path().display().to_string();
+                                let kind = match file.path().extension() {
+                                    Some(extension) if extension == replay::EXTENSION => "Replay",
+                                    Some(extension) if extension == save::EXTENSION => "Saved game",
+                                    _ => "Layout",
+                                };
                                 parent
                                     .spawn(ButtonBundle {
                                         style: Style {
@@ -145,7 +170,7 @@ fn setup_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
                                     })
                                     .with_children(|parent| {
                                         parent.spawn(TextBundle::from_section(
-                                            format!(" Layout {file_name}"),
+                                            format!(" {kind} {file_name}"),
                                             TextStyle {
                                                 font: asset_server.load("fonts/FiraSans-Bold.ttf"),
                                                 font_size: 20.,
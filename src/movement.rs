@@ -1,9 +1,18 @@
-use bevy::prelude::{Component, EventReader, EventWriter, Input, KeyCode, Query, Res, Resource};
+use std::collections::HashMap;
+
+use bevy::prelude::{Component, EventReader, Input, KeyCode, Query, Res, ResMut, Resource};
 use ndarray::{prelude::*, Slice};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
-use crate::{cell::CellPosition, Agent};
+use crate::{
+    cell::CellPosition,
+    game::Ghost,
+    rng::{GameRng, Stream},
+    Agent,
+};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Direction {
     TOP,
     LEFT,
@@ -11,13 +20,38 @@ pub enum Direction {
     RIGHT,
 }
 
+impl Direction {
+    pub const ALL: [Direction; 4] = [
+        Direction::TOP,
+        Direction::LEFT,
+        Direction::BOTTOM,
+        Direction::RIGHT,
+    ];
+
+    pub fn perpendicular(&self) -> [Direction; 2] {
+        match self {
+            Direction::TOP | Direction::BOTTOM => [Direction::LEFT, Direction::RIGHT],
+            Direction::LEFT | Direction::RIGHT => [Direction::TOP, Direction::BOTTOM],
+        }
+    }
+}
+
 pub struct Movement {
+    agent: u32,
     direction: Direction,
 }
 
 impl Movement {
-    pub fn new(direction: Direction) -> Self {
-        Self { direction }
+    pub fn new(agent: u32, direction: Direction) -> Self {
+        Self { agent, direction }
+    }
+
+    pub fn agent(&self) -> u32 {
+        self.agent
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
     }
 }
 pub struct Shifts {
@@ -28,6 +62,15 @@ pub struct Shifts {
 }
 
 impl Shifts {
+    pub fn allows(&self, direction: Direction) -> bool {
+        match direction {
+            Direction::TOP => self.top > 0,
+            Direction::LEFT => self.left > 0,
+            Direction::BOTTOM => self.bottom > 0,
+            Direction::RIGHT => self.right > 0,
+        }
+    }
+
     pub fn new(top: u8, left: u8, bottom: u8, right: u8) -> Self {
         Self {
             top,
@@ -79,23 +122,6 @@ impl Actions {
         Self { grid, action_grid }
     }
 
-    pub fn empty(height: u32, width: u32) -> Self {
-        let mut base = Array2::<i8>::ones(Dim([height as usize, width as usize]));
-
-        let max = (height * width) / 10;
-        for _ in 0..max {
-            let x = rand::thread_rng().gen_range(0..height) as usize;
-            let y = rand::thread_rng().gen_range(0..width) as usize;
-            base[[x, y]] = 0;
-        }
-        for _ in 0..max {
-            let x = rand::thread_rng().gen_range(0..height) as usize;
-            let y = rand::thread_rng().gen_range(0..width) as usize;
-            base[[x, y]] = 2;
-        }
-        Actions::new(base)
-    }
-
     pub fn indices_of(&self, to_find: i8) -> impl Iterator<Item = (usize, usize)> + '_ {
         self.grid
             .indexed_iter()
@@ -120,6 +146,18 @@ impl Actions {
             .collect::<Vec<_>>()
     }
 
+    pub fn get_ghosts(&self) -> Vec<CellPosition> {
+        self.indices_of(3)
+            .map(|(i, j)| CellPosition::new(i as u32, j as u32))
+            .collect::<Vec<_>>()
+    }
+
+    pub fn get_capsules(&self) -> Vec<CellPosition> {
+        self.indices_of(4)
+            .map(|(i, j)| CellPosition::new(i as u32, j as u32))
+            .collect::<Vec<_>>()
+    }
+
     pub fn get_shifts(&self, x: u8, y: u8) -> Shifts {
         let xs = x as usize;
         let ys = y as usize;
@@ -128,43 +166,308 @@ impl Actions {
         let action = self.action_grid.slice(s![xs..xe, ys..ye]);
         Shifts::from(action.to_owned())
     }
+
+    pub fn legal_directions(&self, position: &CellPosition) -> Vec<Direction> {
+        let shifts = self.get_shifts(position.x as u8, position.y as u8);
+        Direction::ALL
+            .into_iter()
+            .filter(|direction| shifts.allows(*direction))
+            .collect()
+    }
+
+    pub fn next_position(&self, position: &CellPosition, direction: Direction) -> CellPosition {
+        let shifts = self.get_shifts(position.x as u8, position.y as u8);
+        let mut next = *position;
+        match direction {
+            Direction::TOP => {
+                next.y -= shifts.top as u32;
+            }
+            Direction::LEFT => {
+                next.x -= shifts.left as u32;
+            }
+            Direction::BOTTOM => {
+                next.y += shifts.bottom as u32;
+            }
+            Direction::RIGHT => {
+                next.x += shifts.right as u32;
+            }
+        }
+        next
+    }
 }
 
-pub fn keyboard_movement(
-    mut movement_event: EventWriter<Movement>,
-    keyboard_input: Res<Input<KeyCode>>,
-) {
+/// How an intended direction turns into the cell an agent actually reaches.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TransitionModel {
+    #[default]
+    Deterministic,
+    /// Slips to one of the perpendicular directions with probability `slip`
+    /// (split evenly) and stays put with probability `stay`.
+    Noisy { slip: f32, stay: f32 },
+}
+
+impl TransitionModel {
+    pub fn noisy(slip: f32, stay: f32) -> Self {
+        assert!(
+            slip >= 0.0 && stay >= 0.0 && slip + stay <= 1.0,
+            "invalid transition probabilities"
+        );
+        TransitionModel::Noisy { slip, stay }
+    }
+
+    /// Directions the agent may actually take, `None` staying put, with
+    /// their probability.
+    fn moves(&self, direction: Direction) -> Vec<(Option<Direction>, f32)> {
+        match *self {
+            TransitionModel::Deterministic => vec![(Some(direction), 1.0)],
+            TransitionModel::Noisy { slip, stay } => {
+                let [first, second] = direction.perpendicular();
+                vec![
+                    (Some(direction), 1.0 - slip - stay),
+                    (Some(first), slip / 2.0),
+                    (Some(second), slip / 2.0),
+                    (None, stay),
+                ]
+            }
+        }
+    }
+
+    /// Every reachable cell with its probability, blocked moves staying in place.
+    pub fn outcomes(
+        &self,
+        actions: &Actions,
+        position: &CellPosition,
+        direction: Direction,
+    ) -> Vec<(CellPosition, f32)> {
+        let mut outcomes: Vec<(CellPosition, f32)> = Vec::new();
+        for (direction, probability) in self.moves(direction) {
+            if probability <= 0.0 {
+                continue;
+            }
+            let next = direction.map_or(*position, |direction| {
+                actions.next_position(position, direction)
+            });
+            match outcomes.iter_mut().find(|(cell, _)| *cell == next) {
+                Some((_, total)) => *total += probability,
+                None => outcomes.push((next, probability)),
+            }
+        }
+        outcomes
+    }
+
+    /// Direction the agent takes when it means to go `direction`, `None` when
+    /// it stays put.
+    pub fn sample(&self, direction: Direction, rng: &mut impl Rng) -> Option<Direction> {
+        let moves = self.moves(direction);
+        let mut roll = rng.gen::<f32>();
+        for (direction, probability) in moves.iter() {
+            if roll < *probability {
+                return *direction;
+            }
+            roll -= probability;
+        }
+        moves
+            .iter()
+            .rev()
+            .find(|(_, probability)| *probability > 0.0)
+            .and_then(|(direction, _)| *direction)
+    }
+}
+
+/// Whether the agent's last move was into a wall.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Bumped(pub bool);
+
+/// Cell an agent was on before the last tick's movement.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PreviousPosition(pub CellPosition);
+
+pub fn keyboard_direction(keyboard_input: &Input<KeyCode>) -> Option<Direction> {
     if keyboard_input.just_pressed(KeyCode::Q) {
-        movement_event.send(Movement::new(Direction::LEFT));
+        Some(Direction::LEFT)
     } else if keyboard_input.just_pressed(KeyCode::D) {
-        movement_event.send(Movement::new(Direction::RIGHT));
+        Some(Direction::RIGHT)
     } else if keyboard_input.just_pressed(KeyCode::Z) {
-        movement_event.send(Movement::new(Direction::TOP));
+        Some(Direction::TOP)
     } else if keyboard_input.just_pressed(KeyCode::S) {
-        movement_event.send(Movement::new(Direction::BOTTOM));
+        Some(Direction::BOTTOM)
+    } else {
+        None
     }
 }
 
 pub fn movement(
     actions: Res<Actions>,
+    transition_model: Res<TransitionModel>,
+    mut rng: ResMut<GameRng>,
     mut movement_event: EventReader<Movement>,
-    mut agent_query: Query<(&Agent, &mut CellPosition)>,
+    mut agent_query: Query<(
+        &Agent,
+        &mut CellPosition,
+        &mut PreviousPosition,
+        &mut Bumped,
+        Option<&Ghost>,
+    )>,
 ) {
-    for dir in movement_event.iter() {
-        for (_agent, mut position) in agent_query.iter_mut() {
-            let shifts = actions.get_shifts(position.x as u8, position.y as u8);
-            match dir.direction {
-                Direction::TOP => {
-                    position.y -= shifts.top as u32;
-                }
-                Direction::LEFT => {
-                    position.x -= shifts.left as u32;
-                }
-                Direction::BOTTOM => {
-                    position.y += shifts.bottom as u32;
+    let mut intents = HashMap::new();
+    for movement in movement_event.iter() {
+        intents.insert(movement.agent, movement.direction);
+    }
+
+    let mut agents = agent_query
+        .iter()
+        .map(|(agent, position, _, _, ghost)| (agent.id, *position, ghost.is_some()))
+        .collect::<Vec<_>>();
+    agents.sort_unstable_by_key(|(id, _, _)| *id);
+
+    // Pacman and ghosts walk into each other, only agents of a same team block.
+    // Bumps come from the direction an agent slipped into, not the one it meant.
+    let mut taken = HashMap::new();
+    let mut targets = HashMap::new();
+    for team in [false, true] {
+        let positions = agents
+            .iter()
+            .filter(|(_, _, ghost)| *ghost == team)
+            .map(|(id, position, _)| (*id, *position))
+            .collect::<HashMap<_, _>>();
+        let mut team_targets = HashMap::new();
+        for (id, position, _) in agents.iter().filter(|(_, _, ghost)| *ghost == team) {
+            let direction = intents.get(id).and_then(|direction| {
+                transition_model.sample(*direction, rng.stream(Stream::Noise))
+            });
+            taken.insert(*id, direction);
+            let target = direction.map_or(*position, |direction| {
+                actions.next_position(position, direction)
+            });
+            team_targets.insert(*id, target);
+        }
+        targets.extend(resolve_conflicts(&positions, team_targets));
+    }
+
+    for (agent, mut position, mut previous, mut bumped, _) in agent_query.iter_mut() {
+        if previous.0 != *position {
+            previous.0 = *position;
+        }
+        let bump = taken
+            .get(&agent.id)
+            .copied()
+            .flatten()
+            .is_some_and(|direction| !actions.legal_directions(&position).contains(&direction));
+        if bumped.0 != bump {
+            bumped.0 = bump;
+        }
+        if let Some(target) = targets.get(&agent.id) {
+            if *target != *position {
+                *position = *target;
+            }
+        }
+    }
+}
+
+/// Keeps agents from sharing a cell or swapping places: agents staying put keep
+/// their cell, otherwise the lowest id wins and the others stay where they are.
+pub fn resolve_conflicts(
+    positions: &HashMap<u32, CellPosition>,
+    mut targets: HashMap<u32, CellPosition>,
+) -> HashMap<u32, CellPosition> {
+    loop {
+        let mut ids = targets.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        let blocked = ids.iter().copied().find(|id| {
+            let target = targets[id];
+            if target == positions[id] {
+                return false;
+            }
+            ids.iter().any(|other| {
+                other != id
+                    && ((targets[other] == target
+                        && (targets[other] == positions[other] || other < id))
+                        || (targets[other] == positions[id] && positions[other] == target))
+            })
+        });
+
+        match blocked {
+            Some(id) => {
+                targets.insert(id, positions[&id]);
+            }
+            None => return targets,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(cells: &[(u32, (u32, u32))]) -> HashMap<u32, CellPosition> {
+        cells
+            .iter()
+            .map(|(id, (x, y))| (*id, CellPosition::new(*x, *y)))
+            .collect()
+    }
+
+    #[test]
+    fn agents_cannot_swap_places() {
+        let positions = cells(&[(0, (1, 1)), (1, (2, 1))]);
+        let targets = cells(&[(0, (2, 1)), (1, (1, 1))]);
+        assert_eq!(resolve_conflicts(&positions, targets), positions);
+    }
+
+    #[test]
+    fn lowest_id_wins_a_shared_target() {
+        let positions = cells(&[(0, (3, 1)), (1, (1, 1))]);
+        let targets = cells(&[(0, (2, 1)), (1, (2, 1))]);
+        assert_eq!(
+            resolve_conflicts(&positions, targets),
+            cells(&[(0, (2, 1)), (1, (1, 1))])
+        );
+    }
+
+    #[test]
+    fn agents_staying_put_keep_their_cell() {
+        let positions = cells(&[(0, (2, 1)), (1, (1, 1))]);
+        let targets = cells(&[(0, (2, 1)), (1, (2, 1))]);
+        assert_eq!(resolve_conflicts(&positions, targets), positions);
+    }
+
+    #[test]
+    fn chains_move_together_or_not_at_all() {
+        let positions = cells(&[(0, (1, 1)), (1, (2, 1)), (2, (3, 1))]);
+        let targets = cells(&[(0, (2, 1)), (1, (3, 1)), (2, (4, 1))]);
+        assert_eq!(resolve_conflicts(&positions, targets.clone()), targets);
+
+        let blocked = cells(&[(0, (2, 1)), (1, (3, 1)), (2, (3, 1))]);
+        assert_eq!(resolve_conflicts(&positions, blocked), positions);
+    }
+
+    #[test]
+    fn noisy_outcomes_add_up_to_one() {
+        let actions = Actions::new(array![[2, 2, 2], [2, 0, 2], [2, 2, 1]]);
+        for model in [
+            TransitionModel::Deterministic,
+            TransitionModel::noisy(0.2, 0.0),
+            TransitionModel::noisy(0.3, 0.1),
+        ] {
+            for ((x, y), value) in actions.grid.indexed_iter() {
+                if *value == 0 {
+                    continue;
                 }
-                Direction::RIGHT => {
-                    position.x += shifts.right as u32;
+                let position = CellPosition::new(x as u32, y as u32);
+                for direction in Direction::ALL {
+                    let outcomes = model.outcomes(&actions, &position, direction);
+                    let total = outcomes.iter().map(|(_, p)| p).sum::<f32>();
+                    assert!(
+                        (total - 1.0).abs() < 1e-6,
+                        "{:?} from {:?} going {:?} adds up to {}",
+                        model,
+                        position,
+                        direction,
+                        total
+                    );
+                    assert!(outcomes
+                        .iter()
+                        .all(|(cell, _)| actions.grid[[cell.x as usize, cell.y as usize]] != 0));
                 }
             }
         }
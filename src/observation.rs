@@ -0,0 +1,122 @@
+use ndarray::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::controller::GameView;
+
+/// How ghosts are laid out in the observation.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GhostPlanes {
+    /// Every ghost that is not scared on a single plane.
+    #[default]
+    Shared,
+    /// One plane per ghost in id order, for at most that many ghosts.
+    PerGhost(usize),
+}
+
+/// Which planes an observation is made of and how much of the layout it covers.
+///
+/// Planes come in this order: walls, food, capsules, the agent itself, other
+/// pacmen, ghosts (one or several planes), scared ghosts and, when enabled,
+/// the cells visited by the agent during the episode.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObservationConfig {
+    pub ghosts: GhostPlanes,
+    pub visited: bool,
+    /// Half size of a window centred on the agent, the whole layout when `None`.
+    /// Cells outside of the layout read as walls.
+    pub window: Option<usize>,
+}
+
+impl ObservationConfig {
+    pub fn planes(&self) -> usize {
+        let ghosts = match self.ghosts {
+            GhostPlanes::Shared => 1,
+            GhostPlanes::PerGhost(count) => count,
+        };
+        6 + ghosts + self.visited as usize
+    }
+
+    /// `(planes, width, height)` of the observations of a `(width, height)` layout.
+    pub fn shape(&self, width: usize, height: usize) -> (usize, usize, usize) {
+        match self.window {
+            Some(radius) => (self.planes(), 2 * radius + 1, 2 * radius + 1),
+            None => (self.planes(), width, height),
+        }
+    }
+
+    pub fn encode(&self, view: &GameView) -> Array3<f32> {
+        let planes = self.full_planes(view);
+        match self.window {
+            Some(radius) => self.crop(&planes, view, radius),
+            None => planes,
+        }
+    }
+
+    fn full_planes(&self, view: &GameView) -> Array3<f32> {
+        let (width, height) = view.actions.grid.dim();
+        let mut planes = Array3::zeros((self.planes(), width, height));
+        let mut set = |plane: usize, x: u32, y: u32| planes[[plane, x as usize, y as usize]] = 1.0;
+
+        for ((x, y), value) in view.actions.grid.indexed_iter() {
+            match value {
+                0 => set(0, x as u32, y as u32),
+                1 => set(1, x as u32, y as u32),
+                4 => set(2, x as u32, y as u32),
+                _ => {}
+            }
+        }
+        set(3, view.position.x, view.position.y);
+        for (id, position) in view.agents {
+            if *id != view.agent.id && view.ghosts.iter().all(|ghost| ghost.id != *id) {
+                set(4, position.x, position.y);
+            }
+        }
+
+        let scared_plane = self.planes() - 1 - self.visited as usize;
+        let mut ghosts = view
+            .ghosts
+            .iter()
+            .filter(|ghost| ghost.id != view.agent.id)
+            .collect::<Vec<_>>();
+        ghosts.sort_unstable_by_key(|ghost| ghost.id);
+        for (index, ghost) in ghosts.into_iter().enumerate() {
+            let plane = match (ghost.scared_timer > 0, self.ghosts) {
+                (true, _) => scared_plane,
+                (false, GhostPlanes::Shared) => 5,
+                (false, GhostPlanes::PerGhost(count)) if index < count => 5 + index,
+                (false, GhostPlanes::PerGhost(_)) => continue,
+            };
+            set(plane, ghost.position.x, ghost.position.y);
+        }
+
+        if self.visited {
+            let plane = self.planes() - 1;
+            for position in view.visited {
+                set(plane, position.x, position.y);
+            }
+        }
+        planes
+    }
+
+    fn crop(&self, planes: &Array3<f32>, view: &GameView, radius: usize) -> Array3<f32> {
+        let (_, width, height) = planes.dim();
+        let size = 2 * radius + 1;
+        let mut window = Array3::zeros((self.planes(), size, size));
+        window.index_axis_mut(Axis(0), 0).fill(1.0);
+
+        for (wx, wy) in iproduct!(0..size, 0..size) {
+            let x = view.position.x as i64 + wx as i64 - radius as i64;
+            let y = view.position.y as i64 + wy as i64 - radius as i64;
+            if (0..width as i64).contains(&x) && (0..height as i64).contains(&y) {
+                let cell = planes.slice(s![.., x as usize, y as usize]);
+                window.slice_mut(s![.., wx, wy]).assign(&cell);
+            }
+        }
+        window
+    }
+}
+
+/// Observation of the whole layout with the default planes.
+pub fn grid_planes(view: &GameView) -> Array3<f32> {
+    ObservationConfig::default().encode(view)
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::{prelude::*, sprite::MaterialMesh2dBundle};
+use ndarray::Array2;
+
+use crate::{
+    cell::{self, CellPosition},
+    game::Ghost,
+    grid::{self, Grid, GridData},
+    movement::{Actions, Direction},
+    Agent, AppState, UpdateCell,
+};
+
+/// Per-cell values and greedy directions drawn on top of the layout.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct ValueOverlay {
+    pub values: HashMap<CellPosition, f32>,
+    pub policy: HashMap<CellPosition, Direction>,
+}
+
+impl ValueOverlay {
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.policy.clear();
+    }
+}
+
+/// What other displays draw over the cells and ghosts. They only fill in
+/// their layer and [`render_values`] paints everything at once, so that none
+/// of them undoes what another one drew.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct CellLayers {
+    /// Brightness of every cell, `1.0` where nothing darkens it.
+    pub fog: Option<Array2<f32>>,
+    /// Ghosts that can be seen, all of them when `None`.
+    pub visible_ghosts: Option<HashSet<u32>>,
+    /// Colors of the cells while the ghosts are tracked rather than seen,
+    /// which hides every ghost.
+    pub heat: Option<HashMap<CellPosition, Color>>,
+}
+
+/// Red for negative values, green for positive ones, scaled by the largest magnitude.
+pub fn value_color(value: f32, max_magnitude: f32) -> Color {
+    let intensity = if max_magnitude > 0.0 {
+        (value.abs() / max_magnitude).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    if value < 0.0 {
+        Color::rgb(0.1 + 0.8 * intensity, 0.1, 0.1)
+    } else {
+        Color::rgb(0.1, 0.1 + 0.8 * intensity, 0.1)
+    }
+}
+
+#[derive(Component, Debug, Clone, Copy)]
+pub struct PolicyArrow(pub CellPosition);
+
+fn direction_angle(direction: Direction) -> f32 {
+    match direction {
+        Direction::TOP => 0.0,
+        Direction::LEFT => std::f32::consts::FRAC_PI_2,
+        Direction::BOTTOM => std::f32::consts::PI,
+        Direction::RIGHT => -std::f32::consts::FRAC_PI_2,
+    }
+}
+
+pub struct OverlayPlugin;
+
+impl Plugin for OverlayPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ValueOverlay>()
+            .init_resource::<CellLayers>()
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(render_values)
+                    .with_system(render_policy),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(clear_overlay));
+    }
+}
+
+pub(crate) fn darken(color: Color, factor: f32) -> Color {
+    let [r, g, b, a] = color.as_rgba_f32();
+    Color::rgba(r * factor, g * factor, b * factor, a)
+}
+
+/// Paints the cells with their values, or the color of what is on them, then
+/// the [`CellLayers`] on top, and shows the ghosts that can be seen.
+pub fn render_values(
+    mut commands: Commands,
+    overlay: Res<ValueOverlay>,
+    layers: Res<CellLayers>,
+    actions: Res<Actions>,
+    grid_query: Query<&Grid>,
+    mut ghost_query: Query<(&Agent, &mut Visibility), With<Ghost>>,
+) {
+    if !overlay.is_changed() && !actions.is_changed() && !layers.is_changed() {
+        return;
+    }
+    for (agent, mut visibility) in ghost_query.iter_mut() {
+        let visible = layers.heat.is_none()
+            && layers
+                .visible_ghosts
+                .as_ref()
+                .is_none_or(|ghosts| ghosts.contains(&agent.id));
+        if visibility.is_visible != visible {
+            visibility.is_visible = visible;
+        }
+    }
+    let max_magnitude = overlay
+        .values
+        .values()
+        .map(|value| value.abs())
+        .fold(0.0, f32::max);
+
+    for grid in grid_query.iter() {
+        for ((x, y), value) in actions.grid.indexed_iter() {
+            let position = CellPosition::new(x as u32, y as u32);
+            let Some(cell_entity) = grid.checked_get(&position) else {
+                continue;
+            };
+            let color = match overlay.values.get(&position) {
+                Some(value) => value_color(*value, max_magnitude),
+                None => grid::cell_color(*value),
+            };
+            let color = layers
+                .heat
+                .as_ref()
+                .and_then(|heat| heat.get(&position).copied())
+                .unwrap_or(color);
+            let color = match &layers.fog {
+                Some(fog) => darken(color, fog[[x, y]]),
+                None => color,
+            };
+            commands.entity(cell_entity).insert(UpdateCell { color });
+        }
+    }
+}
+
+fn render_policy(
+    mut commands: Commands,
+    overlay: Res<ValueOverlay>,
+    grid_data: Option<Res<GridData>>,
+    grid_query: Query<&Grid>,
+    mut arrow_query: Query<(&PolicyArrow, &mut Transform, &mut Visibility)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<cell::CellMaterial>>,
+) {
+    if !overlay.is_changed() {
+        return;
+    }
+    let Some(grid_data) = grid_data else {
+        return;
+    };
+    let Ok(grid) = grid_query.get(grid_data.grid_id) else {
+        return;
+    };
+
+    let mut missing = overlay.policy.clone();
+    for (arrow, mut transform, mut visibility) in arrow_query.iter_mut() {
+        match missing.remove(&arrow.0) {
+            Some(direction) => {
+                transform.rotation = Quat::from_rotation_z(direction_angle(direction));
+                visibility.is_visible = true;
+            }
+            None => visibility.is_visible = false,
+        }
+    }
+
+    let size = (grid.config.window_width / grid.config.grid_width)
+        .min(grid.config.window_height / grid.config.grid_height) as f32;
+    commands.entity(grid_data.grid_id).with_children(|parent| {
+        for (position, direction) in missing {
+            let (x, y) = position.to_screen_position(&grid.config);
+            parent
+                .spawn(MaterialMesh2dBundle {
+                    mesh: meshes.add(cell::Arrow::new(size * 0.5).into()).into(),
+                    material: materials.add(cell::CellMaterial::new(Color::WHITE)),
+                    transform: Transform::from_xyz(x, y, 0.5)
+                        .with_rotation(Quat::from_rotation_z(direction_angle(direction))),
+                    ..default()
+                })
+                .insert(PolicyArrow(position))
+                .insert(Name::new(format!("Arrow {} {}", position.x, position.y)));
+        }
+    });
+}
+
+fn clear_overlay(mut overlay: ResMut<ValueOverlay>, mut layers: ResMut<CellLayers>) {
+    overlay.clear();
+    *layers = CellLayers::default();
+}
@@ -0,0 +1,703 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::{ecs::system::CommandQueue, prelude::*, time::TimePlugin};
+use ndarray::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cell::CellPosition,
+    controller::{self, Controller, ControllerConfig, ControllerKind},
+    game::{self, AgentScore, Episode, Ghost, Rewards, Visited},
+    layout::Layout,
+    movement::{self, Actions, Bumped, Direction, Movement, PreviousPosition, TransitionModel},
+    rng::{GameRng, GameSeed, Stream, StreamRng},
+    simulation::{self, SimulationClock},
+    Agent, AppState,
+};
+
+/// Extension of replay files, written to `replays/` and listed by the menu.
+pub const EXTENSION: &str = "replay";
+
+/// Everything needed to play a game again exactly: the layout, the rules, the
+/// seed of the game and the moves of every agent on every tick.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub layout: Layout,
+    pub seed: u64,
+    pub transition_model: TransitionModel,
+    pub rewards: Rewards,
+    /// One character per agent and tick: `U`, `L`, `D`, `R` or `.` without a move.
+    pub ticks: Vec<String>,
+}
+
+fn direction_char(direction: Option<Direction>) -> char {
+    match direction {
+        Some(Direction::TOP) => 'U',
+        Some(Direction::LEFT) => 'L',
+        Some(Direction::BOTTOM) => 'D',
+        Some(Direction::RIGHT) => 'R',
+        None => '.',
+    }
+}
+
+pub(crate) fn char_direction(c: char) -> Option<Direction> {
+    match c {
+        'U' => Some(Direction::TOP),
+        'L' => Some(Direction::LEFT),
+        'D' => Some(Direction::BOTTOM),
+        'R' => Some(Direction::RIGHT),
+        _ => None,
+    }
+}
+
+impl Replay {
+    pub fn new(
+        layout: Layout,
+        seed: u64,
+        transition_model: TransitionModel,
+        rewards: Rewards,
+    ) -> Self {
+        Self {
+            layout,
+            seed,
+            transition_model,
+            rewards,
+            ticks: Vec::new(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ticks.is_empty()
+    }
+
+    /// Number of agents of the layout, pacmen and ghosts.
+    pub fn agents(&self) -> usize {
+        self.layout
+            .grid
+            .iter()
+            .filter(|value| **value == -1 || **value == 3)
+            .count()
+    }
+
+    pub fn push(&mut self, moves: &HashMap<u32, Direction>) {
+        let tick = (0..self.agents() as u32)
+            .map(|id| direction_char(moves.get(&id).copied()))
+            .collect();
+        self.ticks.push(tick);
+    }
+
+    /// Moves played on `tick`.
+    pub fn moves(&self, tick: usize) -> Vec<Movement> {
+        self.ticks.get(tick).map_or_else(Vec::new, |tick| {
+            tick.chars()
+                .enumerate()
+                .filter_map(|(id, c)| {
+                    char_direction(c).map(|direction| Movement::new(id as u32, direction))
+                })
+                .collect()
+        })
+    }
+
+    /// Plays the replay without a window and keeps the state of the game
+    /// before the first tick and after every tick.
+    pub fn snapshots(&self) -> Vec<Snapshot> {
+        let mut app = self.headless_app();
+        // The first update spawns the agents without ticking the clock.
+        app.update();
+        let mut snapshots = vec![Snapshot::capture(&mut app.world)];
+        for tick in 0..self.len() {
+            let mut events = app.world.resource_mut::<Events<Movement>>();
+            for movement in self.moves(tick) {
+                events.send(movement);
+            }
+            app.world.resource_mut::<SimulationClock>().step();
+            app.update();
+            snapshots.push(Snapshot::capture(&mut app.world));
+        }
+        snapshots
+    }
+
+    pub(crate) fn headless_app(&self) -> App {
+        let mut app = App::new();
+        app.add_plugin(CorePlugin::default())
+            .add_plugin(TimePlugin)
+            .init_resource::<Input<KeyCode>>()
+            .add_state(AppState::InGame)
+            .add_plugin(simulation::SimulationPlugin)
+            .add_plugin(game::GamePlugin)
+            .add_plugin(controller::ControllerPlugin)
+            .insert_resource(self.rewards)
+            .insert_resource(self.transition_model)
+            .insert_resource(GameSeed(Some(self.seed)))
+            .insert_resource(ControllerConfig {
+                default: ControllerKind::External,
+                ghosts: ControllerKind::External,
+                agents: HashMap::new(),
+            });
+        let mut clock = SimulationClock::default();
+        clock.paused = true;
+        app.insert_resource(clock);
+
+        let mut queue = CommandQueue::default();
+        let mut commands = Commands::new(&mut queue, &app.world);
+        self.layout.insert_resources(&mut commands);
+        queue.apply(&mut app.world);
+        app
+    }
+}
+
+/// State of an agent in a [`Snapshot`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentSnapshot {
+    pub id: u32,
+    pub position: CellPosition,
+    pub previous: CellPosition,
+    pub bumped: bool,
+    pub score: f32,
+    pub last_reward: f32,
+    pub visited: HashSet<CellPosition>,
+    /// `None` for pacmen.
+    pub scared_timer: Option<u32>,
+    /// Generator of the agent's controller, `None` when it draws no random numbers.
+    #[serde(default)]
+    pub rng: Option<StreamRng>,
+}
+
+impl AgentSnapshot {
+    /// Same state, whatever the state of the controller's generator.
+    fn same_agent(&self, other: &AgentSnapshot) -> bool {
+        self.id == other.id
+            && self.position == other.position
+            && self.previous == other.previous
+            && self.bumped == other.bumped
+            && self.score == other.score
+            && self.last_reward == other.last_reward
+            && self.visited == other.visited
+            && self.scared_timer == other.scared_timer
+    }
+}
+
+/// State of a game between two ticks, enough to go on playing it from there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub grid: Array2<i8>,
+    pub episode: Episode,
+    pub agents: Vec<AgentSnapshot>,
+    pub rng: GameRng,
+}
+
+impl Snapshot {
+    pub fn capture(world: &mut World) -> Self {
+        let mut query = world.query::<(
+            &Agent,
+            &CellPosition,
+            &PreviousPosition,
+            &Bumped,
+            &AgentScore,
+            &Visited,
+            Option<&Ghost>,
+            Option<&mut Controller>,
+        )>();
+        let mut agents = query
+            .iter_mut(world)
+            .map(
+                |(agent, position, previous, bumped, score, visited, ghost, controller)| {
+                    AgentSnapshot {
+                        id: agent.id,
+                        position: *position,
+                        previous: previous.0,
+                        bumped: bumped.0,
+                        score: score.score,
+                        last_reward: score.last_reward,
+                        visited: visited.0.clone(),
+                        scared_timer: ghost.map(|ghost| ghost.scared_timer),
+                        rng: controller.and_then(|mut controller| {
+                            controller.bypass_change_detection().brain.rng().cloned()
+                        }),
+                    }
+                },
+            )
+            .collect::<Vec<_>>();
+        agents.sort_unstable_by_key(|agent| agent.id);
+        Self {
+            grid: world.resource::<Actions>().grid.clone(),
+            episode: *world.resource::<Episode>(),
+            agents,
+            rng: world.resource::<GameRng>().clone(),
+        }
+    }
+
+    /// Puts the game back in this state, only touching what differs.
+    pub fn restore(&self, world: &mut World) {
+        if world.resource::<Actions>().grid != self.grid {
+            world.resource_mut::<Actions>().grid = self.grid.clone();
+        }
+        *world.resource_mut::<Episode>() = self.episode;
+        self.restore_rngs(world);
+
+        let mut query = world.query::<(
+            &Agent,
+            &mut CellPosition,
+            &mut PreviousPosition,
+            &mut Bumped,
+            &mut AgentScore,
+            &mut Visited,
+            Option<&mut Ghost>,
+        )>();
+        for (agent, mut position, mut previous, mut bumped, mut score, mut visited, ghost) in
+            query.iter_mut(world)
+        {
+            let Some(saved) = self.agents.iter().find(|saved| saved.id == agent.id) else {
+                continue;
+            };
+            if *position != saved.position {
+                *position = saved.position;
+            }
+            previous.0 = saved.previous;
+            bumped.0 = saved.bumped;
+            score.score = saved.score;
+            score.last_reward = saved.last_reward;
+            if visited.0 != saved.visited {
+                visited.0 = saved.visited.clone();
+            }
+            if let (Some(mut ghost), Some(timer)) = (ghost, saved.scared_timer) {
+                ghost.scared_timer = timer;
+            }
+        }
+    }
+
+    /// Puts back the game's generator and those of the controllers, so that
+    /// the game draws the same numbers again from here.
+    pub fn restore_rngs(&self, world: &mut World) {
+        *world.resource_mut::<GameRng>() = self.rng.clone();
+        let mut query = world.query::<(&Agent, &mut Controller)>();
+        for (agent, mut controller) in query.iter_mut(world) {
+            let saved = self
+                .agents
+                .iter()
+                .find(|saved| saved.id == agent.id)
+                .and_then(|saved| saved.rng.as_ref());
+            if let (Some(saved), Some(rng)) =
+                (saved, controller.bypass_change_detection().brain.rng())
+            {
+                *rng = saved.clone();
+            }
+        }
+    }
+
+    /// Same board, agents and scores, whatever the state of the random generator.
+    pub fn same_game(&self, other: &Snapshot) -> bool {
+        self.grid == other.grid
+            && self.episode.number == other.episode.number
+            && self.episode.done == other.episode.done
+            && self.agents.len() == other.agents.len()
+            && self
+                .agents
+                .iter()
+                .zip(&other.agents)
+                .all(|(agent, other)| agent.same_agent(other))
+    }
+}
+
+/// Records the game being played, saved with F5 into `dir`.
+#[derive(Resource, Debug, Clone)]
+pub struct ReplayRecorder {
+    pub dir: PathBuf,
+    /// Saves the replay when leaving the game as well.
+    pub auto_save: bool,
+    replay: Option<Replay>,
+}
+
+impl Default for ReplayRecorder {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("replays"),
+            auto_save: false,
+            replay: None,
+        }
+    }
+}
+
+impl ReplayRecorder {
+    pub fn replay(&self) -> Option<&Replay> {
+        self.replay.as_ref()
+    }
+
+    pub fn replay_mut(&mut self) -> Option<&mut Replay> {
+        self.replay.as_mut()
+    }
+
+    /// Stops recording, for games that did not start from their layout.
+    pub fn stop(&mut self) {
+        self.replay = None;
+    }
+
+    /// Writes the replay as `<dir>/<layout>_<seconds since epoch>.replay`.
+    pub fn save(&self) -> io::Result<Option<PathBuf>> {
+        let Some(replay) = self.replay.as_ref() else {
+            return Ok(None);
+        };
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        let path = self
+            .dir
+            .join(format!("{}_{}.{}", replay.layout.name, seconds, EXTENSION));
+        replay.save(&path)?;
+        Ok(Some(path))
+    }
+}
+
+/// Plays a replay in the game instead of the controllers. Space and N pause
+/// and step as usual, Left steps back, Home and End jump to the first and
+/// last tick and typing a tick number followed by Return jumps to it.
+#[derive(Resource)]
+pub struct ReplayViewer {
+    pub replay: Replay,
+    snapshots: Vec<Snapshot>,
+    tick: usize,
+    played: bool,
+    /// Tick at which the game went another way than the replay, which stops
+    /// the playback until another tick is picked.
+    diverged: Option<usize>,
+    typed: String,
+    transition_model: Option<TransitionModel>,
+}
+
+impl ReplayViewer {
+    pub fn new(replay: Replay) -> Self {
+        let snapshots = replay.snapshots();
+        Self {
+            replay,
+            snapshots,
+            tick: 0,
+            played: false,
+            diverged: None,
+            typed: String::new(),
+            transition_model: None,
+        }
+    }
+
+    /// Number of ticks of the replay played so far.
+    pub fn tick(&self) -> usize {
+        self.tick
+    }
+
+    pub fn len(&self) -> usize {
+        self.replay.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replay.is_empty()
+    }
+
+    pub fn diverged(&self) -> Option<usize> {
+        self.diverged
+    }
+}
+
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReplayRecorder>()
+            .add_system_set(
+                SystemSet::on_enter(AppState::InGame).with_system(
+                    start_replay
+                        .after(game::start_game)
+                        .before(game::spawn_agents),
+                ),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(
+                        record_moves
+                            .after(controller::drive_agents)
+                            .before(movement::movement),
+                    )
+                    .with_system(save_replay)
+                    .with_system(silence_agents.before(controller::drive_agents))
+                    .with_system(
+                        play_replay
+                            .after(controller::drive_agents)
+                            .before(movement::movement),
+                    )
+                    .with_system(follow_replay.after(game::reset_episode))
+                    .with_system(browse_replay.before(simulation::advance_clock)),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(stop_replay));
+    }
+}
+
+/// Seeds the game with the replay being watched, or starts recording the game
+/// with the seed it was given.
+fn start_replay(
+    layout: Res<Layout>,
+    rewards: Res<Rewards>,
+    viewer: Option<ResMut<ReplayViewer>>,
+    mut recorder: ResMut<ReplayRecorder>,
+    mut transition_model: ResMut<TransitionModel>,
+    mut rng: ResMut<GameRng>,
+) {
+    match viewer {
+        Some(mut viewer) => {
+            viewer.transition_model = Some(*transition_model);
+            *transition_model = viewer.replay.transition_model;
+            *rng = GameRng::new(viewer.replay.seed);
+            viewer.tick = 0;
+            recorder.replay = None;
+        }
+        None => {
+            recorder.replay = Some(Replay::new(
+                layout.clone(),
+                rng.seed(),
+                *transition_model,
+                *rewards,
+            ));
+        }
+    }
+}
+
+pub fn record_moves(
+    clock: Res<SimulationClock>,
+    mut recorder: ResMut<ReplayRecorder>,
+    mut movement_event: EventReader<Movement>,
+) {
+    let moves = movement_event
+        .iter()
+        .map(|movement| (movement.agent(), movement.direction()))
+        .collect::<HashMap<_, _>>();
+    if !clock.just_ticked() {
+        return;
+    }
+    if let Some(replay) = recorder.replay.as_mut() {
+        replay.push(&moves);
+    }
+}
+
+fn save_replay(keyboard_input: Res<Input<KeyCode>>, recorder: Res<ReplayRecorder>) {
+    if !keyboard_input.just_pressed(KeyCode::F5) {
+        return;
+    }
+    match recorder.save() {
+        Ok(Some(path)) => info!("Replay saved to {}", path.display()),
+        Ok(None) => {}
+        Err(error) => warn!("Could not save the replay: {}", error),
+    }
+}
+
+/// Agents of a replay only make the moves it recorded.
+fn silence_agents(
+    viewer: Option<Res<ReplayViewer>>,
+    mut rng: ResMut<GameRng>,
+    mut controller_query: Query<&mut Controller, Added<Agent>>,
+) {
+    if viewer.is_none() {
+        return;
+    }
+    for mut controller in controller_query.iter_mut() {
+        *controller = Controller::new(&ControllerKind::External, rng.fork(Stream::Agents));
+    }
+}
+
+fn play_replay(
+    viewer: Option<ResMut<ReplayViewer>>,
+    clock: Res<SimulationClock>,
+    mut movement_event: EventWriter<Movement>,
+) {
+    let Some(mut viewer) = viewer else {
+        return;
+    };
+    if !clock.just_ticked() || viewer.diverged.is_some() {
+        return;
+    }
+    viewer.played = viewer.tick < viewer.len();
+    if viewer.played {
+        movement_event.send_batch(viewer.replay.moves(viewer.tick));
+        viewer.tick += 1;
+    }
+}
+
+/// Checks every tick played against the replay and pauses at the end. A game
+/// that went another way than the replay stops there, on the recorded state.
+fn follow_replay(world: &mut World) {
+    if !world.contains_resource::<ReplayViewer>()
+        || !world.resource::<SimulationClock>().just_ticked()
+    {
+        return;
+    }
+    world.resource_scope(|world, mut viewer: Mut<ReplayViewer>| {
+        let current = Snapshot::capture(world);
+        if !current.same_game(&viewer.snapshots[viewer.tick]) {
+            if viewer.played {
+                error!(
+                    "The game went away from the replay at tick {}, stopping the playback",
+                    viewer.tick
+                );
+                viewer.diverged = Some(viewer.tick);
+            }
+            viewer.snapshots[viewer.tick].restore(world);
+        }
+        viewer.played = false;
+        if viewer.tick >= viewer.len() || viewer.diverged.is_some() {
+            world.resource_mut::<SimulationClock>().paused = true;
+        }
+    });
+}
+
+fn browse_replay(world: &mut World) {
+    if !world.contains_resource::<ReplayViewer>() {
+        return;
+    }
+    world.resource_scope(|world, mut viewer: Mut<ReplayViewer>| {
+        let keyboard_input = world.resource::<Input<KeyCode>>();
+        let digits = [
+            KeyCode::Key0,
+            KeyCode::Key1,
+            KeyCode::Key2,
+            KeyCode::Key3,
+            KeyCode::Key4,
+            KeyCode::Key5,
+            KeyCode::Key6,
+            KeyCode::Key7,
+            KeyCode::Key8,
+            KeyCode::Key9,
+        ];
+        for (digit, key) in digits.iter().enumerate() {
+            if keyboard_input.just_pressed(*key) {
+                viewer.typed.push(char::from(b'0' + digit as u8));
+            }
+        }
+
+        let target = if keyboard_input.just_pressed(KeyCode::Left) {
+            Some(viewer.tick.saturating_sub(1))
+        } else if keyboard_input.just_pressed(KeyCode::Home) {
+            Some(0)
+        } else if keyboard_input.just_pressed(KeyCode::End) {
+            Some(viewer.len())
+        } else if keyboard_input.just_pressed(KeyCode::Return) {
+            std::mem::take(&mut viewer.typed).parse::<usize>().ok()
+        } else {
+            None
+        };
+        let Some(target) = target.map(|target| target.min(viewer.len())) else {
+            return;
+        };
+        viewer.snapshots[target].restore(world);
+        viewer.tick = target;
+        viewer.diverged = None;
+        world.resource_mut::<SimulationClock>().paused = true;
+        info!("Replay at tick {} of {}", viewer.tick, viewer.len());
+    });
+}
+
+fn stop_replay(
+    mut commands: Commands,
+    viewer: Option<Res<ReplayViewer>>,
+    mut transition_model: ResMut<TransitionModel>,
+    recorder: Res<ReplayRecorder>,
+) {
+    if let Some(viewer) = viewer {
+        if let Some(model) = viewer.transition_model {
+            *transition_model = model;
+        }
+        commands.remove_resource::<ReplayViewer>();
+    }
+    if recorder.auto_save && recorder.replay().is_some_and(|replay| !replay.is_empty()) {
+        match recorder.save() {
+            Ok(Some(path)) => info!("Replay saved to {}", path.display()),
+            Ok(None) => {}
+            Err(error) => warn!("Could not save the replay: {}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+    use super::*;
+
+    /// Plays `ticks` random moves of every agent on a noisy layout, recording
+    /// them, and keeps the state after every tick.
+    fn record_noisy_game(ticks: usize) -> (Replay, Vec<Snapshot>) {
+        let layout = Layout::load("assets/layouts/smallClassic.json").unwrap();
+        let agents = layout
+            .grid
+            .iter()
+            .filter(|value| **value == -1 || **value == 3)
+            .count() as u32;
+        let replay = Replay::new(
+            layout,
+            11,
+            TransitionModel::noisy(0.2, 0.1),
+            Rewards::default(),
+        );
+        let mut app = replay.headless_app();
+        app.add_plugin(ReplayPlugin);
+        app.update();
+
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut snapshots = vec![Snapshot::capture(&mut app.world)];
+        for _ in 0..ticks {
+            let mut events = app.world.resource_mut::<Events<Movement>>();
+            for agent in 0..agents {
+                events.send(Movement::new(
+                    agent,
+                    *Direction::ALL.choose(&mut rng).unwrap(),
+                ));
+            }
+            app.world.resource_mut::<SimulationClock>().step();
+            app.update();
+            snapshots.push(Snapshot::capture(&mut app.world));
+        }
+        let recorded = app
+            .world
+            .resource::<ReplayRecorder>()
+            .replay()
+            .unwrap()
+            .clone();
+        (recorded, snapshots)
+    }
+
+    #[test]
+    fn snapshots_replay_a_noisy_game_exactly() {
+        let (replay, played) = record_noisy_game(60);
+        assert_eq!(replay.len(), 60);
+        let replayed = replay.snapshots();
+        assert_eq!(replayed.len(), played.len());
+        for (tick, (played, replayed)) in played.iter().zip(&replayed).enumerate() {
+            assert_eq!(played.grid, replayed.grid, "tick {}", tick);
+            assert_eq!(played.agents, replayed.agents, "tick {}", tick);
+            // Every field of an episode is in its Debug output.
+            assert_eq!(
+                format!("{:?}", played.episode),
+                format!("{:?}", replayed.episode),
+                "tick {}",
+                tick
+            );
+            assert_eq!(played.rng, replayed.rng, "tick {}", tick);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use bevy::prelude::Resource;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+
+/// Parts of the game drawing random numbers, each from its own stream so that
+/// one drawing more numbers does not change what the others draw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stream {
+    /// Layouts and mazes.
+    Generation,
+    Ghosts,
+    /// Pacmen, whatever controls them.
+    Agents,
+    /// Noisy transitions.
+    Noise,
+    /// What agents sense, fog of war and sonar readings.
+    Sensor,
+}
+
+impl Stream {
+    pub const ALL: [Stream; 5] = [
+        Stream::Generation,
+        Stream::Ghosts,
+        Stream::Agents,
+        Stream::Noise,
+        Stream::Sensor,
+    ];
+}
+
+/// Generator of a stream, the one behind `StdRng` but with a state that can be saved.
+pub type StreamRng = ChaCha12Rng;
+
+/// Seed of every game, `None` draws a new one each time a game starts.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GameSeed(pub Option<u64>);
+
+/// Every random number of a game comes from here: the same seed always plays
+/// the same game.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GameRng {
+    seed: u64,
+    streams: [StreamRng; Stream::ALL.len()],
+}
+
+impl Default for GameRng {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            streams: Stream::ALL.map(|stream| {
+                // Spreads the streams apart, seed_from_u64 mixes the bits further.
+                let offset = (stream as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+                StreamRng::seed_from_u64(seed ^ offset)
+            }),
+        }
+    }
+
+    /// Seeded with `seed`, or with a random seed when there is none.
+    pub fn seeded(seed: Option<u64>) -> Self {
+        Self::new(seed.unwrap_or_else(|| rand::thread_rng().gen()))
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn stream(&mut self, stream: Stream) -> &mut StreamRng {
+        &mut self.streams[stream as usize]
+    }
+
+    /// An independent generator drawn from `stream`, for whatever keeps its own.
+    pub fn fork(&mut self, stream: Stream) -> StreamRng {
+        StreamRng::seed_from_u64(self.stream(stream).gen())
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    game::Rewards,
+    layout::Layout,
+    movement::TransitionModel,
+    replay::{ReplayRecorder, Snapshot},
+    simulation::{self, SimulationClock},
+    timeline::Timeline,
+    Agent, AppState,
+};
+
+/// Extension of saved games, written to `saves/` and listed by the menu.
+pub const EXTENSION: &str = "save";
+
+/// A game in progress: the layout and rules it is played with, and where it
+/// was when it was saved, random generators included.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedGame {
+    pub layout: Layout,
+    pub rewards: Rewards,
+    pub transition_model: TransitionModel,
+    pub tick: u64,
+    pub snapshot: Snapshot,
+}
+
+impl SavedGame {
+    pub fn capture(world: &mut World) -> Self {
+        Self {
+            layout: world.resource::<Layout>().clone(),
+            rewards: *world.resource::<Rewards>(),
+            transition_model: *world.resource::<TransitionModel>(),
+            tick: world.resource::<SimulationClock>().tick,
+            snapshot: Snapshot::capture(world),
+        }
+    }
+
+    /// Puts a game of the same layout back where it was saved. The rules are
+    /// left to the caller, which may want to play on with others.
+    pub fn restore(&self, world: &mut World) {
+        self.snapshot.restore(world);
+        world.resource_mut::<SimulationClock>().tick = self.tick;
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)
+    }
+}
+
+/// Where F6 writes the game being played.
+#[derive(Resource, Debug, Clone)]
+pub struct SaveSettings {
+    pub dir: PathBuf,
+}
+
+impl Default for SaveSettings {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("saves"),
+        }
+    }
+}
+
+/// Saved game chosen in the menu, put back as soon as its agents are spawned.
+#[derive(Resource)]
+pub struct PendingLoad(pub SavedGame);
+
+pub struct SavePlugin;
+
+impl Plugin for SavePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SaveSettings>()
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(save_game)
+                    .with_system(load_game.before(simulation::advance_clock)),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(forget_load));
+    }
+}
+
+fn save_game(world: &mut World) {
+    if !world.resource::<Input<KeyCode>>().just_pressed(KeyCode::F6) {
+        return;
+    }
+    let saved = SavedGame::capture(world);
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let path = world
+        .resource::<SaveSettings>()
+        .dir
+        .join(format!("{}_{}.{}", saved.layout.name, seconds, EXTENSION));
+    match saved.save(&path) {
+        Ok(()) => info!("Game saved to {}", path.display()),
+        Err(error) => warn!("Could not save the game: {}", error),
+    }
+}
+
+/// A loaded game did not start from its layout, so it is not recorded and
+/// the timeline starts over from it.
+fn load_game(world: &mut World) {
+    if !world.contains_resource::<PendingLoad>() {
+        return;
+    }
+    let mut agent_query = world.query::<&Agent>();
+    if agent_query.iter(world).next().is_none() {
+        return;
+    }
+    let Some(PendingLoad(saved)) = world.remove_resource::<PendingLoad>() else {
+        return;
+    };
+    saved.restore(world);
+    world.resource_mut::<SimulationClock>().paused = true;
+    if let Some(mut recorder) = world.get_resource_mut::<ReplayRecorder>() {
+        recorder.stop();
+    }
+    if let Some(mut timeline) = world.get_resource_mut::<Timeline>() {
+        timeline.clear();
+    }
+    info!("Game loaded at tick {}", saved.tick);
+}
+
+fn forget_load(mut commands: Commands) {
+    commands.remove_resource::<PendingLoad>();
+}
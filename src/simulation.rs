@@ -0,0 +1,150 @@
+use bevy::prelude::*;
+
+use crate::{
+    movement::{self, Direction},
+    AppState,
+};
+
+/// Drives the game in fixed ticks, independently from the frame rate.
+#[derive(Resource, Debug, Clone)]
+pub struct SimulationClock {
+    /// Seconds between two ticks at normal speed.
+    pub tick_length: f32,
+    pub speed: f32,
+    pub paused: bool,
+    pub tick: u64,
+    accumulator: f32,
+    pending_steps: u32,
+    ticked: bool,
+}
+
+impl Default for SimulationClock {
+    fn default() -> Self {
+        Self::new(0.1)
+    }
+}
+
+impl SimulationClock {
+    pub const MIN_SPEED: f32 = 0.125;
+    pub const MAX_SPEED: f32 = 16.0;
+
+    /// `tick_length` must be finite and not negative, which only debug builds
+    /// check.
+    pub fn new(tick_length: f32) -> Self {
+        debug_assert!(
+            tick_length.is_finite() && tick_length >= 0.0,
+            "a tick cannot last {} seconds",
+            tick_length
+        );
+        Self {
+            tick_length,
+            speed: 1.0,
+            paused: false,
+            tick: 0,
+            accumulator: 0.0,
+            pending_steps: 0,
+            ticked: false,
+        }
+    }
+
+    /// True during the frame in which a tick happened.
+    pub fn just_ticked(&self) -> bool {
+        self.ticked
+    }
+
+    /// Requests a single tick, even while paused.
+    pub fn step(&mut self) {
+        self.pending_steps += 1;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.accumulator = 0.0;
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed * 2.0).min(Self::MAX_SPEED);
+    }
+
+    pub fn slower(&mut self) {
+        self.speed = (self.speed / 2.0).max(Self::MIN_SPEED);
+    }
+
+    pub fn reset(&mut self) {
+        self.tick = 0;
+        self.accumulator = 0.0;
+        self.pending_steps = 0;
+        self.ticked = false;
+    }
+
+    /// Advances the clock by `delta` seconds; at most one tick happens per call.
+    pub fn advance(&mut self, delta: f32) {
+        self.ticked = false;
+        if self.pending_steps > 0 {
+            self.pending_steps -= 1;
+            self.ticked = true;
+        } else if !self.paused {
+            self.accumulator += delta * self.speed;
+            if self.accumulator >= self.tick_length {
+                self.accumulator = (self.accumulator - self.tick_length).min(self.tick_length);
+                self.ticked = true;
+            }
+        }
+        if self.ticked {
+            self.tick += 1;
+        }
+    }
+}
+
+/// Last direction pressed on the keyboard, kept until the next tick consumes it.
+#[derive(Resource, Debug, Default, Clone, Copy)]
+pub struct KeyboardBuffer(pub Option<Direction>);
+
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SimulationClock>()
+            .init_resource::<KeyboardBuffer>()
+            .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(reset_clock))
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(advance_clock)
+                    .with_system(clock_controls.before(advance_clock))
+                    .with_system(buffer_keyboard),
+            );
+    }
+}
+
+fn reset_clock(mut clock: ResMut<SimulationClock>, mut keyboard_buffer: ResMut<KeyboardBuffer>) {
+    clock.reset();
+    keyboard_buffer.0 = None;
+}
+
+pub fn advance_clock(time: Res<Time>, mut clock: ResMut<SimulationClock>) {
+    clock.advance(time.delta_seconds());
+}
+
+fn clock_controls(keyboard_input: Res<Input<KeyCode>>, mut clock: ResMut<SimulationClock>) {
+    if keyboard_input.just_pressed(KeyCode::Space) {
+        clock.toggle_pause();
+    }
+    if keyboard_input.just_pressed(KeyCode::N) {
+        clock.step();
+    }
+    if keyboard_input.any_just_pressed([KeyCode::NumpadAdd, KeyCode::Equals]) {
+        clock.faster();
+    }
+    if keyboard_input.any_just_pressed([KeyCode::NumpadSubtract, KeyCode::Minus]) {
+        clock.slower();
+    }
+}
+
+pub fn buffer_keyboard(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut keyboard_buffer: ResMut<KeyboardBuffer>,
+) {
+    if let Some(direction) = movement::keyboard_direction(&keyboard_input) {
+        keyboard_buffer.0 = Some(direction);
+    }
+}
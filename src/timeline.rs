@@ -0,0 +1,347 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::{
+    game,
+    replay::{ReplayRecorder, ReplayViewer, Snapshot},
+    simulation::{self, SimulationClock},
+    AppState,
+};
+
+/// Snapshots of the last ticks of the game, to go back to any of them. Once a
+/// past tick is shown, playing on forks a new run from it and forgets the
+/// ticks that came after.
+#[derive(Resource, Debug, Clone)]
+pub struct Timeline {
+    pub capacity: usize,
+    /// Whether the slider is shown, `T` toggles it.
+    pub visible: bool,
+    entries: VecDeque<(u64, Snapshot)>,
+    cursor: Option<usize>,
+    shown: Option<usize>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self::new(600)
+    }
+}
+
+impl Timeline {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            visible: false,
+            entries: VecDeque::new(),
+            cursor: None,
+            shown: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Index of the snapshot being looked at, `None` while the game runs.
+    pub fn cursor(&self) -> Option<usize> {
+        self.cursor
+    }
+
+    /// Tick and state at `index`, the oldest kept first.
+    pub fn get(&self, index: usize) -> Option<&(u64, Snapshot)> {
+        self.entries.get(index)
+    }
+
+    /// Looks at the snapshot at `index`, the game pauses on it.
+    pub fn scrub(&mut self, index: usize) {
+        if !self.entries.is_empty() {
+            self.cursor = Some(index.min(self.entries.len() - 1));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.cursor = None;
+        self.shown = None;
+    }
+
+    /// Drops the snapshots of the ticks after `tick`, taken back by an undo.
+    pub fn forget_after(&mut self, tick: u64) {
+        self.entries.retain(|(entry, _)| *entry <= tick);
+        self.cursor = None;
+        self.shown = None;
+    }
+
+    /// Keeps the state after `tick`. When a past snapshot was being looked at,
+    /// the ones after it belong to another run and are dropped first.
+    pub fn record(&mut self, tick: u64, snapshot: Snapshot) {
+        if let Some(cursor) = self.cursor.take() {
+            self.entries.truncate(cursor + 1);
+        }
+        self.shown = None;
+        self.entries.push_back((tick, snapshot));
+        while self.entries.len() > self.capacity.max(1) {
+            self.entries.pop_front();
+        }
+    }
+}
+
+#[derive(Component)]
+struct TimelinePanel;
+
+#[derive(Component)]
+struct TimelineBar;
+
+#[derive(Component)]
+struct TimelineHandle;
+
+#[derive(Component)]
+struct TimelineText;
+
+pub struct TimelinePlugin;
+
+impl Plugin for TimelinePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Timeline>()
+            .add_system_set(
+                SystemSet::on_enter(AppState::InGame)
+                    .with_system(clear_timeline)
+                    .with_system(spawn_slider),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(record_tick.after(game::reset_episode))
+                    .with_system(scrub_keys.before(simulation::advance_clock))
+                    .with_system(drag_slider.before(simulation::advance_clock))
+                    .with_system(
+                        show_scrubbed
+                            .after(scrub_keys)
+                            .after(drag_slider)
+                            .before(simulation::advance_clock),
+                    )
+                    .with_system(render_slider.after(record_tick)),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(despawn_slider));
+    }
+}
+
+fn clear_timeline(mut timeline: ResMut<Timeline>) {
+    timeline.clear();
+}
+
+/// Keeps a snapshot of every tick, the first one as soon as the agents are
+/// there. A tick played while scrubbing forks the run, the replay being
+/// recorded forgets the moves of the abandoned ticks.
+fn record_tick(world: &mut World) {
+    if world.contains_resource::<ReplayViewer>() {
+        return;
+    }
+    let clock = world.resource::<SimulationClock>();
+    let tick = clock.tick;
+    let ticked = clock.just_ticked();
+    let timeline = world.resource::<Timeline>();
+    if !ticked && !timeline.is_empty() {
+        return;
+    }
+    if let Some(cursor) = timeline.cursor {
+        let from = timeline.entries[cursor].0;
+        info!("Forking a new run from tick {}", from);
+        let mut recorder = world.get_resource_mut::<ReplayRecorder>();
+        if let Some(replay) = recorder.as_mut().and_then(|recorder| recorder.replay_mut()) {
+            let last = replay.ticks.pop();
+            replay.ticks.truncate(from as usize);
+            replay.ticks.extend(last);
+        }
+    }
+    let snapshot = Snapshot::capture(world);
+    if snapshot.agents.is_empty() {
+        return;
+    }
+    world.resource_mut::<Timeline>().record(tick, snapshot);
+}
+
+/// `T` shows the slider, `,` and `.` then move along it.
+fn scrub_keys(keyboard_input: Res<Input<KeyCode>>, mut timeline: ResMut<Timeline>) {
+    if keyboard_input.just_pressed(KeyCode::T) {
+        timeline.visible = !timeline.visible;
+    }
+    if !timeline.visible || timeline.is_empty() {
+        return;
+    }
+    let current = timeline.cursor.unwrap_or(timeline.len() - 1);
+    if keyboard_input.just_pressed(KeyCode::Comma) {
+        timeline.scrub(current.saturating_sub(1));
+    }
+    if keyboard_input.just_pressed(KeyCode::Period) {
+        timeline.scrub(current + 1);
+    }
+}
+
+fn drag_slider(
+    windows: Res<Windows>,
+    mut timeline: ResMut<Timeline>,
+    bar_query: Query<(&Interaction, &Node, &GlobalTransform), With<TimelineBar>>,
+) {
+    if !timeline.visible || timeline.is_empty() {
+        return;
+    }
+    let Some(cursor) = windows
+        .get_primary()
+        .and_then(|window| window.cursor_position())
+    else {
+        return;
+    };
+    for (interaction, node, transform) in bar_query.iter() {
+        if *interaction != Interaction::Clicked {
+            continue;
+        }
+        let left = transform.translation().x - node.size().x / 2.0;
+        let fraction = ((cursor.x - left) / node.size().x).clamp(0.0, 1.0);
+        let index = (fraction * (timeline.len() - 1) as f32).round() as usize;
+        if timeline.cursor != Some(index) {
+            timeline.scrub(index);
+        }
+    }
+}
+
+/// Puts the game in the state being looked at and pauses it there.
+fn show_scrubbed(world: &mut World) {
+    world.resource_scope(|world, mut timeline: Mut<Timeline>| {
+        let Some(cursor) = timeline.cursor else {
+            return;
+        };
+        if timeline.shown == Some(cursor) {
+            return;
+        }
+        let (tick, snapshot) = &timeline.entries[cursor];
+        snapshot.restore(world);
+        let mut clock = world.resource_mut::<SimulationClock>();
+        clock.tick = *tick;
+        clock.paused = true;
+        timeline.shown = Some(cursor);
+    });
+}
+
+fn spawn_slider(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Percent(5.0),
+                    bottom: Val::Px(10.0),
+                    ..default()
+                },
+                size: Size::new(Val::Percent(90.0), Val::Undefined),
+                flex_direction: FlexDirection::ColumnReverse,
+                padding: UiRect::all(Val::Px(6.0)),
+                ..default()
+            },
+            background_color: Color::rgba(0.0, 0.0, 0.0, 0.7).into(),
+            visibility: Visibility { is_visible: false },
+            ..default()
+        })
+        .insert(TimelinePanel)
+        .insert(Name::new("Timeline"))
+        .with_children(|panel| {
+            panel
+                .spawn(TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                        font_size: 16.0,
+                        color: Color::WHITE,
+                    },
+                ))
+                .insert(TimelineText);
+            panel
+                .spawn(ButtonBundle {
+                    style: Style {
+                        size: Size::new(Val::Percent(100.0), Val::Px(16.0)),
+                        margin: UiRect::top(Val::Px(6.0)),
+                        ..default()
+                    },
+                    background_color: Color::DARK_GRAY.into(),
+                    ..default()
+                })
+                .insert(TimelineBar)
+                .with_children(|bar| {
+                    bar.spawn(NodeBundle {
+                        style: Style {
+                            position_type: PositionType::Absolute,
+                            size: Size::new(Val::Px(6.0), Val::Percent(100.0)),
+                            ..default()
+                        },
+                        background_color: Color::ORANGE.into(),
+                        ..default()
+                    })
+                    .insert(TimelineHandle);
+                });
+        });
+}
+
+/// Moves the handle and describes the snapshot under it.
+fn render_slider(
+    timeline: Res<Timeline>,
+    mut panel_query: Query<&mut Visibility, With<TimelinePanel>>,
+    mut handle_query: Query<&mut Style, With<TimelineHandle>>,
+    mut text_query: Query<&mut Text, With<TimelineText>>,
+) {
+    if !timeline.is_changed() {
+        return;
+    }
+    for mut visibility in panel_query.iter_mut() {
+        visibility.is_visible = timeline.visible;
+    }
+    if !timeline.visible || timeline.is_empty() {
+        return;
+    }
+    let index = timeline.cursor.unwrap_or(timeline.len() - 1);
+    let fraction = match timeline.len() {
+        1 => 1.0,
+        len => index as f32 / (len - 1) as f32,
+    };
+    for mut style in handle_query.iter_mut() {
+        style.position.left = Val::Percent(fraction * 99.0);
+    }
+
+    let (tick, snapshot) = &timeline.entries[index];
+    let mut description = format!(
+        "Tick {} ({}/{}) {}  episode {}{}",
+        tick,
+        index + 1,
+        timeline.len(),
+        match timeline.cursor {
+            Some(_) => "scrubbing, play on to fork",
+            None => "live",
+        },
+        snapshot.episode.number,
+        if snapshot.episode.done { " done" } else { "" },
+    );
+    for agent in snapshot.agents.iter() {
+        description += &match agent.scared_timer {
+            Some(timer) => format!(
+                "\nGhost {} at ({}, {}) scared {}",
+                agent.id, agent.position.x, agent.position.y, timer
+            ),
+            None => format!(
+                "\nAgent {} at ({}, {}) score {} last reward {}",
+                agent.id, agent.position.x, agent.position.y, agent.score, agent.last_reward
+            ),
+        };
+    }
+    for mut text in text_query.iter_mut() {
+        text.sections[0].value = description.clone();
+    }
+}
+
+fn despawn_slider(mut commands: Commands, panel_query: Query<Entity, With<TimelinePanel>>) {
+    for entity in panel_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
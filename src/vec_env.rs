@@ -0,0 +1,278 @@
+use std::{
+    io,
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+};
+
+use ndarray::{prelude::*, stack};
+
+use crate::{
+    env::{Env, EnvConfig, Observation, Space, StepInfo},
+    movement::Direction,
+};
+
+/// Seed of the `episode`-th episode of instance `index`, so that every
+/// instance plays its own reproducible sequence of episodes.
+pub fn instance_seed(seed: u64, index: usize, episode: u64) -> u64 {
+    // SplitMix64 finaliser over the three values.
+    let mut z = seed
+        ^ (index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ episode.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Result of one step of every instance.
+pub struct VecStep {
+    /// `(instances, planes, width, height)`; finished instances already show
+    /// the first observation of their next episode.
+    pub observations: Array4<f32>,
+    pub rewards: Array1<f32>,
+    pub dones: Vec<bool>,
+    pub infos: Vec<StepInfo>,
+    /// Last observation of the episodes that just ended.
+    pub final_observations: Vec<Option<Observation>>,
+}
+
+struct Outcome {
+    observation: Observation,
+    reward: f32,
+    done: bool,
+    info: StepInfo,
+    final_observation: Option<Observation>,
+}
+
+enum Request {
+    Reset,
+    Step(Vec<usize>),
+}
+
+struct Instance {
+    env: Env,
+    index: usize,
+    episode: u64,
+}
+
+impl Instance {
+    fn reset(&mut self, seed: u64) -> Observation {
+        let observation = self
+            .env
+            .reset(Some(instance_seed(seed, self.index, self.episode)));
+        self.episode += 1;
+        observation
+    }
+}
+
+struct Worker {
+    count: usize,
+    requests: Sender<Request>,
+    replies: Receiver<Vec<Outcome>>,
+    handle: JoinHandle<()>,
+}
+
+/// Many headless environments stepped together on a pool of threads. Each
+/// thread owns its instances, the game itself is not shared between threads.
+pub struct VecEnv {
+    workers: Vec<Worker>,
+    len: usize,
+}
+
+impl VecEnv {
+    /// `count` copies of the same environment.
+    pub fn uniform(config: EnvConfig, count: usize, seed: u64, threads: usize) -> io::Result<Self> {
+        Self::new(vec![config; count], seed, threads)
+    }
+
+    /// One instance per config; every layout must have the same size so the
+    /// observations can be batched.
+    pub fn new(configs: Vec<EnvConfig>, seed: u64, threads: usize) -> io::Result<Self> {
+        let len = configs.len();
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a vectorized environment needs at least one instance",
+            ));
+        }
+        let chunk = len.div_ceil(threads.clamp(1, len));
+
+        let mut indexed = configs.into_iter().enumerate().collect::<Vec<_>>();
+        let mut workers = Vec::new();
+        let mut shape = None;
+        while !indexed.is_empty() {
+            let rest = indexed.split_off(chunk.min(indexed.len()));
+            let configs = std::mem::replace(&mut indexed, rest);
+            let count = configs.len();
+            let (requests, worker_requests) = mpsc::channel();
+            let (worker_replies, replies) = mpsc::channel();
+            let (ready, started) = mpsc::channel();
+            let handle = thread::spawn(move || {
+                run_worker(configs, seed, worker_requests, worker_replies, ready)
+            });
+            for worker_shape in started.recv().unwrap()? {
+                if *shape.get_or_insert(worker_shape.clone()) != worker_shape {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "every layout of a vectorized environment must have the same size",
+                    ));
+                }
+            }
+            workers.push(Worker {
+                count,
+                requests,
+                replies,
+                handle,
+            });
+        }
+        Ok(Self { workers, len })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Starts a new episode in every instance.
+    pub fn reset(&mut self) -> Array4<f32> {
+        let outcomes = self.dispatch(|_| Request::Reset);
+        let observations = outcomes
+            .iter()
+            .map(|outcome| outcome.observation.view())
+            .collect::<Vec<_>>();
+        stack(Axis(0), &observations).unwrap()
+    }
+
+    /// Steps every instance with its action, restarting the ones that finish.
+    pub fn step(&mut self, actions: &[usize]) -> VecStep {
+        assert_eq!(actions.len(), self.len, "one action per instance");
+        // Checked here rather than in the worker threads, which would only hang up.
+        assert!(
+            actions.iter().all(|&action| action < Direction::ALL.len()),
+            "actions are in 0..{}",
+            Direction::ALL.len()
+        );
+        let mut offset = 0;
+        let outcomes = self.dispatch(|count| {
+            let request = Request::Step(actions[offset..offset + count].to_vec());
+            offset += count;
+            request
+        });
+
+        let observations = outcomes
+            .iter()
+            .map(|outcome| outcome.observation.view())
+            .collect::<Vec<_>>();
+        VecStep {
+            observations: stack(Axis(0), &observations).unwrap(),
+            rewards: outcomes.iter().map(|outcome| outcome.reward).collect(),
+            dones: outcomes.iter().map(|outcome| outcome.done).collect(),
+            infos: outcomes.iter().map(|outcome| outcome.info).collect(),
+            final_observations: outcomes
+                .into_iter()
+                .map(|outcome| outcome.final_observation)
+                .collect(),
+        }
+    }
+
+    /// Sends a request to every worker, built from its number of instances,
+    /// and gathers the replies in instance order.
+    fn dispatch(&mut self, mut request: impl FnMut(usize) -> Request) -> Vec<Outcome> {
+        for worker in self.workers.iter() {
+            worker.requests.send(request(worker.count)).unwrap();
+        }
+        self.workers
+            .iter()
+            .flat_map(|worker| worker.replies.recv().unwrap())
+            .collect()
+    }
+}
+
+impl Drop for VecEnv {
+    fn drop(&mut self) {
+        for worker in self.workers.drain(..) {
+            drop(worker.requests);
+            let _ = worker.handle.join();
+        }
+    }
+}
+
+fn run_worker(
+    configs: Vec<(usize, EnvConfig)>,
+    seed: u64,
+    requests: Receiver<Request>,
+    replies: Sender<Vec<Outcome>>,
+    ready: Sender<io::Result<Vec<Vec<usize>>>>,
+) {
+    let instances = configs
+        .into_iter()
+        .map(|(index, config)| {
+            Env::new(config).map(|env| Instance {
+                env,
+                index,
+                episode: 0,
+            })
+        })
+        .collect::<io::Result<Vec<_>>>();
+    let mut instances = match instances {
+        Ok(instances) => {
+            let shapes = instances
+                .iter()
+                .map(|instance| match instance.env.observation_space() {
+                    Space::Box { shape, .. } => shape,
+                    Space::Discrete(n) => vec![n],
+                })
+                .collect();
+            ready.send(Ok(shapes)).unwrap();
+            instances
+        }
+        Err(error) => {
+            ready.send(Err(error)).unwrap();
+            return;
+        }
+    };
+
+    for request in requests.iter() {
+        let outcomes = match request {
+            Request::Reset => instances
+                .iter_mut()
+                .map(|instance| Outcome {
+                    observation: instance.reset(seed),
+                    reward: 0.0,
+                    done: false,
+                    info: StepInfo {
+                        legal: instance.env.legal_actions(),
+                        ..Default::default()
+                    },
+                    final_observation: None,
+                })
+                .collect(),
+            Request::Step(actions) => instances
+                .iter_mut()
+                .zip(actions)
+                .map(|(instance, action)| {
+                    let (observation, reward, done, mut info) = instance.env.step(action);
+                    let (observation, final_observation) = if done {
+                        let next = instance.reset(seed);
+                        info.legal = instance.env.legal_actions();
+                        (next, Some(observation))
+                    } else {
+                        (observation, None)
+                    };
+                    Outcome {
+                        observation,
+                        reward,
+                        done,
+                        info,
+                        final_observation,
+                    }
+                })
+                .collect(),
+        };
+        if replies.send(outcomes).is_err() {
+            break;
+        }
+    }
+}